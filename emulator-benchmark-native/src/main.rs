@@ -6,8 +6,88 @@ use std::{fs::File, io::Read};
 
 use emulator::{createEmulator, assemble, disassemble};
 use emulator::core::io::DevNull;
+use emulator::core::emulator::tracer::{TraceFormat, REFERENCE_TRACE};
+use emulator::core::trace_diff::{diff_traces, parse_trace};
+
+const USAGE: &str = "Usage:
+    emulator-benchmark-native
+        runs the 8080EXM.COM benchmark
+    emulator-benchmark-native diff LEFT RIGHT [--format FORMAT] [--right-format FORMAT] [--context LINES]
+        compares two instruction traces and shows where they diverge
+        FORMAT is default, reference or a trace line format like \"{pc} {af}\"";
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|arg| arg.as_str()) {
+        None => benchmark(),
+        Some("diff") => match diff(&args[1..]) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(message) => {
+                eprintln!("{}\n\n{}", message, USAGE);
+                std::process::exit(2);
+            }
+        },
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn trace_format(name: &str) -> Result<TraceFormat, String> {
+    let template = match name {
+        "default" => return Ok(TraceFormat::default()),
+        "reference" => REFERENCE_TRACE,
+        template => template,
+    };
+    TraceFormat::parse(template).map_err(String::from)
+}
+
+/*
+ * Prints the first divergence of the traces, returns whether they match
+ */
+fn diff(args: &[String]) -> Result<bool, String> {
+    let mut paths = Vec::new();
+    let mut format = TraceFormat::default();
+    let mut right_format = None;
+    let mut context = 10;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--format" => format = trace_format(value()?)?,
+            "--right-format" => right_format = Some(trace_format(value()?)?),
+            "--context" => context = value()?.parse().map_err(|_| "Invalid number of context lines")?,
+            path => paths.push(path),
+        }
+    }
+    let (left, right) = match paths.as_slice() {
+        [left, right] => (*left, *right),
+        _ => return Err(String::from("Expected two trace files")),
+    };
+    let read = |path: &str| std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error));
+    let parse = |path: &str, format: &TraceFormat| {
+        parse_trace(&read(path)?, format).map_err(|error| format!("{}: {}", path, error))
+    };
+    let left = parse(left, &format)?;
+    let right = parse(right, right_format.as_ref().unwrap_or(&format))?;
+
+    let diff = diff_traces(&left, &right, context);
+    match diff.divergence {
+        Some(divergence) => {
+            println!("Traces diverge after {} matching instructions", diff.instructions);
+            print!("{}", divergence);
+            Ok(false)
+        }
+        None => {
+            println!("Traces match for {} instructions", diff.instructions);
+            Ok(true)
+        }
+    }
+}
+
+fn benchmark() {
     let mut file = File::open("8080EXM.COM").unwrap();
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).unwrap();
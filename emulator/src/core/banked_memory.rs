@@ -0,0 +1,289 @@
+use std::cell::Cell;
+use std::ops::{Index, IndexMut, RangeInclusive};
+use std::rc::Rc;
+
+use crate::core::io::{DeviceState, InputDevice, OutputDevice};
+use crate::core::ram::{MemoryState, RAM};
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+/*
+ * Memory where a window of the address space shows one of several banks.
+ * Addresses outside of the window always reach the same memory. The bank is
+ * selected by writing its number to the port of the selector device, numbers
+ * past the last bank wrap around.
+ */
+pub struct BankedMemory {
+    mem: Vec<u8>,
+    banks: Vec<Vec<u8>>,
+    window: RangeInclusive<u16>,
+    selected: Rc<Cell<usize>>,
+    last_change: u16,
+}
+
+/*
+ * Output device switching the banks of a BankedMemory, reading the port
+ * returns the selected bank. Register it as device state as well, so that
+ * stepping back undoes bank switches.
+ */
+pub struct BankSelector {
+    selected: Rc<Cell<usize>>,
+    banks: usize,
+}
+
+impl BankedMemory {
+    pub fn new(window: RangeInclusive<u16>, banks: usize) -> Self {
+        let size = (window.end() - window.start()) as usize + 1;
+        BankedMemory {
+            mem: vec![0; ADDRESS_SPACE],
+            banks: vec![vec![0; size]; banks.max(1)],
+            window,
+            selected: Rc::new(Cell::new(0)),
+            last_change: 0,
+        }
+    }
+
+    // The device to register as output (and optionally input) device
+    pub fn selector(&self) -> BankSelector {
+        BankSelector { selected: self.selected.clone(), banks: self.banks.len() }
+    }
+
+    pub fn bank(&self) -> usize {
+        self.selected.get()
+    }
+
+    pub fn select(&mut self, bank: usize) {
+        self.selected.set(bank % self.banks.len());
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn window(&self) -> RangeInclusive<u16> {
+        self.window.clone()
+    }
+
+    // Reads from any bank, no matter which one is selected
+    pub fn read_bank(&self, bank: usize, address: u16) -> u8 {
+        match self.window.contains(&address) {
+            true => self.banks[bank % self.banks.len()][(address - self.window.start()) as usize],
+            false => self.mem[address as usize],
+        }
+    }
+
+    // Loads bytes into any bank, addresses outside of the window are skipped
+    pub fn load_bank(&mut self, bank: usize, vec: Vec<u8>, start: u16) {
+        let bank = bank % self.banks.len();
+        let mut address = start;
+        for byte in vec {
+            if self.window.contains(&address) {
+                self.banks[bank][(address - self.window.start()) as usize] = byte;
+            }
+            address = address.wrapping_add(1);
+        }
+    }
+}
+
+impl RAM for BankedMemory {
+    fn size(&self) -> usize {
+        ADDRESS_SPACE - self.banks[0].len() + self.banks.len() * self.banks[0].len()
+    }
+
+    // Loads into the selected bank inside of the window
+    fn load_vec(&mut self, vec: Vec<u8>, start: u16) {
+        let mut address = start;
+        for byte in vec {
+            self[address] = byte;
+            address = address.wrapping_add(1);
+        }
+    }
+
+    // Memory outside of the window, the banks are not included
+    fn get_ptr(&self) -> *const u8 {
+        self.mem.as_ptr()
+    }
+
+    fn get_last_changed_address(&self) -> u16 {
+        self.last_change
+    }
+
+    fn bank_at(&self, address: u16) -> Option<usize> {
+        match self.window.contains(&address) {
+            true => Some(self.bank()),
+            false => None,
+        }
+    }
+
+    fn save_state(&self) -> MemoryState {
+        MemoryState { memory: self.mem.clone(), banks: self.banks.clone(), bank: self.bank() }
+    }
+
+    fn load_state(&mut self, state: &MemoryState) -> Result<(), &'static str> {
+        let fits = state.memory.len() == ADDRESS_SPACE
+            && state.banks.len() == self.banks.len()
+            && state.banks.iter().all(|bank| bank.len() == self.banks[0].len())
+            && state.bank < self.banks.len();
+        if !fits {
+            return Err("Save state doesn't match the memory");
+        }
+        self.mem = state.memory.clone();
+        self.banks = state.banks.clone();
+        self.selected.set(state.bank);
+        Ok(())
+    }
+}
+
+impl Index<u16> for BankedMemory {
+    type Output = u8;
+
+    fn index(&self, index: u16) -> &Self::Output {
+        match self.window.contains(&index) {
+            true => &self.banks[self.selected.get()][(index - self.window.start()) as usize],
+            false => &self.mem[index as usize],
+        }
+    }
+}
+
+impl IndexMut<u16> for BankedMemory {
+    fn index_mut(&mut self, index: u16) -> &mut Self::Output {
+        self.last_change = index;
+        match self.window.contains(&index) {
+            true => &mut self.banks[self.selected.get()][(index - self.window.start()) as usize],
+            false => &mut self.mem[index as usize],
+        }
+    }
+}
+
+impl OutputDevice for BankSelector {
+    fn write(&mut self, byte: u8) {
+        self.selected.set(byte as usize % self.banks);
+    }
+}
+
+impl InputDevice for BankSelector {
+    fn read(&self) -> u8 {
+        self.selected.get() as u8
+    }
+}
+
+// The state is the selected bank as little endian u32
+impl DeviceState for BankSelector {
+    fn save_state(&self) -> Vec<u8> {
+        (self.selected.get() as u32).to_le_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), &'static str> {
+        let bank = match state {
+            [a, b, c, d] => u32::from_le_bytes([*a, *b, *c, *d]) as usize,
+            _ => return Err("Save state doesn't match the bank selector"),
+        };
+        if bank >= self.banks {
+            return Err("Save state doesn't match the bank selector");
+        }
+        self.selected.set(bank);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::Emulator;
+    use std::cell::RefCell;
+
+    #[test]
+    fn banks() {
+        let mut memory = BankedMemory::new(0x8000..=0xbfff, 4);
+        assert_eq!(4 * 0x4000 + 0xc000, memory.size());
+        memory[0x8000] = 1;
+        memory[0x7fff] = 2;
+        memory.select(2);
+        assert_eq!(0, memory[0x8000]);
+        assert_eq!(2, memory[0x7fff]);
+        memory[0xbfff] = 3;
+        assert_eq!(Some(2), memory.bank_at(0xbfff));
+        assert_eq!(None, memory.bank_at(0xc000));
+
+        memory.select(6);
+        assert_eq!(2, memory.bank());
+        assert_eq!(1, memory.read_bank(0, 0x8000));
+        assert_eq!(3, memory.read_bank(2, 0xbfff));
+        memory.load_bank(3, vec![4, 5], 0xbfff);
+        assert_eq!(4, memory.read_bank(3, 0xbfff));
+        assert_eq!(0, memory[0xc000]);
+    }
+
+    #[test]
+    fn switched_by_output() {
+        let memory = BankedMemory::new(0x1000..=0x1fff, 2);
+        let selector = Rc::new(RefCell::new(memory.selector()));
+        let mut emu = Emulator::new();
+        emu.ram = Box::new(memory);
+        emu.register_output_device(selector.clone(), 0x40).expect("");
+        emu.register_input_device(selector, 0x40).expect("");
+
+        // MVI A,55H, STA 1000H, MVI A,1, OUT 40H, LDA 1000H, IN 40H
+        emu.load_ram(vec![0x3e, 0x55, 0x32, 0x00, 0x10, 0x3e, 0x01, 0xd3, 0x40, 0x3a, 0x00, 0x10, 0xdb, 0x40], 0);
+        for _ in 0..5 {
+            emu.execute_next().expect("");
+        }
+        assert_eq!(emu.reg['a'], 0);
+        assert_eq!(Some(1), emu.ram.bank_at(0x1000));
+        emu.execute_next().expect("");
+        assert_eq!(emu.reg['a'], 1);
+    }
+
+    #[test]
+    fn step_back_over_bank_switch() {
+        let memory = BankedMemory::new(0x1000..=0x1fff, 2);
+        let selector = Rc::new(RefCell::new(memory.selector()));
+        let mut emu = Emulator::new();
+        emu.ram = Box::new(memory);
+        emu.register_output_device(selector.clone(), 0x40).expect("");
+        emu.register_device_state("bank selector", selector.clone()).expect("");
+        emu.enable_journal(10, 10);
+
+        // MVI A,1, OUT 40H, STA 1000H, XRA A, OUT 40H, STA 1000H
+        emu.load_ram(vec![0x3e, 0x01, 0xd3, 0x40, 0x32, 0x00, 0x10, 0xaf, 0xd3, 0x40, 0x32, 0x00, 0x10], 0);
+        emu.ram[0x1000] = 0x55;
+        for _ in 0..6 {
+            emu.execute_next().expect("");
+        }
+        assert_eq!(Some(0), emu.ram.bank_at(0x1000));
+        assert_eq!(0, emu.ram[0x1000]);
+
+        // back to before the second switch, the write went to bank 1
+        for _ in 0..3 {
+            emu.step_back().expect("");
+        }
+        assert_eq!(Some(1), emu.ram.bank_at(0x1000));
+        assert_eq!(1, emu.ram[0x1000]);
+
+        // back to before the first write to bank 1
+        emu.step_back().expect("");
+        assert_eq!(0, emu.ram[0x1000]);
+        emu.step_back().expect("");
+        assert_eq!(Some(0), emu.ram.bank_at(0x1000));
+        assert_eq!(0x55, emu.ram[0x1000]);
+
+        assert!(selector.borrow_mut().load_state(&[2, 0, 0, 0]).is_err());
+        assert!(selector.borrow_mut().load_state(&[1]).is_err());
+    }
+
+    #[test]
+    fn state() {
+        let mut memory = BankedMemory::new(0x1000..=0x1fff, 2);
+        memory[0x1000] = 1;
+        memory.select(1);
+        memory[0x1000] = 2;
+        let state = memory.save_state();
+        assert_eq!(1, state.bank);
+
+        let mut restored = BankedMemory::new(0x1000..=0x1fff, 2);
+        restored.load_state(&state).unwrap();
+        assert_eq!(2, restored[0x1000]);
+        assert_eq!(1, restored.read_bank(0, 0x1000));
+        assert!(BankedMemory::new(0x1000..=0x1fff, 3).load_state(&state).is_err());
+    }
+}
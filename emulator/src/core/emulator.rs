@@ -1,6 +1,9 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
 use std::rc::Rc;
 
+use crate::core::instruction::OPCODES;
 use crate::core::io::*;
 use crate::core::ram::*;
 use crate::core::register::RegisterArray;
@@ -8,25 +11,11 @@ use wasm_bindgen::prelude::wasm_bindgen;
 
 pub type EResult<T> = Result<T, &'static str>;
 
-static CLOCK_CYCLES: [usize; 256] = [
-    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
-    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
-    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4,
-    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4,
-    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
-    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
-    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
-    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    0, 10, 10, 10, 0, 11, 7, 11, 0, 10, 10, 10, 0, 17, 7, 11,
-    0, 10, 10, 10, 0, 11, 7, 11, 0, 10, 10, 10, 0, 17, 7, 11,
-    0, 10, 10, 18, 0, 11, 7, 11, 0, 5, 10, 5, 0, 17, 7, 11,
-    0, 10, 10, 4, 0, 11, 7, 11, 0, 5, 10, 4, 0, 17, 7, 11
-];
+// Memory mapped devices with the addresses they take up
+type MemoryDevices = Vec<(RangeInclusive<u16>, Rc<RefCell<dyn MemoryDevice>>)>;
 
+// Devices saved in save states, by name
+type StatefulDevices = Vec<(String, Rc<RefCell<dyn DeviceState>>)>;
 
 #[wasm_bindgen]
 pub struct Emulator {
@@ -37,8 +26,21 @@ pub struct Emulator {
     pub reg: RegisterArray,
     input_devices: [Option<Rc<RefCell<dyn InputDevice>>>; 256],
     output_devices: [Option<Rc<RefCell<dyn OutputDevice>>>; 256],
+    memory_devices: MemoryDevices,
+    stateful_devices: StatefulDevices,
+    // history for stepping backwards, if enabled
+    journal: Option<journal::Journal>,
+    debugger: debugger::Debugger,
+    // subroutines being executed, for backtraces
+    call_stack: Vec<callstack::Frame>,
+    // log of the executed instructions, if enabled
+    tracer: Option<tracer::Tracer>,
+    // cycle counts for optimizing programs, if enabled
+    profiler: Option<profiler::Profiler>,
     pub running: bool,
     pub interrupts_enabled: bool,
+    // names of the addresses, for the debugger
+    symbols: BTreeMap<u16, Vec<String>>,
 }
 
 #[wasm_bindgen]
@@ -51,8 +53,16 @@ impl Emulator {
             reg: RegisterArray::new(),
             input_devices: unsafe { std::mem::zeroed() },
             output_devices: unsafe { std::mem::zeroed() },
+            memory_devices: Vec::new(),
+            stateful_devices: Vec::new(),
+            journal: None,
+            debugger: debugger::Debugger::default(),
+            call_stack: Vec::new(),
+            tracer: None,
+            profiler: None,
             running: true,
             interrupts_enabled: true, // INTE
+            symbols: BTreeMap::new(),
         }
     }
     
@@ -66,6 +76,11 @@ impl Emulator {
         self.ram.get_last_changed_address()
     }
 
+    // Used for frontend to show the bank at an address, -1 if it isn't banked
+    pub fn get_bank(&self, address: u16) -> i32 {
+        self.ram.bank_at(address).map_or(-1, |bank| bank as i32)
+    }
+
     fn execute_instruction(&mut self, opcode: u8) -> EResult<usize> {
         match opcode {
             0x00 => {
@@ -637,32 +652,34 @@ impl Emulator {
                 self.call(0x38)?;
             }
         }
-        Ok(CLOCK_CYCLES[opcode as usize] as usize)
+        Ok(OPCODES[opcode as usize].cycles as usize)
     }
 
     #[wasm_bindgen]
     pub fn execute_next(&mut self) -> EResult<usize> {
-        let opcode = self.ram[self.pc];
-        self.pc += 1;
-        self.execute_instruction(opcode)
+        self.begin_step();
+        self.trace(None);
+        self.begin_profile();
+        let pc = self.pc;
+        let opcode = self.fetch(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        let cycles = self.execute_instruction(opcode);
+        self.leave_frames();
+        self.trace_cycles(&cycles);
+        self.end_profile(Some(pc), &cycles);
+        cycles
     }
 
+    // The pc wraps around the end of the address space like on the 8080
     fn read_byte(&mut self) -> EResult<u8> {
-        if self.pc + 1 > self.ram.size() as u16 {
-            return Err("READ_BYTE: Not enough bytes available");
-        }
-        self.pc += 1;
-        Ok(self.ram[self.pc - 1])
+        let byte = self.fetch(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        Ok(byte)
     }
 
     fn read_addr(&mut self) -> EResult<u16> {
-        if self.pc + 2 > self.ram.size() as u16 {
-            return Err("READ_ADDR: Not enough bytes available");
-        }
-        let low = self.ram[self.pc] as u16;
-        self.pc += 1;
-        let high = self.ram[self.pc] as u16;
-        self.pc += 1;
+        let low = self.read_byte()? as u16;
+        let high = self.read_byte()? as u16;
         Ok((high << 8) | low)
     }
 
@@ -672,8 +689,19 @@ impl Emulator {
 
     pub fn interrupt(&mut self, opcode: u8) -> EResult<usize> {
         if self.interrupts_enabled {
+            self.begin_step();
+            self.trace(Some(opcode));
+            self.begin_profile();
             self.interrupts_enabled = false;
-            return self.execute_instruction(opcode);
+            let depth = self.call_stack.len();
+            let cycles = self.execute_instruction(opcode);
+            self.leave_frames();
+            if self.call_stack.len() > depth {
+                self.enter_interrupt();
+            }
+            self.trace_cycles(&cycles);
+            self.end_profile(None, &cycles);
+            return cycles;
         }
         Err("Interrupts disabled")
     }
@@ -681,6 +709,14 @@ impl Emulator {
 
 mod instructions;
 mod devices;
+pub mod disassembly;
+pub mod state;
+mod journal;
+pub mod debugger;
+mod stepping;
+pub mod callstack;
+pub mod tracer;
+pub mod profiler;
 
 #[cfg(test)]
 mod tests {
@@ -724,4 +760,37 @@ mod tests {
         // TODO: Add another test for non RST instruction interrupts
         Ok(())
     }
+
+    #[test]
+    fn wraparound() {
+        let mut emu = Emulator::new();
+        // JMP 1234H split across the end of memory
+        emu.load_ram(vec![0xc3, 0x34], 0xfffe);
+        emu.load_ram(vec![0x12], 0);
+        emu.pc = 0xfffe;
+        emu.execute_next().expect("");
+        assert_eq!(emu.pc, 0x1234);
+
+        // MOV M,A and LHLD at the last address
+        emu.load_ram(vec![0x77, 0x2a, 0xff, 0xff], 0x1234);
+        emu.reg["hl"] = 0xffff;
+        emu.reg['a'] = 0x42;
+        emu.execute_next().expect("");
+        emu.execute_next().expect("");
+        assert_eq!(emu.reg['l'], 0x42);
+        assert_eq!(emu.reg['h'], 0x12);
+    }
+
+    #[test]
+    fn smaller_memory() {
+        let mut emu = Emulator::new();
+        emu.ram = Box::new(DefaultRam::with_size(0x1000));
+        // STA 0f000H ends up at 0
+        emu.load_ram(vec![0x32, 0x00, 0xf0], 0x100);
+        emu.pc = 0x100;
+        emu.reg['a'] = 0x55;
+        emu.execute_next().expect("");
+        assert_eq!(emu.ram[0x0000], 0x55);
+        assert_eq!(emu.ram[0x1100], 0x32);
+    }
 }
@@ -0,0 +1,285 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::debugger::stack_offset;
+use super::Emulator;
+
+/*
+ * How a subroutine was entered
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallKind {
+    // CALL and the conditional calls
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/*
+ * Subroutine on the shadow call stack
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Frame {
+    pub kind: CallKind,
+    // entry of the subroutine
+    pub target: u16,
+    // where the return address was pushed to
+    pub slot: u16,
+}
+
+/*
+ * Line of a backtrace, the innermost frame comes first
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackFrame {
+    // where execution is or continues once the inner frame returns
+    pub address: u16,
+    // entry of the subroutine, unknown for the outermost frame
+    pub function: Option<u16>,
+    // e.g. LOOP+3H
+    pub location: String,
+    // how the inner frame was entered from here
+    pub call: Option<CallKind>,
+}
+
+impl Emulator {
+    // Called after the return address was pushed
+    pub(super) fn enter_frame(&mut self, kind: CallKind, target: u16) {
+        self.call_stack.push(Frame { kind, target, slot: self.sp });
+    }
+
+    // Called when an interrupt executed a call
+    pub(super) fn enter_interrupt(&mut self) {
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.kind = CallKind::Interrupt;
+        }
+    }
+
+    /*
+     * Called after every instruction. Frames whose return address was popped
+     * are dropped, no matter if that happened through a return, POP, SPHL,
+     * INX SP or LXI SP.
+     */
+    pub(super) fn leave_frames(&mut self) {
+        while self.call_stack.last().is_some_and(|frame| stack_offset(self.sp, frame.slot) > 0) {
+            let frame = self.call_stack.pop().unwrap();
+            self.record_left_frame(frame);
+        }
+    }
+
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    /*
+     * The return addresses are read from the stack, so ones changed by the
+     * program, e.g. through XTHL to skip inline parameters, are shown as
+     * they are now
+     */
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        let mut frames = Vec::with_capacity(self.call_stack.len() + 1);
+        let mut address = self.pc;
+        let mut call = None;
+        for frame in self.call_stack.iter().rev() {
+            frames.push(StackFrame { address, function: Some(frame.target), location: self.location(address, Some(frame.target)), call });
+            address = self.peek(frame.slot) as u16 | (self.peek(frame.slot.wrapping_add(1)) as u16) << 8;
+            call = Some(frame.kind);
+        }
+        frames.push(StackFrame { address, function: None, location: self.location(address, None), call });
+        frames
+    }
+
+    // The address relative to the function's symbol or the closest symbol before it
+    pub(super) fn location(&self, address: u16, function: Option<u16>) -> String {
+        let named = function.filter(|&function| function <= address && !self.symbols_at(function).is_empty());
+        let base = match named {
+            Some(function) => Some(function),
+            None => self.symbols.range(..=address).next_back().map(|(&base, _)| base),
+        };
+        match base {
+            Some(base) if base == address => self.symbols_at(base)[0].clone(),
+            Some(base) => format!("{}+{:X}H", self.symbols_at(base)[0], address - base),
+            None => format!("{:04X}H", address),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    // The backtrace as JSON for the frontend
+    pub fn backtrace_json(&self) -> String {
+        serde_json::to_string(&self.backtrace()).unwrap()
+    }
+
+    // The backtrace as text, one frame per line
+    pub fn backtrace_text(&self) -> String {
+        self.backtrace()
+            .iter()
+            .enumerate()
+            .map(|(depth, frame)| format!("#{} {:04X}H {}\n", depth, frame.address, frame.location))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+
+    const PROGRAM: &str = "
+        JMP MAIN
+        NOP
+        NOP
+        NOP
+        NOP
+        NOP
+        RET
+        MAIN: LXI SP,100H
+        CALL OUTER
+        HLT
+        OUTER: RST 1
+        LXI H,SKIP
+        PUSH H
+        CALL INNER
+        RET
+        INNER: CALL ARGS
+        DB 2
+        POP H
+        RET
+        ARGS: XTHL
+        INX H
+        XTHL
+        RET
+        SKIP: NOP
+        END";
+
+    const OUTER: u16 = 16;
+    const INNER: u16 = 25;
+    const ARGS: u16 = 31;
+    const SKIP: u16 = 35;
+
+    fn emulator() -> Emulator {
+        let (bytes, labels) = Assembler::new(PROGRAM).assemble_with_labels().unwrap();
+        let mut emu = Emulator::new();
+        emu.load_ram(bytes, 0);
+        emu.set_symbols(&labels);
+        emu
+    }
+
+    fn depth_at(emu: &mut Emulator, address: u16) -> usize {
+        while emu.pc != address {
+            emu.execute_next().unwrap();
+        }
+        emu.call_stack().len()
+    }
+
+    #[test]
+    fn calls() {
+        let mut emu = emulator();
+        assert_eq!(2, depth_at(&mut emu, 8));
+        assert_eq!(CallKind::Rst, emu.call_stack()[1].kind);
+        assert_eq!(1, depth_at(&mut emu, OUTER + 1));
+        assert_eq!(Frame { kind: CallKind::Call, target: OUTER, slot: 0xfe }, emu.call_stack()[0]);
+        assert_eq!(3, depth_at(&mut emu, ARGS));
+    }
+
+    #[test]
+    fn manual_returns() {
+        let mut emu = emulator();
+        depth_at(&mut emu, ARGS);
+
+        // XTHL changes the return address to skip the DB
+        for _ in 0..3 {
+            emu.execute_next().unwrap();
+        }
+        let frames = emu.backtrace();
+        assert_eq!(INNER + 4, frames[1].address);
+        assert_eq!("INNER+4H", frames[1].location);
+        assert_eq!(3, emu.call_stack().len());
+
+        // INNER pops its return address and returns to SKIP within OUTER
+        assert_eq!(2, depth_at(&mut emu, INNER + 4));
+        emu.execute_next().unwrap();
+        assert_eq!(1, emu.call_stack().len());
+        emu.execute_next().unwrap();
+        assert_eq!(1, emu.call_stack().len());
+
+        // SPHL drops everything above the new stack pointer
+        emu.reg["hl"] = 0x100;
+        emu.ram[emu.pc] = 0xf9;
+        emu.execute_next().unwrap();
+        assert!(emu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut emu = emulator();
+        depth_at(&mut emu, ARGS);
+        let frames = emu.backtrace();
+        assert_eq!(4, frames.len());
+        assert_eq!(
+            StackFrame { address: ARGS, function: Some(ARGS), location: String::from("ARGS"), call: None },
+            frames[0]
+        );
+        assert_eq!(Some(CallKind::Call), frames[1].call);
+        assert_eq!(Some(INNER), frames[1].function);
+        assert_eq!(None, frames[3].function);
+        assert_eq!(
+            "#0 001FH ARGS\n#1 001CH INNER+3H\n#2 0018H OUTER+8H\n#3 000FH MAIN+6H\n",
+            emu.backtrace_text()
+        );
+        assert!(emu.backtrace_json().starts_with("[{\"address\":31,\"function\":31,\"location\":\"ARGS\",\"call\":null}"));
+    }
+
+    #[test]
+    fn stack_at_top_of_memory() {
+        let mut emu = emulator();
+        depth_at(&mut emu, OUTER);
+        // the return address of a call with SP at 0 is pushed to FFFEH
+        emu.sp = 0;
+        emu.pc = 0x20;
+        emu.interrupt(0xcf).unwrap();
+        assert_eq!(0xfffe, emu.call_stack()[1].slot);
+        assert_eq!(0x20, emu.backtrace()[1].address);
+        emu.execute_next().unwrap();
+        assert_eq!(0, emu.sp);
+        assert_eq!(1, emu.call_stack().len());
+    }
+
+    #[test]
+    fn step_back() {
+        let mut emu = emulator();
+        emu.enable_journal(100, 10);
+        depth_at(&mut emu, ARGS);
+        let frames = emu.call_stack().to_vec();
+        depth_at(&mut emu, SKIP);
+        assert_eq!(1, emu.call_stack().len());
+
+        // the RET and POP that left the frames are undone
+        assert_eq!(Ok(6), emu.run_back_to(ARGS));
+        assert_eq!(frames, emu.call_stack());
+        while emu.can_step_back() {
+            emu.step_back().unwrap();
+        }
+        assert!(emu.call_stack().is_empty());
+
+        // restoring a state starts with an empty call stack
+        let state = emu.snapshot();
+        depth_at(&mut emu, ARGS);
+        emu.restore(&state).unwrap();
+        assert!(emu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut emu = emulator();
+        emu.sp = 0x100;
+        emu.pc = 0x20;
+        emu.interrupt(0xcf).unwrap();
+        assert_eq!(CallKind::Interrupt, emu.call_stack()[0].kind);
+        assert_eq!(0x20, emu.backtrace()[1].address);
+        emu.execute_next().unwrap();
+        assert!(emu.call_stack().is_empty());
+    }
+}
@@ -0,0 +1,628 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{EResult, Emulator};
+use crate::kreator::parser::{eval_with, Context};
+
+/*
+ * Kind of access a watchpoint triggers on
+ */
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+/*
+ * Why run returned
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum StopReason {
+    // the instruction at the address is next, it hasn't been executed
+    Breakpoint { address: u16 },
+    // after the instruction that accessed the address
+    Watchpoint { address: u16, access: Access },
+    // after the instruction that accessed the port
+    Port { port: u8, access: Access },
+    // the instruction at the address is next, for the host to handle it
+    Trap { address: u16 },
+    // step over, step out or run to reached its destination
+    Step,
+    Halted,
+    // the budget was used up
+    Limit,
+    Error { message: &'static str },
+}
+
+/*
+ * Budget of a run, cycle budgets are checked after each instruction, so the
+ * last one may take the run a few cycles over
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Instructions(u64),
+    Cycles(u64),
+}
+
+/*
+ * Why run returned and what it executed until then
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RunResult {
+    #[serde(flatten)]
+    pub reason: StopReason,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/*
+ * Stops execution when reached, unless the condition is false or it hasn't
+ * been hit often enough yet. Tracepoints log a message instead of stopping.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct Breakpoint {
+    condition: Option<String>,
+    // times it was reached with the condition holding
+    hits: usize,
+    // stops from this hit on
+    min_hits: usize,
+    // tracepoint message, {expression} is replaced by the value
+    message: Option<String>,
+}
+
+impl RunResult {
+    fn exhausted(&self, limit: Limit) -> bool {
+        match limit {
+            Limit::Instructions(count) => self.instructions >= count,
+            Limit::Cycles(count) => self.cycles >= count,
+        }
+    }
+}
+
+impl Breakpoint {
+    fn new() -> Self {
+        Breakpoint { condition: None, hits: 0, min_hits: 1, message: None }
+    }
+}
+
+/*
+ * Where a run started by a step ends
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Target {
+    // before the instruction at pc, with the stack pointer at sp or above
+    Address { pc: u16, sp: Option<u16> },
+    // after a return that leaves the stack pointer above sp
+    Return { sp: u16 },
+}
+
+#[derive(Default)]
+pub(super) struct Debugger {
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    watchpoints: Vec<(RangeInclusive<u16>, Access)>,
+    port_watchpoints: BTreeMap<u8, Access>,
+    // addresses the host handles itself, e.g. CP/M system calls
+    traps: BTreeSet<u16>,
+    // first watchpoint triggered by the current instruction
+    hit: Cell<Option<StopReason>>,
+    // where the last run stopped at a breakpoint or trap, continuing doesn't stop there again
+    stopped_at: Option<u16>,
+    // messages of the tracepoints
+    messages: Vec<String>,
+    // destination of the current step
+    target: Option<Target>,
+}
+
+/*
+ * How far the stack pointer is above base. The stack wraps around, a call
+ * with the stack pointer at 0 pushes to FFFEH, below 0.
+ */
+pub(super) fn stack_offset(sp: u16, base: u16) -> i16 {
+    sp.wrapping_sub(base) as i16
+}
+
+impl Emulator {
+    /*
+     * Executes instructions until the budget is used up or something else
+     * stops it. Calling run again after a breakpoint or trap continues past
+     * it. A halted CPU doesn't execute anything until running is set.
+     */
+    pub fn run(&mut self, limit: Limit) -> RunResult {
+        let mut result = RunResult { reason: StopReason::Limit, instructions: 0, cycles: 0 };
+        if !self.running {
+            result.reason = StopReason::Halted;
+            return result;
+        }
+        while !result.exhausted(limit) {
+            if result.instructions > 0 && self.reached_address() {
+                self.debugger.stopped_at = Some(self.pc);
+                result.reason = StopReason::Step;
+                return result;
+            }
+            if self.debugger.stopped_at.take() != Some(self.pc) {
+                let stop = match self.debugger.traps.contains(&self.pc) {
+                    true => Some(StopReason::Trap { address: self.pc }),
+                    false => self.check_breakpoint(),
+                };
+                if let Some(reason) = stop {
+                    if let StopReason::Breakpoint { address } | StopReason::Trap { address } = reason {
+                        self.debugger.stopped_at = Some(address);
+                    }
+                    result.reason = reason;
+                    return result;
+                }
+            }
+            let returning = self.returning();
+            let sp = self.sp;
+            self.debugger.hit.set(None);
+            match self.execute_next() {
+                Ok(cycles) => {
+                    result.instructions += 1;
+                    result.cycles += cycles as u64;
+                }
+                Err(message) => {
+                    result.reason = StopReason::Error { message };
+                    return result;
+                }
+            }
+            if let Some(reason) = self.debugger.hit.take() {
+                result.reason = reason;
+                return result;
+            }
+            // conditional returns that aren't taken leave the stack pointer alone
+            if returning && self.sp != sp && self.left_frame() {
+                result.reason = StopReason::Step;
+                return result;
+            }
+            if !self.running {
+                result.reason = StopReason::Halted;
+                return result;
+            }
+        }
+        result
+    }
+
+    /*
+     * Runs like run, but also stops at the target. Steps start with the
+     * instruction at pc even if there is a breakpoint.
+     */
+    pub(super) fn run_until(&mut self, target: Target, limit: Limit) -> RunResult {
+        self.debugger.stopped_at = Some(self.pc);
+        self.debugger.target = Some(target);
+        let result = self.run(limit);
+        self.debugger.target = None;
+        result
+    }
+
+    fn reached_address(&self) -> bool {
+        match self.debugger.target {
+            Some(Target::Address { pc, sp }) => self.pc == pc && sp.is_none_or(|sp| stack_offset(self.sp, sp) >= 0),
+            _ => false,
+        }
+    }
+
+    // Whether the next instruction may return from the subroutine being stepped out of
+    fn returning(&self) -> bool {
+        if !matches!(self.debugger.target, Some(Target::Return { .. })) {
+            return false;
+        }
+        let opcode = self.peek(self.pc);
+        // RET, its alias and the conditional returns
+        opcode == 0xc9 || opcode == 0xd9 || opcode & 0xc7 == 0xc0
+    }
+
+    fn left_frame(&self) -> bool {
+        match self.debugger.target {
+            Some(Target::Return { sp }) => stack_offset(self.sp, sp) > 0,
+            _ => false,
+        }
+    }
+
+    fn check_breakpoint(&mut self) -> Option<StopReason> {
+        let breakpoint = self.debugger.breakpoints.get(&self.pc)?.clone();
+        if let Some(condition) = &breakpoint.condition {
+            match eval_with(condition, self) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(_) => return Some(StopReason::Error { message: "Breakpoint condition failed" }),
+            }
+        }
+        let hits = breakpoint.hits + 1;
+        if let Some(stored) = self.debugger.breakpoints.get_mut(&self.pc) {
+            stored.hits = hits;
+        }
+        if hits < breakpoint.min_hits {
+            return None;
+        }
+        match &breakpoint.message {
+            Some(message) => {
+                let message = self.format_message(message);
+                self.debugger.messages.push(message);
+                None
+            }
+            None => Some(StopReason::Breakpoint { address: self.pc }),
+        }
+    }
+
+    /*
+     * Replaces every {expression} by its value in hex,
+     * e.g. "A={A} at {PC}" becomes "A=2AH at 100H"
+     */
+    pub fn format_message(&self, message: &str) -> String {
+        let mut formatted = String::new();
+        let mut rest = message;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            formatted.push_str(&rest[..start]);
+            match eval_with(&rest[start + 1..end], self) {
+                Ok(value) => formatted.push_str(&format!("{:X}H", value)),
+                Err(_) => formatted.push('?'),
+            }
+            rest = &rest[end + 1..];
+        }
+        formatted.push_str(rest);
+        formatted
+    }
+
+    // Breakpoints are only added with conditions that can be evaluated now
+    fn check_condition(&self, condition: &str) -> EResult<()> {
+        eval_with(condition, self).map(|_| ()).map_err(|_| "Invalid breakpoint condition")
+    }
+
+    // Called on every memory access of an instruction
+    pub(super) fn watch_memory(&self, address: u16, access: Access) {
+        let triggered = self
+            .debugger
+            .watchpoints
+            .iter()
+            .any(|(range, watched)| watched.covers(access) && range.contains(&address));
+        if triggered && self.debugger.hit.get().is_none() {
+            self.debugger.hit.set(Some(StopReason::Watchpoint { address, access }));
+        }
+    }
+
+    // Called on every IN and OUT
+    pub(super) fn watch_port(&self, port: u8, access: Access) {
+        let triggered = self.debugger.port_watchpoints.get(&port).is_some_and(|watched| watched.covers(access));
+        if triggered && self.debugger.hit.get().is_none() {
+            self.debugger.hit.set(Some(StopReason::Port { port, access }));
+        }
+    }
+}
+
+/*
+ * Names in conditions and tracepoint messages: the registers A, B, C, D, E,
+ * H, L, the pairs BC, DE, HL, PSW, SP, PC, the flags Z, CY, S, P, AC and the
+ * symbols set with set_symbols. Registers and flags are case insensitive.
+ */
+impl Context for Emulator {
+    fn name(&self, name: &str) -> Option<i32> {
+        let value = match name.to_ascii_uppercase().as_str() {
+            "A" => self.reg['a'] as u16,
+            "B" => self.reg['b'] as u16,
+            "C" => self.reg['c'] as u16,
+            "D" => self.reg['d'] as u16,
+            "E" => self.reg['e'] as u16,
+            "H" => self.reg['h'] as u16,
+            "L" => self.reg['l'] as u16,
+            "BC" => self.reg["bc"],
+            "DE" => self.reg["de"],
+            "HL" => self.reg["hl"],
+            "PSW" => self.reg["psw"],
+            "SP" => self.sp,
+            "PC" => self.pc,
+            "Z" => self.reg.get_flag("zero") as u16,
+            "CY" => self.reg.get_flag("carry") as u16,
+            "S" => self.reg.get_flag("sign") as u16,
+            "P" => self.reg.get_flag("parity") as u16,
+            "AC" => self.reg.get_flag("aux") as u16,
+            _ => {
+                let (address, _) = self.symbols.iter().find(|(_, names)| names.iter().any(|symbol| symbol == name))?;
+                *address
+            }
+        };
+        Some(value as i32)
+    }
+
+    // Reads without the side effects of memory mapped devices, like the code view
+    fn memory(&self, address: u16) -> i32 {
+        self.peek(address) as i32
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.debugger.breakpoints.insert(address, Breakpoint::new());
+    }
+
+    /*
+     * Breakpoint that only stops if the condition isn't 0, see Context for
+     * Emulator for the names, e.g. "A == 0 && HL > 2000H" or "[SP] == LOOP"
+     */
+    pub fn add_conditional_breakpoint(&mut self, address: u16, condition: &str) -> EResult<()> {
+        self.check_condition(condition)?;
+        let breakpoint = Breakpoint { condition: Some(condition.to_string()), ..Breakpoint::new() };
+        self.debugger.breakpoints.insert(address, breakpoint);
+        Ok(())
+    }
+
+    /*
+     * Logs the formatted message instead of stopping, the condition is
+     * optional and ignored when empty
+     */
+    pub fn add_tracepoint(&mut self, address: u16, message: &str, condition: &str) -> EResult<()> {
+        let condition = match condition.trim().is_empty() {
+            true => None,
+            false => {
+                self.check_condition(condition)?;
+                Some(condition.to_string())
+            }
+        };
+        let breakpoint = Breakpoint { condition, message: Some(message.to_string()), ..Breakpoint::new() };
+        self.debugger.breakpoints.insert(address, breakpoint);
+        Ok(())
+    }
+
+    // Ignores the breakpoint until it was hit count times
+    pub fn set_breakpoint_hit_count(&mut self, address: u16, count: usize) -> EResult<()> {
+        let breakpoint = self.debugger.breakpoints.get_mut(&address).ok_or("No breakpoint at this address")?;
+        breakpoint.min_hits = count;
+        Ok(())
+    }
+
+    pub fn breakpoint_hits(&self, address: u16) -> usize {
+        self.debugger.breakpoints.get(&address).map_or(0, |breakpoint| breakpoint.hits)
+    }
+
+    // Messages logged by tracepoints since the last call, one per line
+    pub fn take_trace_messages(&mut self) -> String {
+        let mut messages = self.debugger.messages.join("\n");
+        if !messages.is_empty() {
+            messages.push('\n');
+        }
+        self.debugger.messages.clear();
+        messages
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.debugger.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        self.debugger.breakpoints.keys().copied().collect()
+    }
+
+    // Watches start to end, both included
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, access: Access) {
+        self.debugger.watchpoints.push((start..=end, access));
+    }
+
+    // Removes the watchpoints of exactly that range
+    pub fn remove_watchpoint(&mut self, start: u16, end: u16) -> bool {
+        let count = self.debugger.watchpoints.len();
+        self.debugger.watchpoints.retain(|(range, _)| *range != (start..=end));
+        self.debugger.watchpoints.len() != count
+    }
+
+    pub fn watch_port_access(&mut self, port: u8, access: Access) {
+        self.debugger.port_watchpoints.insert(port, access);
+    }
+
+    pub fn unwatch_port_access(&mut self, port: u8) -> bool {
+        self.debugger.port_watchpoints.remove(&port).is_some()
+    }
+
+    // run stops before the instruction at the address is executed
+    pub fn add_trap(&mut self, address: u16) {
+        self.debugger.traps.insert(address);
+    }
+
+    pub fn remove_trap(&mut self, address: u16) -> bool {
+        self.debugger.traps.remove(&address)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.debugger.watchpoints.clear();
+        self.debugger.port_watchpoints.clear();
+    }
+
+    /*
+     * run for the frontend, e.g. once per animation frame. The result is
+     * returned as JSON, e.g.
+     * {"reason":"breakpoint","address":256,"instructions":10,"cycles":70}
+     */
+    pub fn run_instructions(&mut self, count: u32) -> String {
+        serde_json::to_string(&self.run(Limit::Instructions(count as u64))).unwrap()
+    }
+
+    pub fn run_cycles(&mut self, cycles: u32) -> String {
+        serde_json::to_string(&self.run(Limit::Cycles(cycles as u64))).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::io::DevNull;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // LOOP: LDA 100H, INR A, STA 101H, OUT 2, JMP LOOP
+    const PROGRAM: [u8; 12] = [0x3a, 0x00, 0x01, 0x3c, 0x32, 0x01, 0x01, 0xd3, 0x02, 0xc3, 0x00, 0x00];
+
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new();
+        emu.load_ram(PROGRAM.to_vec(), 0);
+        emu.register_output_device(Rc::new(RefCell::new(DevNull {})), 2).unwrap();
+        emu
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut emu = emulator();
+        assert_eq!(StopReason::Limit, emu.run(Limit::Instructions(100)).reason);
+        emu.add_breakpoint(7);
+        emu.add_breakpoint(0);
+        assert_eq!(vec![0, 7], emu.breakpoints());
+        assert_eq!(StopReason::Breakpoint { address: 0 }, emu.run(Limit::Instructions(100)).reason);
+        // continuing doesn't stop at the same breakpoint again
+        assert_eq!(StopReason::Breakpoint { address: 7 }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(7, emu.pc);
+        assert_eq!(StopReason::Breakpoint { address: 0 }, emu.run(Limit::Instructions(100)).reason);
+
+        assert!(emu.remove_breakpoint(0));
+        assert!(!emu.remove_breakpoint(0));
+        assert_eq!(StopReason::Breakpoint { address: 7 }, emu.run(Limit::Instructions(100)).reason);
+        emu.clear_breakpoints();
+        assert_eq!(StopReason::Limit, emu.run(Limit::Instructions(3)).reason);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut emu = emulator();
+        // instruction fetches don't count as reads
+        emu.add_watchpoint(0, 0xff, Access::Read);
+        emu.add_watchpoint(0x100, 0x100, Access::Read);
+        assert_eq!(StopReason::Watchpoint { address: 0x100, access: Access::Read }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(3, emu.pc);
+
+        emu.add_watchpoint(0x100, 0x1ff, Access::Write);
+        assert_eq!(StopReason::Watchpoint { address: 0x101, access: Access::Write }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(7, emu.pc);
+
+        emu.watch_port_access(2, Access::ReadWrite);
+        assert_eq!(StopReason::Port { port: 2, access: Access::Write }, emu.run(Limit::Instructions(100)).reason);
+        assert!(emu.remove_watchpoint(0x100, 0x100));
+        assert!(emu.unwatch_port_access(2));
+        emu.clear_watchpoints();
+        assert_eq!(StopReason::Limit, emu.run(Limit::Instructions(100)).reason);
+    }
+
+    #[test]
+    fn stops() {
+        let mut emu = Emulator::new();
+        // MVI A,1, HLT, OUT 9
+        emu.load_ram(vec![0x3e, 0x01, 0x76, 0xd3, 0x09], 0);
+        assert_eq!(StopReason::Halted, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(StopReason::Halted, emu.run(Limit::Instructions(100)).reason);
+        emu.running = true;
+        assert_eq!(StopReason::Error { message: "No device registered at this port" }, emu.run(Limit::Instructions(100)).reason);
+
+        emu.add_breakpoint(0);
+        emu.pc = 0;
+        emu.running = true;
+        assert_eq!(StopReason::Breakpoint { address: 0 }, emu.run(Limit::Instructions(1)).reason);
+        assert_eq!("{\"reason\":\"halted\",\"instructions\":2,\"cycles\":14}", emu.run_instructions(100));
+        emu.running = true;
+        emu.pc = 0;
+        emu.remove_breakpoint(0);
+        emu.add_breakpoint(2);
+        assert_eq!(
+            "{\"reason\":\"breakpoint\",\"address\":2,\"instructions\":1,\"cycles\":7}",
+            emu.run_instructions(100)
+        );
+    }
+
+    // MVI A,3, CALL SUB, DCR A, JNZ 2, HLT, SUB: RET
+    const COUNTDOWN: [u8; 11] = [0x3e, 0x03, 0xcd, 0x0a, 0x00, 0x3d, 0xc2, 0x02, 0x00, 0x76, 0xc9];
+
+    fn countdown() -> Emulator {
+        let mut emu = Emulator::new();
+        emu.load_ram(COUNTDOWN.to_vec(), 0);
+        emu.sp = 0x100;
+        let symbols = [(String::from("SUB"), 10), (String::from("BACK"), 5)].iter().cloned().collect();
+        emu.set_symbols(&symbols);
+        emu
+    }
+
+    #[test]
+    fn conditions() {
+        let mut emu = countdown();
+        emu.add_conditional_breakpoint(10, "A == 1 && [SP] == BACK && sp < 100H").unwrap();
+        assert_eq!(StopReason::Breakpoint { address: 10 }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(1, emu.reg['a']);
+        assert_eq!(1, emu.breakpoint_hits(10));
+        assert_eq!(StopReason::Halted, emu.run(Limit::Instructions(100)).reason);
+
+        assert_eq!(Err("Invalid breakpoint condition"), emu.add_conditional_breakpoint(10, "X == 1"));
+        assert_eq!(Err("Invalid breakpoint condition"), emu.add_conditional_breakpoint(10, "A =="));
+
+        // flags can be used as well
+        let mut emu = countdown();
+        emu.add_conditional_breakpoint(6, "Z").unwrap();
+        assert_eq!(StopReason::Breakpoint { address: 6 }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(0, emu.reg['a']);
+    }
+
+    #[test]
+    fn hit_counts() {
+        let mut emu = countdown();
+        emu.add_breakpoint(5);
+        assert_eq!(Err("No breakpoint at this address"), emu.set_breakpoint_hit_count(4, 2));
+        emu.set_breakpoint_hit_count(5, 2).unwrap();
+        assert_eq!(StopReason::Breakpoint { address: 5 }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(2, emu.reg['a']);
+        assert_eq!(StopReason::Breakpoint { address: 5 }, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(3, emu.breakpoint_hits(5));
+    }
+
+    #[test]
+    fn tracepoints() {
+        let mut emu = countdown();
+        emu.add_tracepoint(10, "A={A} return {[SP]}", "").unwrap();
+        emu.add_tracepoint(5, "last", "A == 1").unwrap();
+        assert_eq!(StopReason::Halted, emu.run(Limit::Instructions(100)).reason);
+        assert_eq!(
+            "A=3H return 5H\nA=2H return 5H\nA=1H return 5H\nlast\n",
+            emu.take_trace_messages()
+        );
+        assert_eq!("", emu.take_trace_messages());
+        assert_eq!("? {A", emu.format_message("{nope} {A"));
+    }
+
+    #[test]
+    fn budgets() {
+        let mut emu = emulator();
+        // LDA takes 13 cycles, INR 5, STA 13, OUT 10 and JMP 10
+        let result = emu.run(Limit::Cycles(30));
+        assert_eq!(RunResult { reason: StopReason::Limit, instructions: 3, cycles: 31 }, result);
+        let result = emu.run(Limit::Instructions(7));
+        assert_eq!(RunResult { reason: StopReason::Limit, instructions: 7, cycles: 71 }, result);
+        assert_eq!(StopReason::Limit, emu.run(Limit::Cycles(0)).reason);
+        assert_eq!("{\"reason\":\"limit\",\"instructions\":1,\"cycles\":13}", emu.run_cycles(1));
+    }
+
+    #[test]
+    fn traps() {
+        let mut emu = countdown();
+        emu.add_trap(10);
+        assert_eq!(StopReason::Trap { address: 10 }, emu.run(Limit::Instructions(100)).reason);
+        // the host handles the call, continuing executes the RET
+        emu.reg['a'] = 1;
+        let result = emu.run(Limit::Instructions(100));
+        assert_eq!(RunResult { reason: StopReason::Halted, instructions: 4, cycles: 32 }, result);
+        assert!(emu.remove_trap(10));
+    }
+}
@@ -1,10 +1,12 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, ops::RangeInclusive, rc::Rc};
 
-use super::{EResult, Emulator, InputDevice, OutputDevice};
+use super::debugger::Access;
+use super::{DeviceState, EResult, Emulator, InputDevice, MemoryDevice, OutputDevice};
 
 impl Emulator {
 
     pub fn input(&mut self, port: u8) -> EResult<()> {
+        self.watch_port(port, Access::Read);
         match &self.input_devices[port as usize] {
             Some(device) => self.reg['a'] = device.borrow().read(),
             None => return Err("No device registered at this port")
@@ -13,6 +15,8 @@ impl Emulator {
     }
 
     pub fn output(&mut self, port: u8) -> EResult<()> {
+        self.watch_port(port, Access::Write);
+        self.record_devices();
         match &self.output_devices[port as usize] {
             Some(device) => device.borrow_mut().write(self.reg['a']),
             None => return Err("No device registered at this port")
@@ -29,11 +33,75 @@ impl Emulator {
         self.output_devices[port] = Some(device);
         Ok(())
     }
+
+    pub fn register_memory_device(&mut self, device: Rc<RefCell<dyn MemoryDevice>>, range: RangeInclusive<u16>) -> EResult<()> {
+        let overlaps = self.memory_devices.iter().any(|(used, _)| used.start() <= range.end() && range.start() <= used.end());
+        if overlaps {
+            return Err("Address range is already used by a device");
+        }
+        self.memory_devices.push((range, device));
+        Ok(())
+    }
+
+    // Includes the device in save states, the name identifies it when loading
+    pub fn register_device_state(&mut self, name: &str, device: Rc<RefCell<dyn DeviceState>>) -> EResult<()> {
+        if self.stateful_devices.iter().any(|(used, _)| used == name) {
+            return Err("Name is already used by a device");
+        }
+        self.stateful_devices.push((name.to_string(), device));
+        Ok(())
+    }
+
+    // Reads go to a memory mapped device if one covers the address, otherwise to RAM
+    pub fn read_memory(&self, address: u16) -> u8 {
+        self.watch_memory(address, Access::Read);
+        self.fetch(address)
+    }
+
+    // Reads of instructions, which don't trigger watchpoints
+    pub(super) fn fetch(&self, address: u16) -> u8 {
+        match self.memory_device(address) {
+            Some((start, device)) => device.borrow_mut().read(address - start),
+            None => self.ram[address],
+        }
+    }
+
+    // Reads for inspecting memory, without the side effects of memory mapped devices
+    pub fn peek(&self, address: u16) -> u8 {
+        match self.memory_device(address) {
+            Some((start, device)) => device.borrow().peek(address - start),
+            None => self.ram[address],
+        }
+    }
+
+    pub fn write_memory(&mut self, address: u16, byte: u8) {
+        self.watch_memory(address, Access::Write);
+        match self.memory_device(address) {
+            Some((start, device)) => {
+                let device = device.clone();
+                self.record_devices();
+                device.borrow_mut().write(address - start, byte);
+            }
+            None => {
+                self.record_memory(address);
+                self.ram[address] = byte;
+            }
+        }
+    }
+
+    fn memory_device(&self, address: u16) -> Option<(u16, &Rc<RefCell<dyn MemoryDevice>>)> {
+        self.memory_devices
+            .iter()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(range, device)| (*range.start(), device))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::emulator::callstack::CallKind;
+    use crate::core::emulator::debugger::{Limit, StopReason};
 
     struct Logger {
         last: u8
@@ -90,4 +158,82 @@ mod tests {
         assert_eq!(logger.borrow().last(), 42);
         assert_eq!(emu.output(1), Err("No device registered at this port"));
     }
+
+    // A serial port with a data register followed by a status register
+    struct Uart {
+        sent: Vec<u8>,
+    }
+
+    impl MemoryDevice for Uart {
+        fn read(&mut self, offset: u16) -> u8 {
+            match offset {
+                1 => 0x01,
+                _ => 0,
+            }
+        }
+
+        fn write(&mut self, offset: u16, byte: u8) {
+            if offset == 0 {
+                self.sent.push(byte);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_mapped() {
+        let mut emu = Emulator::new();
+        let uart = Rc::new(RefCell::new(Uart { sent: Vec::new() }));
+        emu.register_memory_device(uart.clone(), 0x8000..=0x8001).expect("");
+        assert_eq!(
+            emu.register_memory_device(uart.clone(), 0x7000..=0x8000),
+            Err("Address range is already used by a device")
+        );
+
+        // MVI A,48H, STA 8000H, LDA 8001H, MVI M,49H with HL = 8000H
+        emu.load_ram(vec![0x3e, 0x48, 0x32, 0x00, 0x80, 0x3a, 0x01, 0x80, 0x21, 0x00, 0x80, 0x36, 0x49], 0);
+        for _ in 0..5 {
+            emu.execute_next().expect("");
+        }
+        assert_eq!(vec![0x48, 0x49], uart.borrow().sent);
+        assert_eq!(emu.reg['a'], 0x01);
+        // RAM behind the device stays untouched
+        assert_eq!(emu.ram[0x8000], 0);
+        assert_eq!(emu.read_memory(0x8002), 0);
+    }
+
+    // Reading the data register takes the next byte out of the queue
+    struct Fifo {
+        queue: std::collections::VecDeque<u8>,
+    }
+
+    impl MemoryDevice for Fifo {
+        fn read(&mut self, _offset: u16) -> u8 {
+            self.queue.pop_front().unwrap_or(0)
+        }
+
+        fn write(&mut self, _offset: u16, byte: u8) {
+            self.queue.push_back(byte);
+        }
+
+        fn peek(&self, offset: u16) -> u8 {
+            self.queue.get(offset as usize).copied().unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn inspection_has_no_side_effects() {
+        let mut emu = Emulator::new();
+        let fifo = Rc::new(RefCell::new(Fifo { queue: vec![0x10, 0x00].into() }));
+        emu.register_memory_device(fifo.clone(), 0x8000..=0x8001).unwrap();
+        assert_eq!(0x10, emu.peek(0x8000));
+
+        // a return address in the device
+        emu.sp = 0x8000;
+        emu.enter_frame(CallKind::Call, 0x20);
+        assert_eq!(0x10, emu.backtrace()[1].address);
+        assert_eq!(0x10, emu.disassemble_at(0x8000, 1)[0].bytes[0]);
+        emu.add_conditional_breakpoint(0, "[8000H] == 10H").unwrap();
+        assert_eq!(StopReason::Breakpoint { address: 0 }, emu.run(Limit::Instructions(1)).reason);
+        assert_eq!(2, fifo.borrow().queue.len());
+    }
 }
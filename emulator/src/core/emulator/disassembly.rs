@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{EResult, Emulator};
+use crate::core::instruction::Instruction;
+
+// One line for every address, more can't be different
+const MAX_LINES: usize = 0x10000;
+// Decoding backwards decodes again from every possible start
+const MAX_LINES_BEFORE: usize = 0x100;
+
+/*
+ * One decoded instruction of the emulator's memory
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CodeLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // symbols declared at the address
+    pub labels: Vec<String>,
+    // bank the instruction was read from, if the address is banked
+    pub bank: Option<usize>,
+}
+
+impl Emulator {
+    /*
+     * Sets the symbols shown next to the code, e.g. the labels returned by
+     * Assembler::assemble_with_labels
+     */
+    pub fn set_symbols(&mut self, symbols: &HashMap<String, u16>) {
+        self.symbols.clear();
+        for (name, &address) in symbols {
+            self.symbols.entry(address).or_default().push(name.clone());
+        }
+        for names in self.symbols.values_mut() {
+            names.sort();
+        }
+    }
+
+    pub fn symbols_at(&self, address: u16) -> &[String] {
+        self.symbols.get(&address).map_or(&[], |names| names.as_slice())
+    }
+
+    /*
+     * Decodes count instructions starting at the address from the current
+     * memory, so changes made by the program itself show up. Undefined opcodes
+     * are decoded as the instructions that get executed for them. At most
+     * MAX_LINES are decoded.
+     */
+    pub fn disassemble_at(&self, address: u16, count: usize) -> Vec<CodeLine> {
+        let count = count.min(MAX_LINES);
+        let mut lines = Vec::with_capacity(count);
+        let mut address = address;
+        for _ in 0..count {
+            let line = self.code_line(address);
+            address = address.wrapping_add(line.bytes.len() as u16);
+            lines.push(line);
+        }
+        lines
+    }
+
+    /*
+     * Decodes count instructions where the last one is the instruction at the
+     * address. Decoding backwards is ambiguous, so the earliest start whose
+     * instructions line up with the address wins. At most MAX_LINES_BEFORE
+     * are decoded.
+     */
+    pub fn disassemble_before(&self, address: u16, count: usize) -> Vec<CodeLine> {
+        let count = count.min(MAX_LINES_BEFORE);
+        let mut best: Vec<CodeLine> = Vec::new();
+        if count == 0 {
+            return best;
+        }
+        // instructions are at most 3 bytes long
+        for distance in (0..=3 * (count - 1)).rev() {
+            let mut lines = Vec::new();
+            let mut offset = 0;
+            while offset < distance {
+                let line = self.code_line(address.wrapping_sub((distance - offset) as u16));
+                offset += line.bytes.len();
+                lines.push(line);
+            }
+            if offset != distance {
+                continue;
+            }
+            lines.push(self.code_line(address));
+            if lines.len() >= count {
+                return lines.split_off(lines.len() - count);
+            }
+            if lines.len() > best.len() {
+                best = lines;
+            }
+        }
+        best
+    }
+
+    fn code_line(&self, address: u16) -> CodeLine {
+        let bytes: Vec<u8> = (0..3).map(|offset| self.peek(address.wrapping_add(offset))).collect();
+        // every opcode decodes once the aliases are included
+        let instruction = Instruction::decode_with_aliases(&bytes).unwrap();
+        CodeLine {
+            address,
+            bytes: bytes[..instruction.length()].to_vec(),
+            text: instruction.to_string(),
+            labels: self.symbols_at(address).to_vec(),
+            bank: self.ram.bank_at(address),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /*
+     * Takes the symbols as a JSON object mapping names to addresses
+     */
+    pub fn load_symbols(&mut self, json: &str) -> EResult<()> {
+        let symbols: HashMap<String, u16> = serde_json::from_str(json).map_err(|_| "Invalid symbol table")?;
+        self.set_symbols(&symbols);
+        Ok(())
+    }
+
+    /*
+     * Code view for the frontend as JSON, count instructions starting at the
+     * address or ending with the instruction at the address
+     */
+    pub fn disassemble_memory(&self, address: u16, count: usize, ending: bool) -> String {
+        let lines = match ending {
+            true => self.disassemble_before(address, count),
+            false => self.disassemble_at(address, count),
+        };
+        serde_json::to_string(&lines).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+
+    fn load(code: &str) -> Emulator {
+        let (bytes, labels) = Assembler::new(code).assemble_with_labels().unwrap();
+        let mut emu = Emulator::new();
+        emu.load_ram(bytes, 0);
+        emu.set_symbols(&labels);
+        emu
+    }
+
+    const PROGRAM: &str = "START: MVI A,1\nLOOP: INR A\nJMP LOOP\nHLT\nEND";
+
+    #[test]
+    fn forwards() {
+        let emu = load(PROGRAM);
+        let lines = emu.disassemble_at(0, 3);
+        assert_eq!(
+            CodeLine { address: 0, bytes: vec![0x3e, 0x01], text: String::from("MVI A,1H"), labels: vec![String::from("START")], bank: None },
+            lines[0]
+        );
+        assert_eq!(vec![String::from("LOOP")], lines[1].labels);
+        assert_eq!(2, lines[1].address);
+        assert_eq!(vec![0xc3, 0x02, 0x00], lines[2].bytes);
+        assert!(lines[2].labels.is_empty());
+    }
+
+    #[test]
+    fn backwards() {
+        let emu = load(PROGRAM);
+        let lines = emu.disassemble_before(6, 3);
+        let addresses: Vec<u16> = lines.iter().map(|line| line.address).collect();
+        assert_eq!(vec![2, 3, 6], addresses);
+        assert_eq!("HLT", lines[2].text);
+
+        // decoding wraps around the end of memory
+        let addresses: Vec<u16> = emu.disassemble_before(2, 5).iter().map(|line| line.address).collect();
+        assert_eq!(vec![0xfffd, 0xfffe, 0xffff, 0, 2], addresses);
+        assert!(emu.disassemble_before(2, 0).is_empty());
+    }
+
+    #[test]
+    fn bounded_counts() {
+        let emu = Emulator::new();
+        assert_eq!(MAX_LINES, emu.disassemble_at(0, usize::MAX).len());
+        assert_eq!(MAX_LINES_BEFORE, emu.disassemble_before(0, usize::MAX).len());
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let mut emu = load(PROGRAM);
+        assert_eq!("INR A", emu.disassemble_at(2, 1)[0].text);
+        emu.ram[2] = 0x08;
+        assert_eq!("NOP", emu.disassemble_at(2, 1)[0].text);
+    }
+
+    #[test]
+    fn json() {
+        let mut emu = load(PROGRAM);
+        assert!(emu.load_symbols("[1, 2]").is_err());
+        emu.load_symbols("{\"HALT\": 6}").unwrap();
+        assert!(emu.symbols_at(0).is_empty());
+        assert_eq!(
+            "[{\"address\":6,\"bytes\":[118],\"text\":\"HLT\",\"labels\":[\"HALT\"],\"bank\":null}]",
+            emu.disassemble_memory(6, 1, false)
+        );
+    }
+}
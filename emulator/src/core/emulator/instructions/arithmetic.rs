@@ -18,7 +18,7 @@ impl Emulator {
 
     fn add_memory(&mut self, use_carry: bool) {
         let address = self.reg["hl"];
-        let mut memory_value = self.ram[address] as u16;
+        let mut memory_value = self.read_memory(address) as u16;
         self.add_value(memory_value, use_carry);
     }
 
@@ -55,7 +55,7 @@ impl Emulator {
     
     fn sub_memory(&mut self, use_carry: bool) {
         let address = self.reg["hl"];
-        let mut memory_value = self.ram[address];
+        let mut memory_value = self.read_memory(address);
         self.sub_value(memory_value, use_carry);
     }
 
@@ -89,7 +89,7 @@ impl Emulator {
     pub fn inr(&mut self, register: char) {
         let prev: u8;
         if register == 'm' {
-            prev = self.ram[self.reg["hl"]];
+            prev = self.read_memory(self.reg["hl"]);
         } else {
             prev = self.reg[register];
         }
@@ -99,7 +99,7 @@ impl Emulator {
         self.reg.set_flag("parity", result.count_ones() & 1 == 0);
         self.reg.set_flag("aux", ((prev & 0x0F) + 1) > 0x0F);
         if register == 'm' {
-            self.ram[self.reg["hl"]] = result;
+            self.write_memory(self.reg["hl"], result);
         } else {
             self.reg[register] = result;
         }
@@ -108,7 +108,7 @@ impl Emulator {
     pub fn dcr(&mut self, register: char) {
         let prev: u8;
         if register == 'm' {
-            prev = self.ram[self.reg["hl"]];
+            prev = self.read_memory(self.reg["hl"]);
         } else {
             prev = self.reg[register];
         }
@@ -118,7 +118,7 @@ impl Emulator {
         self.reg.set_flag("parity", result.count_ones() & 1 == 0);
         self.reg.set_flag("aux", ((prev & 0x0F) + 0x0F) > 0x0F);
         if register == 'm' {
-            self.ram[self.reg["hl"]] = result;
+            self.write_memory(self.reg["hl"], result);
         } else {
             self.reg[register] = result;
         }
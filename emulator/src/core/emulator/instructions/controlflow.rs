@@ -1,4 +1,5 @@
 use super::super::{EResult, Emulator};
+use super::super::callstack::CallKind;
 
 const REGISTERS: [char; 8] = ['b', 'c', 'd', 'e', 'h', 'l', 'm', 'a'];
 
@@ -7,7 +8,7 @@ impl Emulator {
         if !self.reg.get_flag(flag) {
             self.pc = self.read_addr()?;
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         Ok(())
     }
@@ -16,7 +17,7 @@ impl Emulator {
         if self.reg.get_flag(flag) {
             self.pc = self.read_addr()?;
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         Ok(())
     }
@@ -26,7 +27,7 @@ impl Emulator {
             self.call_imm()?;
             Ok(17)
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
             Ok(11)
         }
     }
@@ -36,7 +37,7 @@ impl Emulator {
             self.call_imm()?;
             Ok(17)
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
             Ok(11)
         }
     }
@@ -44,12 +45,15 @@ impl Emulator {
     pub fn call_imm(&mut self) -> EResult<()> {
         let adr = self.read_addr()?;
         self.push(self.pc)?;
+        self.enter_frame(CallKind::Call, adr);
         self.pc = adr;
         Ok(())
     }
 
+    // RST, also used by interrupts
     pub fn call(&mut self, adr: u16) -> EResult<()> {
         self.push(self.pc)?;
+        self.enter_frame(CallKind::Rst, adr);
         self.pc = adr;
         Ok(())
     }
@@ -8,7 +8,7 @@ impl Emulator {
         let register = REGISTERS[index];
         if register == 'm' {
             let address = self.reg["hl"];
-            self.and_value(self.ram[address]);
+            self.and_value(self.read_memory(address));
         } else {
             self.and_value(self.reg[register]);
         }
@@ -27,7 +27,7 @@ impl Emulator {
         let register = REGISTERS[index];
         if register == 'm' {
             let address = self.reg["hl"];
-            self.xor_value(self.ram[address]);
+            self.xor_value(self.read_memory(address));
         } else {
             self.xor_value(self.reg[register]);
         }
@@ -45,7 +45,7 @@ impl Emulator {
         let register = REGISTERS[index];
         if register == 'm' {
             let address = self.reg["hl"];
-            self.or_value(self.ram[address]);
+            self.or_value(self.read_memory(address));
         } else {
             self.or_value(self.reg[register]);
         }
@@ -63,7 +63,7 @@ impl Emulator {
         let register = REGISTERS[index];
         if register == 'm' {
             let address = self.reg["hl"];
-            self.cmp_value(self.ram[address]);
+            self.cmp_value(self.read_memory(address));
         } else {
             self.cmp_value(self.reg[register]);
         }
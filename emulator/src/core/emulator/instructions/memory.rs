@@ -2,21 +2,19 @@ use super::super::{Emulator, EResult};
 
 impl Emulator {
     pub fn stax(&mut self, register: &str) {
-        self.ram[self.reg[register]] = self.reg['a'];
+        self.write_memory(self.reg[register], self.reg['a']);
     }
     
     pub fn ldax(&mut self, register: &str) {
-        self.reg['a'] = self.ram[self.reg[register]];
+        self.reg['a'] = self.read_memory(self.reg[register]);
     }
     
+    // The stack wraps around the end of the address space
     pub fn push(&mut self, val: u16) -> EResult<()> {
-        if self.sp < 2 {
-            return Err("PUSH: No more stack space");
-        }
-        self.sp -= 1;
-        self.ram[self.sp] = (val >> 8) as u8;
-        self.sp -= 1;
-        self.ram[self.sp] = val as u8;
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_memory(self.sp, (val >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_memory(self.sp, val as u8);
         Ok(())
     }
 
@@ -25,13 +23,10 @@ impl Emulator {
     }
 
     pub fn pop(&mut self) -> EResult<u16> {
-        if self.sp as u32 + 2 > self.ram.size() as u32 {
-            return Err("POP: No return address on the stack");
-        }
-        let low = self.ram[self.sp] as u16;
-        self.sp += 1;
-        let high = self.ram[self.sp] as u16;
-        self.sp += 1;
+        let low = self.read_memory(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read_memory(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
         Ok((high << 8) | low)
     }
     
@@ -41,30 +36,30 @@ impl Emulator {
     }
     
     pub fn shld(&mut self, address: u16) {
-        self.ram[address] = self.reg['l'];
-        self.ram[address+1] = self.reg['h'];
+        self.write_memory(address, self.reg['l']);
+        self.write_memory(address.wrapping_add(1), self.reg['h']);
     }
     
     pub fn lhld(&mut self, address: u16) {
-        self.reg['l'] = self.ram[address];
-        self.reg['h'] = self.ram[address+1];
+        self.reg['l'] = self.read_memory(address);
+        self.reg['h'] = self.read_memory(address.wrapping_add(1));
     }
     
     pub fn sta(&mut self, address: u16) {
-        self.ram[address] = self.reg['a'];
+        self.write_memory(address, self.reg['a']);
     }
     
     pub fn lda(&mut self, address: u16) {
-        self.reg['a'] = self.ram[address];
+        self.reg['a'] = self.read_memory(address);
     }
     
     pub fn xthl(&mut self) {
         let tempL = self.reg['l'];
         let tempH = self.reg['h'];
-        self.reg['l'] = self.ram[self.sp];
-        self.reg['h'] = self.ram[self.sp+1];
-        self.ram[self.sp] = tempL;
-        self.ram[self.sp+1] = tempH;
+        self.reg['l'] = self.read_memory(self.sp);
+        self.reg['h'] = self.read_memory(self.sp.wrapping_add(1));
+        self.write_memory(self.sp, tempL);
+        self.write_memory(self.sp.wrapping_add(1), tempH);
     }
 }
 
@@ -109,11 +104,17 @@ mod tests {
         assert_eq!(emu.sp, 0xfffd);
         assert_eq!(0xabcd, emu.pop().expect("Fuck"));
         assert_eq!(emu.sp, 0xffff);
-        assert_eq!(emu.pop(), Err("POP: No return address on the stack"));
 
-        emu.sp = 0x1;
-
-        assert_eq!(emu.push(0x1234), Err("PUSH: No more stack space"));
+        // the stack wraps around in both directions
+        assert_eq!(0, emu.pop().expect("Fuck"));
+        assert_eq!(emu.sp, 0x0001);
+        emu.push(0x1234).expect("Push failed");
+        emu.push(0x5678).expect("Push failed");
+        assert_eq!(emu.sp, 0xfffd);
+        assert_eq!(emu.ram[0x0000], 0x12);
+        assert_eq!(emu.ram[0xffff], 0x34);
+        assert_eq!(0x5678, emu.pop().expect("Fuck"));
+        assert_eq!(0x1234, emu.pop().expect("Fuck"));
     }
     
     #[test]
@@ -12,7 +12,7 @@ impl Emulator {
         // Move byte 2 to address in HL
         let byte = self.read_byte()?;
         let adr = self.reg["hl"];
-        self.ram[adr] = byte;
+        self.write_memory(adr, byte);
         Ok(())
     }
 
@@ -21,10 +21,10 @@ impl Emulator {
         let dst_idx = opcode_rel >> 3;
         let src_idx = opcode_rel - (dst_idx << 3);
         if dst_idx == 6 {
-            self.ram[self.reg["hl"]] = self.reg[REGISTERS[src_idx as usize]];
+            self.write_memory(self.reg["hl"], self.reg[REGISTERS[src_idx as usize]]);
         } else {
             if src_idx == 6 {
-                self.reg[REGISTERS[dst_idx as usize]] = self.ram[self.reg["hl"]];
+                self.reg[REGISTERS[dst_idx as usize]] = self.read_memory(self.reg["hl"]);
             } else {
                 self.mov(REGISTERS[dst_idx as usize], REGISTERS[src_idx as usize]);
             }
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::callstack::Frame;
+use super::state::State;
+use super::{EResult, Emulator};
+use crate::core::register::RegisterArray;
+
+/*
+ * What an instruction changed, enough to undo it
+ */
+struct Entry {
+    step: u64,
+    // CPU before the instruction
+    pc: u16,
+    sp: u16,
+    reg: RegisterArray,
+    running: bool,
+    interrupts_enabled: bool,
+    // previous values of the written bytes, in the order of the writes
+    memory: Vec<(u16, u8)>,
+    // states of the registered devices before the first output to a device
+    devices: Option<Vec<Vec<u8>>>,
+    // depth of the shadow call stack before the instruction and the frames it left
+    call_depth: usize,
+    left_frames: Vec<Frame>,
+}
+
+// Snapshots kept for history older than the journal entries
+const MAX_KEYFRAMES: usize = 16;
+
+/*
+ * History of the executed instructions for stepping backwards.
+ * The last `capacity` instructions can be undone one by one. Every
+ * `keyframe_interval` instructions a snapshot is taken, older history is only
+ * kept as the last MAX_KEYFRAMES of those, which can be returned to but not
+ * stepped through.
+ */
+pub(super) struct Journal {
+    entries: VecDeque<Entry>,
+    // the shadow call stack isn't part of a State, so it is kept alongside
+    keyframes: VecDeque<(u64, State, Vec<Frame>)>,
+    // instructions executed since the journal was enabled
+    step: u64,
+    capacity: usize,
+    keyframe_interval: u64,
+}
+
+impl Journal {
+    fn new(capacity: usize, keyframe_interval: usize) -> Self {
+        Journal {
+            entries: VecDeque::new(),
+            keyframes: VecDeque::new(),
+            step: 0,
+            capacity: capacity.max(1),
+            keyframe_interval: keyframe_interval.max(1) as u64,
+        }
+    }
+
+    // Latest keyframe before the current step
+    fn previous_keyframe(&self) -> Option<usize> {
+        self.keyframes.iter().rposition(|(step, _, _)| *step < self.step)
+    }
+}
+
+impl Emulator {
+    // Called before an instruction is executed
+    pub(super) fn begin_step(&mut self) {
+        let (step, keyframe) = match &self.journal {
+            Some(journal) => (journal.step, journal.step % journal.keyframe_interval == 0),
+            None => return,
+        };
+        let snapshot = match keyframe {
+            true => Some(self.snapshot()),
+            false => None,
+        };
+        let entry = Entry {
+            step,
+            pc: self.pc,
+            sp: self.sp,
+            reg: self.reg,
+            running: self.running,
+            interrupts_enabled: self.interrupts_enabled,
+            memory: Vec::new(),
+            devices: None,
+            call_depth: self.call_stack.len(),
+            left_frames: Vec::new(),
+        };
+        if let Some(journal) = &mut self.journal {
+            if let Some(snapshot) = snapshot {
+                journal.keyframes.push_back((step, snapshot, self.call_stack.clone()));
+                if journal.keyframes.len() > MAX_KEYFRAMES {
+                    journal.keyframes.pop_front();
+                }
+            }
+            journal.entries.push_back(entry);
+            if journal.entries.len() > journal.capacity {
+                journal.entries.pop_front();
+            }
+            journal.step += 1;
+        }
+    }
+
+    // Called before a byte of RAM is overwritten
+    pub(super) fn record_memory(&mut self, address: u16) {
+        if let Some(entry) = self.journal.as_mut().and_then(|journal| journal.entries.back_mut()) {
+            entry.memory.push((address, self.ram[address]));
+        }
+    }
+
+    // Called before a device is written to
+    pub(super) fn record_devices(&mut self) {
+        if let Some(entry) = self.journal.as_mut().and_then(|journal| journal.entries.back_mut()) {
+            if entry.devices.is_none() {
+                entry.devices = Some(self.stateful_devices.iter().map(|(_, device)| device.borrow().save_state()).collect());
+            }
+        }
+    }
+
+    // Called when a frame is dropped from the shadow call stack
+    pub(super) fn record_left_frame(&mut self, frame: Frame) {
+        if let Some(entry) = self.journal.as_mut().and_then(|journal| journal.entries.back_mut()) {
+            entry.left_frames.push(frame);
+        }
+    }
+
+    fn undo(&mut self, entry: Entry) -> EResult<()> {
+        for &(address, byte) in entry.memory.iter().rev() {
+            self.ram[address] = byte;
+        }
+        if let Some(states) = entry.devices {
+            for ((_, device), state) in self.stateful_devices.iter().zip(states.iter()) {
+                device.borrow_mut().load_state(state)?;
+            }
+        }
+        self.pc = entry.pc;
+        self.sp = entry.sp;
+        self.reg = entry.reg;
+        self.running = entry.running;
+        self.interrupts_enabled = entry.interrupts_enabled;
+        // frames are only entered after the ones left by the same instruction
+        self.call_stack.truncate(entry.call_depth - entry.left_frames.len());
+        self.call_stack.extend(entry.left_frames.into_iter().rev());
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /*
+     * Starts recording executed instructions, see Journal for the limits.
+     * Previous history is discarded.
+     */
+    pub fn enable_journal(&mut self, capacity: usize, keyframe_interval: usize) {
+        self.journal = Some(Journal::new(capacity, keyframe_interval));
+    }
+
+    pub fn disable_journal(&mut self) {
+        self.journal = None;
+    }
+
+    pub fn can_step_back(&self) -> bool {
+        match &self.journal {
+            Some(journal) => !journal.entries.is_empty() || journal.previous_keyframe().is_some(),
+            None => false,
+        }
+    }
+
+    /*
+     * Undoes the last instruction. Once the journal is used up this returns
+     * to the previous keyframe, which may be many instructions back.
+     */
+    pub fn step_back(&mut self) -> EResult<()> {
+        let journal = self.journal.as_mut().ok_or("Journal is disabled")?;
+        if let Some(entry) = journal.entries.pop_back() {
+            journal.step = entry.step;
+            journal.keyframes.retain(|(step, _, _)| *step <= entry.step);
+            return self.undo(entry);
+        }
+        let index = journal.previous_keyframe().ok_or("Nothing to step back to")?;
+        journal.keyframes.truncate(index + 1);
+        let (step, state, call_stack) = journal.keyframes[index].clone();
+        journal.step = step;
+        self.restore(&state)?;
+        self.call_stack = call_stack;
+        Ok(())
+    }
+
+    /*
+     * Steps back to the last time the instruction at the address was about to
+     * be executed. Nothing changes if that isn't part of the history.
+     * Returns how many instructions were undone.
+     */
+    pub fn run_back_to(&mut self, address: u16) -> EResult<usize> {
+        let journal = self.journal.as_ref().ok_or("Journal is disabled")?;
+        let target = match journal.entries.iter().rev().find(|entry| entry.pc == address) {
+            Some(entry) => entry.step,
+            None => journal
+                .keyframes
+                .iter()
+                .rev()
+                .find(|(step, state, _)| *step < journal.step && state.pc == address)
+                .map(|(step, _, _)| *step)
+                .ok_or("Address isn't part of the history")?,
+        };
+        let undone = (journal.step - target) as usize;
+        // keyframes are returned to one by one, so the target is hit exactly
+        while self.journal.as_ref().is_some_and(|journal| journal.step > target) {
+            self.step_back()?;
+        }
+        Ok(undone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::io::{DeviceState, OutputDevice};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Printer {
+        printed: Vec<u8>,
+    }
+
+    impl OutputDevice for Printer {
+        fn write(&mut self, byte: u8) {
+            self.printed.push(byte);
+        }
+    }
+
+    impl DeviceState for Printer {
+        fn save_state(&self) -> Vec<u8> {
+            self.printed.clone()
+        }
+
+        fn load_state(&mut self, state: &[u8]) -> EResult<()> {
+            self.printed = state.to_vec();
+            Ok(())
+        }
+    }
+
+    // LOOP: INR A, STA 100H, OUT 1, PUSH PSW, JMP LOOP
+    const PROGRAM: [u8; 11] = [0x3c, 0x32, 0x00, 0x01, 0xd3, 0x01, 0xf5, 0xc3, 0x00, 0x00, 0x00];
+
+    fn emulator(capacity: usize, keyframe_interval: usize) -> (Emulator, Rc<RefCell<Printer>>) {
+        let printer = Rc::new(RefCell::new(Printer { printed: Vec::new() }));
+        let mut emu = Emulator::new();
+        emu.register_output_device(printer.clone(), 1).unwrap();
+        emu.register_device_state("printer", printer.clone()).unwrap();
+        emu.load_ram(PROGRAM.to_vec(), 0);
+        emu.sp = 0x2000;
+        emu.enable_journal(capacity, keyframe_interval);
+        (emu, printer)
+    }
+
+    #[test]
+    fn step_back() {
+        let (mut emu, printer) = emulator(100, 10);
+        let start = emu.snapshot();
+        for _ in 0..10 {
+            emu.execute_next().unwrap();
+        }
+        assert_eq!(vec![1, 2], printer.borrow().printed);
+        assert_eq!(0x1ffc, emu.sp);
+
+        // back to before the second OUT
+        for _ in 0..3 {
+            emu.step_back().unwrap();
+        }
+        assert_eq!(4, emu.pc);
+        assert_eq!(vec![1], printer.borrow().printed);
+        assert_eq!(2, emu.ram[0x100]);
+        assert_eq!(0x1ffe, emu.sp);
+
+        while emu.can_step_back() {
+            emu.step_back().unwrap();
+        }
+        assert_eq!(start, emu.snapshot());
+        assert_eq!(Err("Nothing to step back to"), emu.step_back());
+    }
+
+    #[test]
+    fn run_back_to() {
+        let (mut emu, printer) = emulator(100, 10);
+        for _ in 0..12 {
+            emu.execute_next().unwrap();
+        }
+        assert_eq!(Ok(4), emu.run_back_to(6));
+        assert_eq!(6, emu.pc);
+        assert_eq!(2, emu.reg['a']);
+        assert_eq!(vec![1, 2], printer.borrow().printed);
+        assert_eq!(Err("Address isn't part of the history"), emu.run_back_to(9));
+        assert_eq!(6, emu.pc);
+
+        // executing again replaces the undone history
+        emu.execute_next().unwrap();
+        assert_eq!(Ok(1), emu.run_back_to(6));
+    }
+
+    #[test]
+    fn keyframes() {
+        let (mut emu, _) = emulator(4, 2);
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            states.push(emu.snapshot());
+            emu.execute_next().unwrap();
+        }
+        // the last 4 instructions one by one, then the kept keyframes
+        for step in (6..10).rev() {
+            emu.step_back().unwrap();
+            assert_eq!(states[step], emu.snapshot());
+        }
+        for step in [4, 2, 0].iter() {
+            emu.step_back().unwrap();
+            assert_eq!(states[*step], emu.snapshot());
+        }
+        assert!(!emu.can_step_back());
+
+        // returning to a keyframe works for run_back_to as well
+        for _ in 0..10 {
+            emu.execute_next().unwrap();
+        }
+        // the INR at step 5 was dropped with the journal entries
+        assert_eq!(Ok(10), emu.run_back_to(0));
+        assert_eq!(states[0], emu.snapshot());
+
+        emu.disable_journal();
+        assert_eq!(Err("Journal is disabled"), emu.step_back());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{EResult, Emulator};
+
+// Name of the code outside of any subroutine
+const TOP_LEVEL: &str = "(top)";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HotSpot {
+    pub address: u16,
+    // e.g. LOOP+3H
+    pub location: String,
+    pub executions: u64,
+    pub cycles: u64,
+}
+
+/*
+ * Cycles of a subroutine, exclusive ones were spent in the subroutine
+ * itself, inclusive ones include the subroutines it called
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FunctionProfile {
+    // entry of the subroutine, None for the top level code
+    pub address: Option<u16>,
+    pub name: String,
+    pub calls: u64,
+    pub inclusive_cycles: u64,
+    pub exclusive_cycles: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Profile {
+    pub instructions: u64,
+    pub cycles: u64,
+    // the addresses with the most cycles
+    pub hot_spots: Vec<HotSpot>,
+    // sorted by inclusive cycles
+    pub functions: Vec<FunctionProfile>,
+}
+
+/*
+ * Counts executions and cycles per address and per call stack. The call
+ * stacks are the entries of the subroutines on the shadow call stack, so
+ * everything else is derived from them when the profile is made.
+ */
+pub(super) struct Profiler {
+    // executions and cycles of every address
+    addresses: Vec<(u64, u64)>,
+    stacks: HashMap<Vec<u16>, usize>,
+    // cycles per stack, indexed by the values of stacks
+    stack_cycles: Vec<u64>,
+    calls: HashMap<u16, u64>,
+    // stack and depth of the instruction being executed
+    current: (usize, usize),
+}
+
+impl Profiler {
+    fn new() -> Self {
+        Profiler {
+            addresses: vec![(0, 0); 0x10000],
+            stacks: HashMap::new(),
+            stack_cycles: Vec::new(),
+            calls: HashMap::new(),
+            current: (0, 0),
+        }
+    }
+}
+
+impl Emulator {
+    // Called before an instruction is executed
+    pub(super) fn begin_profile(&mut self) {
+        let profiler = match &mut self.profiler {
+            Some(profiler) => profiler,
+            None => return,
+        };
+        let targets: Vec<u16> = self.call_stack.iter().map(|frame| frame.target).collect();
+        let stack = match profiler.stacks.get(&targets) {
+            Some(&stack) => stack,
+            None => {
+                profiler.stack_cycles.push(0);
+                profiler.stacks.insert(targets, profiler.stack_cycles.len() - 1);
+                profiler.stack_cycles.len() - 1
+            }
+        };
+        profiler.current = (stack, self.call_stack.len());
+    }
+
+    /*
+     * Called after an instruction was executed, interrupts have no address as
+     * they don't execute the instruction at pc
+     */
+    pub(super) fn end_profile(&mut self, address: Option<u16>, cycles: &EResult<usize>) {
+        let (profiler, cycles) = match (&mut self.profiler, cycles) {
+            (Some(profiler), Ok(cycles)) => (profiler, *cycles as u64),
+            _ => return,
+        };
+        let (stack, depth) = profiler.current;
+        profiler.stack_cycles[stack] += cycles;
+        if let Some(address) = address {
+            let (executions, total) = &mut profiler.addresses[address as usize];
+            *executions += 1;
+            *total += cycles;
+        }
+        if self.call_stack.len() > depth {
+            *profiler.calls.entry(self.call_stack[self.call_stack.len() - 1].target).or_default() += 1;
+        }
+    }
+
+    /*
+     * The profile since the profiler was enabled, with at most top hot spots
+     */
+    pub fn profile(&self, top: usize) -> Profile {
+        let profiler = match &self.profiler {
+            Some(profiler) => profiler,
+            None => return Profile { instructions: 0, cycles: 0, hot_spots: Vec::new(), functions: Vec::new() },
+        };
+        let mut hot_spots: Vec<HotSpot> = profiler
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, (executions, _))| *executions > 0)
+            .map(|(address, &(executions, cycles))| HotSpot {
+                address: address as u16,
+                location: self.location(address as u16, None),
+                executions,
+                cycles,
+            })
+            .collect();
+        let instructions = hot_spots.iter().map(|spot| spot.executions).sum();
+        hot_spots.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.address.cmp(&b.address)));
+        hot_spots.truncate(top);
+
+        // cycles of a stack count once for every subroutine on it, even if it recursed
+        let mut functions: HashMap<Option<u16>, (u64, u64)> = HashMap::new();
+        for (targets, &stack) in &profiler.stacks {
+            let cycles = profiler.stack_cycles[stack];
+            let mut seen = vec![None];
+            seen.extend(targets.iter().map(|&target| Some(target)));
+            let innermost = seen[seen.len() - 1];
+            seen.sort();
+            seen.dedup();
+            for function in seen {
+                functions.entry(function).or_default().0 += cycles;
+            }
+            functions.entry(innermost).or_default().1 += cycles;
+        }
+        let mut functions: Vec<FunctionProfile> = functions
+            .into_iter()
+            .map(|(address, (inclusive_cycles, exclusive_cycles))| FunctionProfile {
+                address,
+                name: self.function_name(address),
+                calls: address.and_then(|address| profiler.calls.get(&address)).copied().unwrap_or(0),
+                inclusive_cycles,
+                exclusive_cycles,
+            })
+            .collect();
+        functions.sort_by(|a, b| b.inclusive_cycles.cmp(&a.inclusive_cycles).then(a.address.cmp(&b.address)));
+
+        Profile { instructions, cycles: profiler.stack_cycles.iter().sum(), hot_spots, functions }
+    }
+
+    fn function_name(&self, address: Option<u16>) -> String {
+        match address {
+            Some(address) => match self.symbols_at(address).first() {
+                Some(name) => name.clone(),
+                None => format!("{:04X}H", address),
+            },
+            None => String::from(TOP_LEVEL),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    // Starts counting from scratch
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn disable_profiler(&mut self) {
+        self.profiler = None;
+    }
+
+    pub fn profile_json(&self, top: usize) -> String {
+        serde_json::to_string(&self.profile(top)).unwrap()
+    }
+
+    /*
+     * The cycles per call stack in the folded format of flamegraph.pl and
+     * compatible tools, e.g. "(top);MAIN;PRINT 1234"
+     */
+    pub fn profile_folded(&self) -> String {
+        let profiler = match &self.profiler {
+            Some(profiler) => profiler,
+            None => return String::new(),
+        };
+        let mut lines: Vec<String> = profiler
+            .stacks
+            .iter()
+            .filter(|(_, &stack)| profiler.stack_cycles[stack] > 0)
+            .map(|(targets, &stack)| {
+                let mut names = vec![self.function_name(None)];
+                names.extend(targets.iter().map(|&target| self.function_name(Some(target))));
+                format!("{} {}\n", names.join(";"), profiler.stack_cycles[stack])
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+
+    const PROGRAM: &str = "
+        LXI SP,100H
+        CALL OUTER
+        HLT
+        OUTER: MVI B,2
+        LOOP: CALL INNER
+        DCR B
+        JNZ LOOP
+        RET
+        INNER: INR A
+        RET
+        END";
+
+    fn profiled() -> Emulator {
+        profiled_program(PROGRAM)
+    }
+
+    fn profiled_program(program: &str) -> Emulator {
+        let (bytes, labels) = Assembler::new(program).assemble_with_labels().unwrap();
+        let mut emu = Emulator::new();
+        emu.load_ram(bytes, 0);
+        emu.set_symbols(&labels);
+        emu.enable_profiler();
+        while emu.running {
+            emu.execute_next().unwrap();
+        }
+        emu
+    }
+
+    #[test]
+    fn hot_spots() {
+        let profile = profiled().profile(3);
+        assert_eq!(15, profile.instructions);
+        assert_eq!(145, profile.cycles);
+        assert_eq!(
+            HotSpot { address: 9, location: String::from("LOOP"), executions: 2, cycles: 34 },
+            profile.hot_spots[0]
+        );
+        let locations: Vec<&str> = profile.hot_spots.iter().map(|spot| spot.location.as_str()).collect();
+        assert_eq!(vec!["LOOP", "LOOP+4H", "INNER+1H"], locations);
+    }
+
+    #[test]
+    fn functions() {
+        let profile = profiled().profile(0);
+        assert!(profile.hot_spots.is_empty());
+        assert_eq!(
+            vec![
+                FunctionProfile { address: None, name: String::from("(top)"), calls: 0, inclusive_cycles: 145, exclusive_cycles: 34 },
+                FunctionProfile { address: Some(7), name: String::from("OUTER"), calls: 1, inclusive_cycles: 111, exclusive_cycles: 81 },
+                FunctionProfile { address: Some(17), name: String::from("INNER"), calls: 2, inclusive_cycles: 30, exclusive_cycles: 30 },
+            ],
+            profile.functions
+        );
+    }
+
+    #[test]
+    fn stack_at_top_of_memory() {
+        let emu = profiled_program(&PROGRAM.replace("LXI SP,100H", "LXI SP,0"));
+        assert_eq!(profiled().profile_folded(), emu.profile_folded());
+    }
+
+    #[test]
+    fn exports() {
+        let mut emu = profiled();
+        assert_eq!("(top) 34\n(top);OUTER 81\n(top);OUTER;INNER 30\n", emu.profile_folded());
+        assert!(emu.profile_json(1).starts_with(
+            "{\"instructions\":15,\"cycles\":145,\"hot_spots\":[{\"address\":9,\"location\":\"LOOP\",\"executions\":2,\"cycles\":34}],\"functions\":[{\"address\":null,\"name\":\"(top)\""
+        ));
+        emu.disable_profiler();
+        assert_eq!("", emu.profile_folded());
+        assert_eq!(0, emu.profile(10).cycles);
+    }
+}
@@ -0,0 +1,313 @@
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{EResult, Emulator};
+use crate::core::ram::MemoryState;
+
+// Increased whenever the layout of State changes
+pub const STATE_VERSION: u16 = 1;
+
+// Start of every binary save state
+const MAGIC: &[u8; 4] = b"8080";
+
+const PAIRS: [&str; 5] = ["wz", "bc", "de", "hl", "psw"];
+
+/*
+ * Everything needed to continue a program later: CPU, memory and the state
+ * of the devices registered with register_device_state
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub version: u16,
+    pub pc: u16,
+    pub sp: u16,
+    // register pairs wz, bc, de, hl and psw
+    pub registers: [u16; 5],
+    pub running: bool,
+    pub interrupts_enabled: bool,
+    pub memory: MemoryState,
+    pub devices: Vec<(String, Vec<u8>)>,
+}
+
+impl State {
+    /*
+     * Binary layout, numbers are little endian and byte strings are prefixed
+     * with their length as u32:
+     *
+     * "8080", version, pc, sp, wz, bc, de, hl, psw (u16 each)
+     * running | interrupts_enabled << 1 (u8)
+     * memory, bank count (u32), banks, selected bank (u32)
+     * device count (u32), then name and state of each device
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for value in [self.version, self.pc, self.sp].iter().chain(self.registers.iter()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.push(self.running as u8 | (self.interrupts_enabled as u8) << 1);
+        put_bytes(&mut bytes, &self.memory.memory);
+        bytes.extend_from_slice(&(self.memory.banks.len() as u32).to_le_bytes());
+        for bank in &self.memory.banks {
+            put_bytes(&mut bytes, bank);
+        }
+        bytes.extend_from_slice(&(self.memory.bank as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.devices.len() as u32).to_le_bytes());
+        for (name, state) in &self.devices {
+            put_bytes(&mut bytes, name.as_bytes());
+            put_bytes(&mut bytes, state);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> EResult<State> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a save state");
+        }
+        let version = reader.u16()?;
+        if version != STATE_VERSION {
+            return Err("Unsupported save state version");
+        }
+        let pc = reader.u16()?;
+        let sp = reader.u16()?;
+        let mut registers = [0; 5];
+        for register in registers.iter_mut() {
+            *register = reader.u16()?;
+        }
+        let flags = reader.take(1)?[0];
+        let memory = reader.bytes()?;
+        let banks = (0..reader.u32()?).map(|_| reader.bytes()).collect::<EResult<Vec<_>>>()?;
+        let bank = reader.u32()? as usize;
+        let mut devices = Vec::new();
+        for _ in 0..reader.u32()? {
+            let name = String::from_utf8(reader.bytes()?).map_err(|_| "Invalid device name")?;
+            devices.push((name, reader.bytes()?));
+        }
+        if reader.position != bytes.len() {
+            return Err("Save state is too long");
+        }
+        Ok(State {
+            version,
+            pc,
+            sp,
+            registers,
+            running: flags & 1 != 0,
+            interrupts_enabled: flags & 2 != 0,
+            memory: MemoryState { memory, banks, bank },
+            devices,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> EResult<State> {
+        let state: State = serde_json::from_str(json).map_err(|_| "Invalid save state")?;
+        if state.version != STATE_VERSION {
+            return Err("Unsupported save state version");
+        }
+        Ok(state)
+    }
+}
+
+fn put_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> EResult<&'a [u8]> {
+        let end = self.position.checked_add(count).filter(|&end| end <= self.bytes.len()).ok_or("Save state is too short")?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> EResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> EResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn bytes(&mut self) -> EResult<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+impl Emulator {
+    pub fn snapshot(&self) -> State {
+        let mut registers = [0; 5];
+        for (register, pair) in registers.iter_mut().zip(PAIRS.iter()) {
+            *register = self.reg[*pair];
+        }
+        State {
+            version: STATE_VERSION,
+            pc: self.pc,
+            sp: self.sp,
+            registers,
+            running: self.running,
+            interrupts_enabled: self.interrupts_enabled,
+            memory: self.ram.save_state(),
+            devices: self
+                .stateful_devices
+                .iter()
+                .map(|(name, device)| (name.clone(), device.borrow().save_state()))
+                .collect(),
+        }
+    }
+
+    /*
+     * Every registered device has to be part of the state, devices in the
+     * state that aren't registered are ignored. The shadow call stack isn't
+     * part of the state, it starts out empty.
+     */
+    pub fn restore(&mut self, state: &State) -> EResult<()> {
+        if state.version != STATE_VERSION {
+            return Err("Unsupported save state version");
+        }
+        let mut devices = Vec::new();
+        for (name, device) in &self.stateful_devices {
+            match state.devices.iter().find(|(saved, _)| saved == name) {
+                Some((_, saved)) => devices.push((device.clone(), saved)),
+                None => return Err("Save state is missing a device"),
+            }
+        }
+        self.ram.load_state(&state.memory)?;
+        for (device, saved) in devices {
+            device.borrow_mut().load_state(saved)?;
+        }
+        self.pc = state.pc;
+        self.sp = state.sp;
+        for (register, pair) in state.registers.iter().zip(PAIRS.iter()) {
+            self.reg[*pair] = *register;
+        }
+        self.running = state.running;
+        self.interrupts_enabled = state.interrupts_enabled;
+        self.call_stack.clear();
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    // Binary save state for the save slots of the frontend
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().to_bytes()
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> EResult<()> {
+        self.restore(&State::from_bytes(bytes)?)
+    }
+
+    pub fn save_state_json(&self) -> String {
+        self.snapshot().to_json()
+    }
+
+    pub fn load_state_json(&mut self, json: &str) -> EResult<()> {
+        self.restore(&State::from_json(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::io::DeviceState;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Counter {
+        count: u8,
+    }
+
+    impl DeviceState for Counter {
+        fn save_state(&self) -> Vec<u8> {
+            vec![self.count]
+        }
+
+        fn load_state(&mut self, state: &[u8]) -> EResult<()> {
+            match state {
+                [count] => self.count = *count,
+                _ => return Err("Invalid counter state"),
+            }
+            Ok(())
+        }
+    }
+
+    fn running() -> (Emulator, Rc<RefCell<Counter>>) {
+        let counter = Rc::new(RefCell::new(Counter { count: 7 }));
+        let mut emu = Emulator::new();
+        emu.register_device_state("counter", counter.clone()).unwrap();
+        // MVI A,2, MVI B,3, ADD B, STA 100H, EI
+        emu.load_ram(vec![0x3e, 0x02, 0x06, 0x03, 0x80, 0x32, 0x00, 0x01, 0xfb], 0);
+        emu.sp = 0x2000;
+        for _ in 0..4 {
+            emu.execute_next().unwrap();
+        }
+        (emu, counter)
+    }
+
+    #[test]
+    fn restore() {
+        let (mut emu, counter) = running();
+        let state = emu.snapshot();
+        emu.execute_next().unwrap();
+        emu.reg['a'] = 0;
+        emu.ram[0x100] = 0;
+        counter.borrow_mut().count = 0;
+
+        emu.restore(&state).unwrap();
+        assert_eq!(8, emu.pc);
+        assert_eq!(0x2000, emu.sp);
+        assert_eq!(5, emu.reg['a']);
+        assert_eq!(3, emu.reg['b']);
+        assert!(!emu.reg.get_flag("zero"));
+        assert_eq!(5, emu.ram[0x100]);
+        assert_eq!(7, counter.borrow().count);
+        assert_eq!(state, emu.snapshot());
+    }
+
+    #[test]
+    fn formats() {
+        let (mut emu, _) = running();
+        let state = emu.snapshot();
+        assert_eq!(state, State::from_bytes(&state.to_bytes()).unwrap());
+        assert_eq!(state, State::from_json(&state.to_json()).unwrap());
+
+        let bytes = emu.save_state();
+        emu.execute_next().unwrap();
+        emu.load_state(&bytes).unwrap();
+        assert_eq!(state, emu.snapshot());
+        let json = emu.save_state_json();
+        emu.load_state_json(&json).unwrap();
+        assert_eq!(state, emu.snapshot());
+    }
+
+    #[test]
+    fn invalid() {
+        let (mut emu, _) = running();
+        let bytes = emu.save_state();
+        assert_eq!(Err("Not a save state"), State::from_bytes(b"8086"));
+        assert_eq!(Err("Save state is too short"), State::from_bytes(&bytes[..bytes.len() - 1]));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(Err("Unsupported save state version"), State::from_bytes(&newer));
+        assert!(emu.load_state_json("{}").is_err());
+
+        // a state without the device can't be loaded
+        let mut state = emu.snapshot();
+        state.devices.clear();
+        assert_eq!(Err("Save state is missing a device"), emu.restore(&state));
+        assert!(emu.register_device_state("counter", Rc::new(RefCell::new(Counter { count: 0 }))).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::debugger::{Limit, RunResult, StopReason, Target};
+use super::Emulator;
+use crate::core::instruction::Instruction;
+
+impl Emulator {
+    /*
+     * Executes the next instruction, calls and RST are executed until they
+     * return to the instruction after them at the same stack depth.
+     * Breakpoints in the subroutine still stop the step.
+     */
+    pub fn step_over(&mut self, limit: Limit) -> RunResult {
+        let bytes: Vec<u8> = (0..3).map(|offset| self.peek(self.pc.wrapping_add(offset))).collect();
+        // every opcode decodes once the aliases are included
+        let instruction = Instruction::decode_with_aliases(&bytes).unwrap();
+        let call = match instruction {
+            Instruction::Word(_, _) => instruction.mnemonic().starts_with('C'),
+            _ => instruction.mnemonic() == "RST",
+        };
+        if call {
+            let next = self.pc.wrapping_add(instruction.length() as u16);
+            return self.run_until(Target::Address { pc: next, sp: Some(self.sp) }, limit);
+        }
+        // a single instruction, which doesn't stop at a breakpoint at pc like the other steps
+        let mut result = self.run_until(Target::Address { pc: self.pc, sp: None }, Limit::Instructions(1));
+        if result.reason == StopReason::Limit {
+            result.reason = StopReason::Step;
+        }
+        result
+    }
+
+    /*
+     * Runs until the current subroutine returns, i.e. until a return leaves
+     * the stack pointer above where it is now
+     */
+    pub fn step_out(&mut self, limit: Limit) -> RunResult {
+        self.run_until(Target::Return { sp: self.sp }, limit)
+    }
+
+    /*
+     * Runs until the instruction at the address is next, if execution starts
+     * there it has to come back to it
+     */
+    pub fn run_to(&mut self, address: u16, limit: Limit) -> RunResult {
+        self.run_until(Target::Address { pc: address, sp: None }, limit)
+    }
+}
+
+/*
+ * The steps for the frontend, with at most max_instructions executed.
+ * The results are returned as JSON like the results of run_instructions.
+ */
+#[wasm_bindgen]
+impl Emulator {
+    pub fn step_over_instructions(&mut self, max_instructions: u32) -> String {
+        serde_json::to_string(&self.step_over(Limit::Instructions(max_instructions as u64))).unwrap()
+    }
+
+    pub fn step_out_instructions(&mut self, max_instructions: u32) -> String {
+        serde_json::to_string(&self.step_out(Limit::Instructions(max_instructions as u64))).unwrap()
+    }
+
+    pub fn run_to_instructions(&mut self, address: u16, max_instructions: u32) -> String {
+        serde_json::to_string(&self.run_to(address, Limit::Instructions(max_instructions as u64))).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::io::MemoryDevice;
+    use crate::kreator::assembler::Assembler;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAM: &str = "
+        LXI SP,100H
+        CALL OUTER
+        RST 1
+        HLT
+        NOP
+        NOP
+        RET
+        NOP
+        OUTER: PUSH PSW
+        CALL INNER
+        POP PSW
+        RZ
+        RET
+        INNER: MVI A,0
+        ORA A
+        RET
+        END";
+
+    const LIMIT: Limit = Limit::Instructions(100);
+
+    fn emulator() -> Emulator {
+        let (bytes, labels) = Assembler::new(PROGRAM).assemble_with_labels().unwrap();
+        let mut emu = Emulator::new();
+        emu.load_ram(bytes, 0);
+        emu.set_symbols(&labels);
+        emu
+    }
+
+    #[test]
+    fn step_over() {
+        let mut emu = emulator();
+        assert_eq!(RunResult { reason: StopReason::Step, instructions: 1, cycles: 10 }, emu.step_over(LIMIT));
+        let result = emu.step_over(LIMIT);
+        assert_eq!(StopReason::Step, result.reason);
+        assert_eq!(9, result.instructions);
+        assert_eq!(6, emu.pc);
+        assert_eq!(0x100, emu.sp);
+
+        // RST 1 at 6 calls 8
+        assert_eq!(StopReason::Step, emu.step_over(LIMIT).reason);
+        assert_eq!(7, emu.pc);
+
+        // a breakpoint in the subroutine stops the step
+        emu.pc = 3;
+        emu.add_breakpoint(19);
+        assert_eq!(StopReason::Breakpoint { address: 19 }, emu.step_over(LIMIT).reason);
+        assert_eq!(StopReason::Step, emu.step_over(LIMIT).reason);
+        assert_eq!(21, emu.pc);
+    }
+
+    #[test]
+    fn step_out() {
+        let mut emu = emulator();
+        emu.run_to(19, LIMIT);
+        assert_eq!(0xfa, emu.sp);
+        // INNER returns to the POP
+        assert_eq!(StopReason::Step, emu.step_out(LIMIT).reason);
+        assert_eq!(16, emu.pc);
+        assert_eq!(0xfc, emu.sp);
+        // the RZ isn't taken, the RET returns to the RST
+        let result = emu.step_out(LIMIT);
+        assert_eq!(RunResult { reason: StopReason::Step, instructions: 3, cycles: 25 }, result);
+        assert_eq!(6, emu.pc);
+    }
+
+    #[test]
+    fn run_to() {
+        let mut emu = emulator();
+        assert_eq!(StopReason::Step, emu.run_to(19, LIMIT).reason);
+        assert_eq!(19, emu.pc);
+        // doesn't come back to 19
+        assert_eq!(StopReason::Halted, emu.run_to(19, LIMIT).reason);
+        assert_eq!(
+            "{\"reason\":\"step\",\"instructions\":1,\"cycles\":10}",
+            emulator().run_to_instructions(3, 5)
+        );
+        assert_eq!("{\"reason\":\"limit\",\"instructions\":5,\"cycles\":62}", emulator().step_out_instructions(5));
+        assert_eq!("{\"reason\":\"step\",\"instructions\":1,\"cycles\":10}", emulator().step_over_instructions(5));
+    }
+
+    // Counts the reads to show those made by the debugger
+    struct Rom(Vec<u8>, usize);
+
+    impl MemoryDevice for Rom {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.1 += 1;
+            self.0[offset as usize]
+        }
+
+        fn write(&mut self, _offset: u16, _byte: u8) {}
+
+        fn peek(&self, offset: u16) -> u8 {
+            self.0[offset as usize]
+        }
+    }
+
+    #[test]
+    fn step_over_memory_mapped_code() {
+        let mut emu = Emulator::new();
+        // CALL 10H, the RAM below the device only has NOPs
+        let rom = Rc::new(RefCell::new(Rom(vec![0xcd, 0x10, 0x00], 0)));
+        emu.register_memory_device(rom.clone(), 0x4000..=0x4002).unwrap();
+        emu.load_ram(vec![0xc9], 0x10);
+        emu.pc = 0x4000;
+        emu.sp = 0x100;
+        assert_eq!(StopReason::Step, emu.step_over(LIMIT).reason);
+        assert_eq!(0x4003, emu.pc);
+        // only the CALL itself reads the device
+        assert_eq!(3, rom.borrow().1);
+    }
+
+    #[test]
+    fn step_out_of_memory_mapped_code() {
+        let mut emu = Emulator::new();
+        // MVI B,7, RET
+        let rom = Rc::new(RefCell::new(Rom(vec![0x06, 0x07, 0xc9], 0)));
+        emu.register_memory_device(rom.clone(), 0x4000..=0x4002).unwrap();
+        emu.load_ram(vec![0x76], 0x10);
+        emu.load_ram(vec![0x10, 0x00], 0xfe);
+        emu.pc = 0x4000;
+        emu.sp = 0xfe;
+        assert_eq!(StopReason::Step, emu.step_out(LIMIT).reason);
+        assert_eq!(0x10, emu.pc);
+        assert_eq!(3, rom.borrow().1);
+    }
+
+    #[test]
+    fn stack_at_top_of_memory() {
+        // the CALL pushes to FFFEH and the RET returns SP to 0
+        let (bytes, _) = Assembler::new("LXI SP,0\nCALL WORK\nHLT\nWORK: NOP\nRET\nEND").assemble_with_labels().unwrap();
+        let mut emu = Emulator::new();
+        emu.load_ram(bytes, 0);
+        emu.step_over(LIMIT);
+        assert_eq!(StopReason::Step, emu.step_over(LIMIT).reason);
+        assert_eq!(6, emu.pc);
+        assert_eq!(0, emu.sp);
+
+        emu.pc = 3;
+        emu.run_to(7, LIMIT);
+        assert_eq!(0xfffe, emu.sp);
+        assert_eq!(StopReason::Step, emu.step_out(LIMIT).reason);
+        assert_eq!(6, emu.pc);
+        assert_eq!(0, emu.sp);
+    }
+}
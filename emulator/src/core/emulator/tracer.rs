@@ -0,0 +1,448 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{EResult, Emulator};
+use crate::core::instruction::Instruction;
+
+// Our own trace format, one instruction per line
+pub const DEFAULT_TRACE: &str = "{pc}  {bytes:8}  {disasm:14}  A={a} BC={bc} DE={de} HL={hl} SP={sp} {flags} CYC={cycles}";
+
+// The format of the traces commonly printed by other 8080 emulators
+pub const REFERENCE_TRACE: &str = "PC: {pc}, AF: {af}, BC: {bc}, DE: {de}, HL: {hl}, SP: {sp}, CYC: {cycles}\t({mem})";
+
+/*
+ * Value shown in a trace line, always the state before the instruction
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Pc,
+    // bytes of the instruction
+    Bytes,
+    // the 4 bytes at pc, like the reference format
+    Memory,
+    Disassembly,
+    Register(char),
+    Pair(&'static str),
+    // SZAPC, with a dot for every flag that isn't set
+    Flags,
+    Sp,
+    // cycles executed since tracing started
+    Cycles,
+    // instructions executed since tracing started
+    Step,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        let field = match name {
+            "pc" => Field::Pc,
+            "bytes" => Field::Bytes,
+            "mem" => Field::Memory,
+            "disasm" => Field::Disassembly,
+            "a" | "b" | "c" | "d" | "e" | "h" | "l" | "f" => Field::Register(name.chars().next().unwrap()),
+            "bc" => Field::Pair("bc"),
+            "de" => Field::Pair("de"),
+            "hl" => Field::Pair("hl"),
+            "af" | "psw" => Field::Pair("psw"),
+            "flags" => Field::Flags,
+            "sp" => Field::Sp,
+            "cycles" => Field::Cycles,
+            "step" => Field::Step,
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Pc => f.write_str("PC"),
+            Field::Bytes => f.write_str("bytes"),
+            Field::Memory => f.write_str("memory"),
+            Field::Disassembly => f.write_str("instruction"),
+            Field::Register(register) => write!(f, "{}", register.to_ascii_uppercase()),
+            Field::Pair("psw") => f.write_str("AF"),
+            Field::Pair(pair) => f.write_str(&pair.to_uppercase()),
+            Field::Flags => f.write_str("flags"),
+            Field::Sp => f.write_str("SP"),
+            Field::Cycles => f.write_str("cycles"),
+            Field::Step => f.write_str("step"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    Text(String),
+    // the field, padded with spaces to the width
+    Field(Field, usize),
+}
+
+/*
+ * Layout of a trace line. Fields are written as {name} or {name:width},
+ * e.g. "{pc} {disasm:12} A={a}". Registers and addresses are shown as hex
+ * without suffix, cycles and steps in decimal.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFormat {
+    pub parts: Vec<Part>,
+}
+
+impl TraceFormat {
+    pub fn parse(template: &str) -> EResult<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or("Unclosed trace field")? + start;
+            let (name, width) = match rest[start + 1..end].split_once(':') {
+                Some((name, width)) => (name, width.parse().map_err(|_| "Invalid trace field width")?),
+                None => (&rest[start + 1..end], 0),
+            };
+            let field = Field::from_name(&name.to_lowercase()).ok_or("Unknown trace field")?;
+            parts.push(Part::Field(field, width));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(TraceFormat { parts })
+    }
+
+    /*
+     * Reads the fields back from a line in this format, None if the line
+     * doesn't match it. A field ends where the following text starts, so two
+     * fields without text between them can't be told apart.
+     */
+    pub fn parse_line(&self, line: &str) -> Option<Vec<(Field, String)>> {
+        let mut fields = Vec::new();
+        let mut rest = line;
+        for (index, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Text(text) => rest = rest.strip_prefix(text.as_str())?,
+                Part::Field(field, width) => {
+                    // padding makes the value at least width long
+                    let end = match self.parts.get(index + 1) {
+                        Some(Part::Text(text)) => {
+                            let start = rest.char_indices().nth(*width).map_or(rest.len(), |(start, _)| start);
+                            rest[start..].find(text.as_str())? + start
+                        }
+                        Some(Part::Field(..)) => return None,
+                        None => rest.len(),
+                    };
+                    fields.push((*field, rest[..end].trim_end().to_string()));
+                    rest = &rest[end..];
+                }
+            }
+        }
+        match rest.is_empty() {
+            true => Some(fields),
+            false => None,
+        }
+    }
+}
+
+impl Default for TraceFormat {
+    fn default() -> Self {
+        TraceFormat::parse(DEFAULT_TRACE).unwrap()
+    }
+}
+
+enum Output {
+    // the last lines, for the frontend
+    Buffer { lines: VecDeque<String>, capacity: usize },
+    Writer(Box<dyn Write>),
+}
+
+pub(super) struct Tracer {
+    format: TraceFormat,
+    output: Output,
+    cycles: u64,
+    step: u64,
+    // first error writing the trace, no more lines are written after it
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    fn new(format: TraceFormat, output: Output) -> Self {
+        Tracer { format, output, cycles: 0, step: 0, error: None }
+    }
+
+    fn write(&mut self, line: String) {
+        match &mut self.output {
+            Output::Buffer { lines, capacity } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+            Output::Writer(writer) => {
+                if self.error.is_none() {
+                    self.error = writeln!(writer, "{}", line).err();
+                }
+            }
+        }
+    }
+}
+
+impl Emulator {
+    /*
+     * Called before an instruction is executed, with the opcode for
+     * interrupts, as they don't execute the instruction at pc
+     */
+    pub(super) fn trace(&mut self, opcode: Option<u8>) {
+        let (format, cycles, step) = match &self.tracer {
+            Some(tracer) => (&tracer.format, tracer.cycles, tracer.step),
+            None => return,
+        };
+        // peeked, so tracing doesn't change how memory mapped devices behave
+        let length = match format.parts.iter().any(|part| matches!(part, Part::Field(Field::Memory, _))) {
+            true => 4,
+            false => 3,
+        };
+        let memory: Vec<u8> = (0..length).map(|offset| self.peek(self.pc.wrapping_add(offset))).collect();
+        let bytes = match opcode {
+            Some(opcode) => vec![opcode, 0, 0],
+            None => memory[..3].to_vec(),
+        };
+        // every opcode decodes once the aliases are included
+        let instruction = Instruction::decode_with_aliases(&bytes).unwrap();
+        let mut line = String::new();
+        for part in &format.parts {
+            let (field, width) = match part {
+                Part::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Part::Field(field, width) => (field, *width),
+            };
+            let value = match field {
+                Field::Pc => format!("{:04X}", self.pc),
+                Field::Bytes => hex(&bytes[..instruction.length()]),
+                Field::Memory => hex(&memory),
+                Field::Disassembly => instruction.to_string(),
+                Field::Register('f') => format!("{:02X}", self.reg["psw"] & 0xff),
+                Field::Register(register) => format!("{:02X}", self.reg[*register]),
+                Field::Pair(pair) => format!("{:04X}", self.reg[*pair]),
+                Field::Flags => [('S', "sign"), ('Z', "zero"), ('A', "aux"), ('P', "parity"), ('C', "carry")]
+                    .iter()
+                    .map(|&(letter, flag)| if self.reg.get_flag(flag) { letter } else { '.' })
+                    .collect(),
+                Field::Sp => format!("{:04X}", self.sp),
+                Field::Cycles => cycles.to_string(),
+                Field::Step => step.to_string(),
+            };
+            line.push_str(&format!("{:<width$}", value, width = width));
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write(line);
+        }
+    }
+
+    // Called after an instruction was executed
+    pub(super) fn trace_cycles(&mut self, cycles: &EResult<usize>) {
+        if let (Some(tracer), Ok(cycles)) = (&mut self.tracer, cycles) {
+            tracer.cycles += *cycles as u64;
+            tracer.step += 1;
+        }
+    }
+
+    // Traces every executed instruction to the writer until tracing is stopped
+    pub fn trace_to(&mut self, writer: Box<dyn Write>, format: TraceFormat) {
+        self.tracer = Some(Tracer::new(format, Output::Writer(writer)));
+    }
+
+    pub fn trace_to_file(&mut self, path: &str, format: TraceFormat) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.trace_to(Box::new(BufWriter::new(file)), format);
+        Ok(())
+    }
+
+    /*
+     * Stops tracing, returns the first error that occurred while writing the
+     * trace
+     */
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        let tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return Ok(()),
+        };
+        if let Some(error) = tracer.error {
+            return Err(error);
+        }
+        match tracer.output {
+            Output::Writer(mut writer) => writer.flush(),
+            Output::Buffer { .. } => Ok(()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /*
+     * Traces into a buffer keeping the last capacity lines, see TraceFormat
+     * for the format, an empty format uses DEFAULT_TRACE
+     */
+    pub fn enable_trace(&mut self, format: &str, capacity: usize) -> EResult<()> {
+        let format = match format {
+            "" => TraceFormat::default(),
+            format => TraceFormat::parse(format)?,
+        };
+        let output = Output::Buffer { lines: VecDeque::new(), capacity: capacity.max(1) };
+        self.tracer = Some(Tracer::new(format, output));
+        Ok(())
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.tracer = None;
+    }
+
+    // The buffered lines, which are removed from the buffer
+    pub fn take_trace(&mut self) -> String {
+        match &mut self.tracer {
+            Some(Tracer { output: Output::Buffer { lines, .. }, .. }) => {
+                lines.drain(..).map(|line| line + "\n").collect()
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::io::MemoryDevice;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // MVI A,1, INR A, LXI SP,100H, PUSH PSW, HLT
+    const PROGRAM: [u8; 8] = [0x3e, 0x01, 0x3c, 0x31, 0x00, 0x01, 0xf5, 0x76];
+
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new();
+        emu.load_ram(PROGRAM.to_vec(), 0);
+        emu
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            vec![Part::Text(String::from("PC=")), Part::Field(Field::Pc, 0), Part::Field(Field::Disassembly, 12)],
+            TraceFormat::parse("PC={PC}{disasm:12}").unwrap().parts
+        );
+        assert_eq!(Err("Unknown trace field"), TraceFormat::parse("{ix}"));
+        assert_eq!(Err("Unclosed trace field"), TraceFormat::parse("{pc"));
+        assert_eq!(Err("Invalid trace field width"), TraceFormat::parse("{pc:x}"));
+        assert_eq!(Err("Unknown trace field"), emulator().enable_trace("{}", 10));
+    }
+
+    #[test]
+    fn parse_lines() {
+        let format = TraceFormat::default();
+        let fields = format
+            .parse_line("0002  3C        INR A           A=01 BC=0000 DE=0000 HL=0000 SP=0000 ..... CYC=7")
+            .unwrap();
+        assert_eq!((Field::Bytes, String::from("3C")), fields[1]);
+        assert_eq!((Field::Disassembly, String::from("INR A")), fields[2]);
+        assert_eq!((Field::Cycles, String::from("7")), fields[9]);
+        assert_eq!(None, format.parse_line("Hello, world"));
+
+        let format = TraceFormat::parse(REFERENCE_TRACE).unwrap();
+        let fields = format.parse_line("PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: FF00, CYC: 12\t(3E 01 3C 31)").unwrap();
+        let names: Vec<String> = fields.iter().map(|(field, _)| field.to_string()).collect();
+        assert_eq!(vec!["PC", "AF", "BC", "DE", "HL", "SP", "cycles", "memory"], names);
+        assert_eq!("3E 01 3C 31", fields[7].1);
+        assert_eq!(None, TraceFormat::parse("{a}{b}").unwrap().parse_line("0102"));
+    }
+
+    #[test]
+    fn buffer() {
+        let mut emu = emulator();
+        emu.enable_trace("", 10).unwrap();
+        for _ in 0..4 {
+            emu.execute_next().unwrap();
+        }
+        assert_eq!(
+            "0000  3E 01     MVI A,1H        A=00 BC=0000 DE=0000 HL=0000 SP=0000 ..... CYC=0\n\
+             0002  3C        INR A           A=01 BC=0000 DE=0000 HL=0000 SP=0000 ..... CYC=7\n\
+             0003  31 00 01  LXI SP,100H     A=02 BC=0000 DE=0000 HL=0000 SP=0000 ..... CYC=12\n\
+             0006  F5        PUSH PSW        A=02 BC=0000 DE=0000 HL=0000 SP=0100 ..... CYC=22\n",
+            emu.take_trace()
+        );
+        assert_eq!("", emu.take_trace());
+
+        // only the last lines are kept
+        emu.enable_trace("{step} {sp}", 2).unwrap();
+        emu.pc = 0;
+        for _ in 0..3 {
+            emu.execute_next().unwrap();
+        }
+        assert_eq!("1 00FE\n2 00FE\n", emu.take_trace());
+        emu.disable_trace();
+        emu.execute_next().unwrap();
+        assert_eq!("", emu.take_trace());
+    }
+
+    // Counts the reads to show those made by the tracer
+    struct Rom(Vec<u8>, usize);
+
+    impl MemoryDevice for Rom {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.1 += 1;
+            self.0[offset as usize]
+        }
+
+        fn write(&mut self, _offset: u16, _byte: u8) {}
+
+        fn peek(&self, offset: u16) -> u8 {
+            self.0[offset as usize]
+        }
+    }
+
+    #[test]
+    fn memory_mapped_code() {
+        let mut emu = Emulator::new();
+        // MVI B,7, HLT
+        let rom = Rc::new(RefCell::new(Rom(vec![0x06, 0x07, 0x76], 0)));
+        emu.register_memory_device(rom.clone(), 0x4000..=0x4002).unwrap();
+        emu.pc = 0x4000;
+        emu.enable_trace("{pc} {bytes:9}{disasm}", 10).unwrap();
+        emu.execute_next().unwrap();
+        assert_eq!("4000 06 07    MVI B,7H\n", emu.take_trace());
+        // only the instruction itself reads the device
+        assert_eq!(2, rom.borrow().1);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join("emulator_trace_test.log");
+        let path = path.to_str().unwrap();
+        let mut emu = emulator();
+        emu.trace_to_file(path, TraceFormat::parse(REFERENCE_TRACE).unwrap()).unwrap();
+        emu.execute_next().unwrap();
+        emu.execute_next().unwrap();
+        emu.interrupt(0xff).unwrap();
+        emu.finish_trace().unwrap();
+        emu.execute_next().unwrap();
+
+        let trace = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            "PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\t(3E 01 3C 31)\n\
+             PC: 0002, AF: 0102, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 7\t(3C 31 00 01)\n\
+             PC: 0003, AF: 0202, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 12\t(31 00 01 F5)\n",
+            trace
+        );
+    }
+}
@@ -0,0 +1,452 @@
+use std::fmt;
+
+// Flags an instruction can change, using the same bits as the PSW
+pub const SIGN: u8 = 0b1000_0000;
+pub const ZERO: u8 = 0b0100_0000;
+pub const AUX: u8 = 0b0001_0000;
+pub const PARITY: u8 = 0b0000_0100;
+pub const CARRY: u8 = 0b0000_0001;
+pub const ALL: u8 = SIGN | ZERO | AUX | PARITY | CARRY;
+pub const NONE: u8 = 0;
+
+/*
+ * Metadata of an opcode. The operands are the fixed part of the operands,
+ * for instructions longer than one byte the immediate value follows them.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub operands: &'static str,
+    pub length: u8,
+    pub cycles: u8,
+    // differs from cycles only for conditional calls and returns
+    pub cycles_not_taken: u8,
+    pub flags: u8,
+    // undocumented opcodes execute like the documented instruction they alias
+    pub documented: bool,
+}
+
+const fn op(mnemonic: &'static str, operands: &'static str, length: u8, cycles: u8, cycles_not_taken: u8, flags: u8) -> Opcode {
+    Opcode { mnemonic, operands, length, cycles, cycles_not_taken, flags, documented: true }
+}
+
+const fn alias(mnemonic: &'static str, operands: &'static str, length: u8, cycles: u8) -> Opcode {
+    Opcode { mnemonic, operands, length, cycles, cycles_not_taken: cycles, flags: NONE, documented: false }
+}
+
+pub static OPCODES: [Opcode; 256] = [
+    op("NOP", "", 1, 4, 4, NONE), // 0x00
+    op("LXI", "B", 3, 10, 10, NONE), // 0x01
+    op("STAX", "B", 1, 7, 7, NONE), // 0x02
+    op("INX", "B", 1, 5, 5, NONE), // 0x03
+    op("INR", "B", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x04
+    op("DCR", "B", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x05
+    op("MVI", "B", 2, 7, 7, NONE), // 0x06
+    op("RLC", "", 1, 4, 4, CARRY), // 0x07
+    alias("NOP", "", 1, 4), // 0x08
+    op("DAD", "B", 1, 10, 10, CARRY), // 0x09
+    op("LDAX", "B", 1, 7, 7, NONE), // 0x0a
+    op("DCX", "B", 1, 5, 5, NONE), // 0x0b
+    op("INR", "C", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x0c
+    op("DCR", "C", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x0d
+    op("MVI", "C", 2, 7, 7, NONE), // 0x0e
+    op("RRC", "", 1, 4, 4, CARRY), // 0x0f
+    alias("NOP", "", 1, 4), // 0x10
+    op("LXI", "D", 3, 10, 10, NONE), // 0x11
+    op("STAX", "D", 1, 7, 7, NONE), // 0x12
+    op("INX", "D", 1, 5, 5, NONE), // 0x13
+    op("INR", "D", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x14
+    op("DCR", "D", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x15
+    op("MVI", "D", 2, 7, 7, NONE), // 0x16
+    op("RAL", "", 1, 4, 4, CARRY), // 0x17
+    alias("NOP", "", 1, 4), // 0x18
+    op("DAD", "D", 1, 10, 10, CARRY), // 0x19
+    op("LDAX", "D", 1, 7, 7, NONE), // 0x1a
+    op("DCX", "D", 1, 5, 5, NONE), // 0x1b
+    op("INR", "E", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x1c
+    op("DCR", "E", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x1d
+    op("MVI", "E", 2, 7, 7, NONE), // 0x1e
+    op("RAR", "", 1, 4, 4, CARRY), // 0x1f
+    alias("NOP", "", 1, 4), // 0x20
+    op("LXI", "H", 3, 10, 10, NONE), // 0x21
+    op("SHLD", "", 3, 16, 16, NONE), // 0x22
+    op("INX", "H", 1, 5, 5, NONE), // 0x23
+    op("INR", "H", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x24
+    op("DCR", "H", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x25
+    op("MVI", "H", 2, 7, 7, NONE), // 0x26
+    op("DAA", "", 1, 4, 4, ALL), // 0x27
+    alias("NOP", "", 1, 4), // 0x28
+    op("DAD", "H", 1, 10, 10, CARRY), // 0x29
+    op("LHLD", "", 3, 16, 16, NONE), // 0x2a
+    op("DCX", "H", 1, 5, 5, NONE), // 0x2b
+    op("INR", "L", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x2c
+    op("DCR", "L", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x2d
+    op("MVI", "L", 2, 7, 7, NONE), // 0x2e
+    op("CMA", "", 1, 4, 4, NONE), // 0x2f
+    alias("NOP", "", 1, 4), // 0x30
+    op("LXI", "SP", 3, 10, 10, NONE), // 0x31
+    op("STA", "", 3, 13, 13, NONE), // 0x32
+    op("INX", "SP", 1, 5, 5, NONE), // 0x33
+    op("INR", "M", 1, 10, 10, SIGN | ZERO | AUX | PARITY), // 0x34
+    op("DCR", "M", 1, 10, 10, SIGN | ZERO | AUX | PARITY), // 0x35
+    op("MVI", "M", 2, 10, 10, NONE), // 0x36
+    op("STC", "", 1, 4, 4, CARRY), // 0x37
+    alias("NOP", "", 1, 4), // 0x38
+    op("DAD", "SP", 1, 10, 10, CARRY), // 0x39
+    op("LDA", "", 3, 13, 13, NONE), // 0x3a
+    op("DCX", "SP", 1, 5, 5, NONE), // 0x3b
+    op("INR", "A", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x3c
+    op("DCR", "A", 1, 5, 5, SIGN | ZERO | AUX | PARITY), // 0x3d
+    op("MVI", "A", 2, 7, 7, NONE), // 0x3e
+    op("CMC", "", 1, 4, 4, CARRY), // 0x3f
+    op("MOV", "B,B", 1, 5, 5, NONE), // 0x40
+    op("MOV", "B,C", 1, 5, 5, NONE), // 0x41
+    op("MOV", "B,D", 1, 5, 5, NONE), // 0x42
+    op("MOV", "B,E", 1, 5, 5, NONE), // 0x43
+    op("MOV", "B,H", 1, 5, 5, NONE), // 0x44
+    op("MOV", "B,L", 1, 5, 5, NONE), // 0x45
+    op("MOV", "B,M", 1, 7, 7, NONE), // 0x46
+    op("MOV", "B,A", 1, 5, 5, NONE), // 0x47
+    op("MOV", "C,B", 1, 5, 5, NONE), // 0x48
+    op("MOV", "C,C", 1, 5, 5, NONE), // 0x49
+    op("MOV", "C,D", 1, 5, 5, NONE), // 0x4a
+    op("MOV", "C,E", 1, 5, 5, NONE), // 0x4b
+    op("MOV", "C,H", 1, 5, 5, NONE), // 0x4c
+    op("MOV", "C,L", 1, 5, 5, NONE), // 0x4d
+    op("MOV", "C,M", 1, 7, 7, NONE), // 0x4e
+    op("MOV", "C,A", 1, 5, 5, NONE), // 0x4f
+    op("MOV", "D,B", 1, 5, 5, NONE), // 0x50
+    op("MOV", "D,C", 1, 5, 5, NONE), // 0x51
+    op("MOV", "D,D", 1, 5, 5, NONE), // 0x52
+    op("MOV", "D,E", 1, 5, 5, NONE), // 0x53
+    op("MOV", "D,H", 1, 5, 5, NONE), // 0x54
+    op("MOV", "D,L", 1, 5, 5, NONE), // 0x55
+    op("MOV", "D,M", 1, 7, 7, NONE), // 0x56
+    op("MOV", "D,A", 1, 5, 5, NONE), // 0x57
+    op("MOV", "E,B", 1, 5, 5, NONE), // 0x58
+    op("MOV", "E,C", 1, 5, 5, NONE), // 0x59
+    op("MOV", "E,D", 1, 5, 5, NONE), // 0x5a
+    op("MOV", "E,E", 1, 5, 5, NONE), // 0x5b
+    op("MOV", "E,H", 1, 5, 5, NONE), // 0x5c
+    op("MOV", "E,L", 1, 5, 5, NONE), // 0x5d
+    op("MOV", "E,M", 1, 7, 7, NONE), // 0x5e
+    op("MOV", "E,A", 1, 5, 5, NONE), // 0x5f
+    op("MOV", "H,B", 1, 5, 5, NONE), // 0x60
+    op("MOV", "H,C", 1, 5, 5, NONE), // 0x61
+    op("MOV", "H,D", 1, 5, 5, NONE), // 0x62
+    op("MOV", "H,E", 1, 5, 5, NONE), // 0x63
+    op("MOV", "H,H", 1, 5, 5, NONE), // 0x64
+    op("MOV", "H,L", 1, 5, 5, NONE), // 0x65
+    op("MOV", "H,M", 1, 7, 7, NONE), // 0x66
+    op("MOV", "H,A", 1, 5, 5, NONE), // 0x67
+    op("MOV", "L,B", 1, 5, 5, NONE), // 0x68
+    op("MOV", "L,C", 1, 5, 5, NONE), // 0x69
+    op("MOV", "L,D", 1, 5, 5, NONE), // 0x6a
+    op("MOV", "L,E", 1, 5, 5, NONE), // 0x6b
+    op("MOV", "L,H", 1, 5, 5, NONE), // 0x6c
+    op("MOV", "L,L", 1, 5, 5, NONE), // 0x6d
+    op("MOV", "L,M", 1, 7, 7, NONE), // 0x6e
+    op("MOV", "L,A", 1, 5, 5, NONE), // 0x6f
+    op("MOV", "M,B", 1, 7, 7, NONE), // 0x70
+    op("MOV", "M,C", 1, 7, 7, NONE), // 0x71
+    op("MOV", "M,D", 1, 7, 7, NONE), // 0x72
+    op("MOV", "M,E", 1, 7, 7, NONE), // 0x73
+    op("MOV", "M,H", 1, 7, 7, NONE), // 0x74
+    op("MOV", "M,L", 1, 7, 7, NONE), // 0x75
+    op("HLT", "", 1, 7, 7, NONE), // 0x76
+    op("MOV", "M,A", 1, 7, 7, NONE), // 0x77
+    op("MOV", "A,B", 1, 5, 5, NONE), // 0x78
+    op("MOV", "A,C", 1, 5, 5, NONE), // 0x79
+    op("MOV", "A,D", 1, 5, 5, NONE), // 0x7a
+    op("MOV", "A,E", 1, 5, 5, NONE), // 0x7b
+    op("MOV", "A,H", 1, 5, 5, NONE), // 0x7c
+    op("MOV", "A,L", 1, 5, 5, NONE), // 0x7d
+    op("MOV", "A,M", 1, 7, 7, NONE), // 0x7e
+    op("MOV", "A,A", 1, 5, 5, NONE), // 0x7f
+    op("ADD", "B", 1, 4, 4, ALL), // 0x80
+    op("ADD", "C", 1, 4, 4, ALL), // 0x81
+    op("ADD", "D", 1, 4, 4, ALL), // 0x82
+    op("ADD", "E", 1, 4, 4, ALL), // 0x83
+    op("ADD", "H", 1, 4, 4, ALL), // 0x84
+    op("ADD", "L", 1, 4, 4, ALL), // 0x85
+    op("ADD", "M", 1, 7, 7, ALL), // 0x86
+    op("ADD", "A", 1, 4, 4, ALL), // 0x87
+    op("ADC", "B", 1, 4, 4, ALL), // 0x88
+    op("ADC", "C", 1, 4, 4, ALL), // 0x89
+    op("ADC", "D", 1, 4, 4, ALL), // 0x8a
+    op("ADC", "E", 1, 4, 4, ALL), // 0x8b
+    op("ADC", "H", 1, 4, 4, ALL), // 0x8c
+    op("ADC", "L", 1, 4, 4, ALL), // 0x8d
+    op("ADC", "M", 1, 7, 7, ALL), // 0x8e
+    op("ADC", "A", 1, 4, 4, ALL), // 0x8f
+    op("SUB", "B", 1, 4, 4, ALL), // 0x90
+    op("SUB", "C", 1, 4, 4, ALL), // 0x91
+    op("SUB", "D", 1, 4, 4, ALL), // 0x92
+    op("SUB", "E", 1, 4, 4, ALL), // 0x93
+    op("SUB", "H", 1, 4, 4, ALL), // 0x94
+    op("SUB", "L", 1, 4, 4, ALL), // 0x95
+    op("SUB", "M", 1, 7, 7, ALL), // 0x96
+    op("SUB", "A", 1, 4, 4, ALL), // 0x97
+    op("SBB", "B", 1, 4, 4, ALL), // 0x98
+    op("SBB", "C", 1, 4, 4, ALL), // 0x99
+    op("SBB", "D", 1, 4, 4, ALL), // 0x9a
+    op("SBB", "E", 1, 4, 4, ALL), // 0x9b
+    op("SBB", "H", 1, 4, 4, ALL), // 0x9c
+    op("SBB", "L", 1, 4, 4, ALL), // 0x9d
+    op("SBB", "M", 1, 7, 7, ALL), // 0x9e
+    op("SBB", "A", 1, 4, 4, ALL), // 0x9f
+    op("ANA", "B", 1, 4, 4, ALL), // 0xa0
+    op("ANA", "C", 1, 4, 4, ALL), // 0xa1
+    op("ANA", "D", 1, 4, 4, ALL), // 0xa2
+    op("ANA", "E", 1, 4, 4, ALL), // 0xa3
+    op("ANA", "H", 1, 4, 4, ALL), // 0xa4
+    op("ANA", "L", 1, 4, 4, ALL), // 0xa5
+    op("ANA", "M", 1, 7, 7, ALL), // 0xa6
+    op("ANA", "A", 1, 4, 4, ALL), // 0xa7
+    op("XRA", "B", 1, 4, 4, ALL), // 0xa8
+    op("XRA", "C", 1, 4, 4, ALL), // 0xa9
+    op("XRA", "D", 1, 4, 4, ALL), // 0xaa
+    op("XRA", "E", 1, 4, 4, ALL), // 0xab
+    op("XRA", "H", 1, 4, 4, ALL), // 0xac
+    op("XRA", "L", 1, 4, 4, ALL), // 0xad
+    op("XRA", "M", 1, 7, 7, ALL), // 0xae
+    op("XRA", "A", 1, 4, 4, ALL), // 0xaf
+    op("ORA", "B", 1, 4, 4, ALL), // 0xb0
+    op("ORA", "C", 1, 4, 4, ALL), // 0xb1
+    op("ORA", "D", 1, 4, 4, ALL), // 0xb2
+    op("ORA", "E", 1, 4, 4, ALL), // 0xb3
+    op("ORA", "H", 1, 4, 4, ALL), // 0xb4
+    op("ORA", "L", 1, 4, 4, ALL), // 0xb5
+    op("ORA", "M", 1, 7, 7, ALL), // 0xb6
+    op("ORA", "A", 1, 4, 4, ALL), // 0xb7
+    op("CMP", "B", 1, 4, 4, ALL), // 0xb8
+    op("CMP", "C", 1, 4, 4, ALL), // 0xb9
+    op("CMP", "D", 1, 4, 4, ALL), // 0xba
+    op("CMP", "E", 1, 4, 4, ALL), // 0xbb
+    op("CMP", "H", 1, 4, 4, ALL), // 0xbc
+    op("CMP", "L", 1, 4, 4, ALL), // 0xbd
+    op("CMP", "M", 1, 7, 7, ALL), // 0xbe
+    op("CMP", "A", 1, 4, 4, ALL), // 0xbf
+    op("RNZ", "", 1, 11, 5, NONE), // 0xc0
+    op("POP", "B", 1, 10, 10, NONE), // 0xc1
+    op("JNZ", "", 3, 10, 10, NONE), // 0xc2
+    op("JMP", "", 3, 10, 10, NONE), // 0xc3
+    op("CNZ", "", 3, 17, 11, NONE), // 0xc4
+    op("PUSH", "B", 1, 11, 11, NONE), // 0xc5
+    op("ADI", "", 2, 7, 7, ALL), // 0xc6
+    op("RST", "0", 1, 11, 11, NONE), // 0xc7
+    op("RZ", "", 1, 11, 5, NONE), // 0xc8
+    op("RET", "", 1, 10, 10, NONE), // 0xc9
+    op("JZ", "", 3, 10, 10, NONE), // 0xca
+    alias("JMP", "", 3, 10), // 0xcb
+    op("CZ", "", 3, 17, 11, NONE), // 0xcc
+    op("CALL", "", 3, 17, 17, NONE), // 0xcd
+    op("ACI", "", 2, 7, 7, ALL), // 0xce
+    op("RST", "1", 1, 11, 11, NONE), // 0xcf
+    op("RNC", "", 1, 11, 5, NONE), // 0xd0
+    op("POP", "D", 1, 10, 10, NONE), // 0xd1
+    op("JNC", "", 3, 10, 10, NONE), // 0xd2
+    op("OUT", "", 2, 10, 10, NONE), // 0xd3
+    op("CNC", "", 3, 17, 11, NONE), // 0xd4
+    op("PUSH", "D", 1, 11, 11, NONE), // 0xd5
+    op("SUI", "", 2, 7, 7, ALL), // 0xd6
+    op("RST", "2", 1, 11, 11, NONE), // 0xd7
+    op("RC", "", 1, 11, 5, NONE), // 0xd8
+    alias("RET", "", 1, 10), // 0xd9
+    op("JC", "", 3, 10, 10, NONE), // 0xda
+    op("IN", "", 2, 10, 10, NONE), // 0xdb
+    op("CC", "", 3, 17, 11, NONE), // 0xdc
+    alias("CALL", "", 3, 17), // 0xdd
+    op("SBI", "", 2, 7, 7, ALL), // 0xde
+    op("RST", "3", 1, 11, 11, NONE), // 0xdf
+    op("RPO", "", 1, 11, 5, NONE), // 0xe0
+    op("POP", "H", 1, 10, 10, NONE), // 0xe1
+    op("JPO", "", 3, 10, 10, NONE), // 0xe2
+    op("XTHL", "", 1, 18, 18, NONE), // 0xe3
+    op("CPO", "", 3, 17, 11, NONE), // 0xe4
+    op("PUSH", "H", 1, 11, 11, NONE), // 0xe5
+    op("ANI", "", 2, 7, 7, ALL), // 0xe6
+    op("RST", "4", 1, 11, 11, NONE), // 0xe7
+    op("RPE", "", 1, 11, 5, NONE), // 0xe8
+    op("PCHL", "", 1, 5, 5, NONE), // 0xe9
+    op("JPE", "", 3, 10, 10, NONE), // 0xea
+    op("XCHG", "", 1, 5, 5, NONE), // 0xeb
+    op("CPE", "", 3, 17, 11, NONE), // 0xec
+    alias("CALL", "", 3, 17), // 0xed
+    op("XRI", "", 2, 7, 7, ALL), // 0xee
+    op("RST", "5", 1, 11, 11, NONE), // 0xef
+    op("RP", "", 1, 11, 5, NONE), // 0xf0
+    op("POP", "PSW", 1, 10, 10, ALL), // 0xf1
+    op("JP", "", 3, 10, 10, NONE), // 0xf2
+    op("DI", "", 1, 4, 4, NONE), // 0xf3
+    op("CP", "", 3, 17, 11, NONE), // 0xf4
+    op("PUSH", "PSW", 1, 11, 11, NONE), // 0xf5
+    op("ORI", "", 2, 7, 7, ALL), // 0xf6
+    op("RST", "6", 1, 11, 11, NONE), // 0xf7
+    op("RM", "", 1, 11, 5, NONE), // 0xf8
+    op("SPHL", "", 1, 5, 5, NONE), // 0xf9
+    op("JM", "", 3, 10, 10, NONE), // 0xfa
+    op("EI", "", 1, 4, 4, NONE), // 0xfb
+    op("CM", "", 3, 17, 11, NONE), // 0xfc
+    alias("CALL", "", 3, 17), // 0xfd
+    op("CPI", "", 2, 7, 7, ALL), // 0xfe
+    op("RST", "7", 1, 11, 11, NONE), // 0xff
+];
+
+/*
+ * Why bytes could not be decoded into an instruction
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    Empty,
+    InvalidOpcode(u8),
+    // the input ends before all bytes of the instruction
+    Truncated { opcode: u8, missing: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "No bytes to decode"),
+            DecodeError::InvalidOpcode(opcode) => write!(f, "Invalid opcode {:02X}H", opcode),
+            DecodeError::Truncated { opcode, missing } => {
+                write!(f, "Instruction {} is missing {} byte(s)", OPCODES[*opcode as usize].mnemonic, missing)
+            }
+        }
+    }
+}
+
+/*
+ * A decoded instruction, formatting it is left to the disassembler
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Implied(u8),
+    Byte(u8, u8),
+    Word(u8, u16),
+}
+
+impl Instruction {
+    /*
+     * Decodes the instruction at the start of the bytes
+     */
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let opcode = *bytes.first().ok_or(DecodeError::Empty)?;
+        if !OPCODES[opcode as usize].documented {
+            return Err(DecodeError::InvalidOpcode(opcode));
+        }
+        Instruction::decode_with_aliases(bytes)
+    }
+
+    /*
+     * Like decode, but undocumented opcodes are decoded as the instruction
+     * the emulator executes for them
+     */
+    pub fn decode_with_aliases(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let opcode = *bytes.first().ok_or(DecodeError::Empty)?;
+        let length = OPCODES[opcode as usize].length as usize;
+        if bytes.len() < length {
+            return Err(DecodeError::Truncated { opcode, missing: length - bytes.len() });
+        }
+        Ok(match length {
+            1 => Instruction::Implied(opcode),
+            2 => Instruction::Byte(opcode, bytes[1]),
+            _ => Instruction::Word(opcode, (bytes[2] as u16) << 8 | bytes[1] as u16),
+        })
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Instruction::Implied(opcode) | Instruction::Byte(opcode, _) | Instruction::Word(opcode, _) => opcode,
+        }
+    }
+
+    pub fn info(&self) -> &'static Opcode {
+        &OPCODES[self.opcode() as usize]
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    // The immediate value or address
+    pub fn immediate(&self) -> Option<u16> {
+        match *self {
+            Instruction::Implied(_) => None,
+            Instruction::Byte(_, value) => Some(value as u16),
+            Instruction::Word(_, value) => Some(value),
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        self.info().length as usize
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.info().cycles as usize
+    }
+
+    pub fn cycles_not_taken(&self) -> usize {
+        self.info().cycles_not_taken as usize
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.info().flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(Ok(Instruction::Implied(0x41)), Instruction::decode(&[0x41, 0xff]));
+        assert_eq!(Ok(Instruction::Byte(0x3e, 0x12)), Instruction::decode(&[0x3e, 0x12]));
+        assert_eq!(Ok(Instruction::Word(0xc3, 0x1234)), Instruction::decode(&[0xc3, 0x34, 0x12]));
+        assert_eq!(Err(DecodeError::InvalidOpcode(0x08)), Instruction::decode(&[0x08]));
+        assert_eq!(Err(DecodeError::Truncated { opcode: 0x01, missing: 1 }), Instruction::decode(&[0x01, 0x00]));
+        assert_eq!(Err(DecodeError::Empty), Instruction::decode(&[]));
+    }
+
+    #[test]
+    fn decode_aliases() {
+        assert_eq!(Ok(Instruction::Implied(0x08)), Instruction::decode_with_aliases(&[0x08]));
+        assert_eq!(Ok(Instruction::Word(0xdd, 0x1234)), Instruction::decode_with_aliases(&[0xdd, 0x34, 0x12]));
+        assert_eq!("CALL", Instruction::decode_with_aliases(&[0xdd, 0x34, 0x12]).unwrap().mnemonic());
+        assert_eq!(
+            Err(DecodeError::Truncated { opcode: 0xcb, missing: 2 }),
+            Instruction::decode_with_aliases(&[0xcb])
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!("Invalid opcode 08H", DecodeError::InvalidOpcode(0x08).to_string());
+        assert_eq!("Instruction JMP is missing 2 byte(s)", DecodeError::Truncated { opcode: 0xc3, missing: 2 }.to_string());
+    }
+
+    #[test]
+    fn metadata() {
+        let mov = Instruction::decode(&[0x41]).unwrap();
+        assert_eq!(("MOV", "B,C", 1, 5, NONE), (mov.mnemonic(), mov.info().operands, mov.length(), mov.cycles(), mov.flags()));
+
+        let cnz = Instruction::decode(&[0xc4, 0x00, 0x01]).unwrap();
+        assert_eq!((17, 11), (cnz.cycles(), cnz.cycles_not_taken()));
+        assert_eq!(Some(0x100), cnz.immediate());
+
+        assert_eq!(SIGN | ZERO | AUX | PARITY, Instruction::decode(&[0x04]).unwrap().flags());
+        assert_eq!(CARRY, Instruction::decode(&[0x09]).unwrap().flags());
+        assert_eq!(ALL, Instruction::decode(&[0xfe, 0x00]).unwrap().flags());
+    }
+
+    #[test]
+    fn aliases() {
+        let aliased: Vec<usize> = (0..256).filter(|&opcode| !OPCODES[opcode].documented).collect();
+        assert_eq!(vec![0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd], aliased);
+        for opcode in aliased {
+            let original = OPCODES.iter().find(|info| info.documented && info.mnemonic == OPCODES[opcode].mnemonic).unwrap();
+            assert_eq!((original.length, original.cycles), (OPCODES[opcode].length, OPCODES[opcode].cycles));
+        }
+    }
+}
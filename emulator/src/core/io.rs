@@ -7,6 +7,34 @@ pub trait OutputDevice {
     fn write(&mut self, byte: u8);
 }
 
+/*
+ * Device that takes up a range of the address space instead of a port.
+ * Offsets are counted from the start of that range.
+ */
+pub trait MemoryDevice {
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, byte: u8);
+
+    /*
+     * Reads for the debugger, which must not have side effects like popping a
+     * FIFO. Devices that can't show a byte without them read as an open bus.
+     */
+    fn peek(&self, _offset: u16) -> u8 {
+        0xff
+    }
+}
+
+/*
+ * Devices implement this to be part of save states. The bytes are opaque to
+ * the emulator, a device only has to understand what it saved itself.
+ */
+pub trait DeviceState {
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), &'static str>;
+}
+
 /* Input/Output device that does nothing */
 pub struct DevNull {}
 
@@ -0,0 +1,228 @@
+use std::ops::{Index, IndexMut, RangeInclusive};
+
+use crate::core::ram::RAM;
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// What reads from unmapped addresses return, the data bus is pulled high
+pub const OPEN_BUS: u8 = 0xff;
+
+/*
+ * What is found at a range of addresses
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionKind {
+    Ram,
+    // reads like RAM, writes are ignored
+    Rom,
+    // repeats the addresses of the range, which keep their own kind
+    Mirror(RangeInclusive<u16>),
+    // reads return OPEN_BUS, writes are ignored
+    Unmapped,
+}
+
+// Where an address ends up after resolving mirrors
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    Ram(u16),
+    Rom(u16),
+    Open,
+}
+
+/*
+ * Memory composed of regions as found on real machines. Regions are mapped
+ * one after the other, later ones replace earlier ones where they overlap.
+ * Mirrors resolve to whatever their target is mapped to when they are added.
+ * Everything is unmapped until something else is mapped there.
+ */
+pub struct MemoryMap {
+    mem: Vec<u8>,
+    slots: Vec<Slot>,
+    // writes to ROM and unmapped addresses end up here
+    sink: u8,
+    open_bus: u8,
+    last_change: u16,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap {
+            mem: vec![0; ADDRESS_SPACE],
+            slots: vec![Slot::Open; ADDRESS_SPACE],
+            sink: 0,
+            open_bus: OPEN_BUS,
+            last_change: 0,
+        }
+    }
+
+    /*
+     * The Space Invaders board only decodes the lower 14 address lines
+     *
+     * ROM: 0000-1fff
+     * RAM: 2000-3fff (video RAM from 2400)
+     * Mirror of everything above: 4000-ffff
+     */
+    pub fn space_invaders() -> Self {
+        let mut map = MemoryMap::new();
+        map.map(0x0000..=0x1fff, RegionKind::Rom);
+        map.map(0x2000..=0x3fff, RegionKind::Ram);
+        map.map(0x4000..=0xffff, RegionKind::Mirror(0x0000..=0x3fff));
+        map
+    }
+
+    pub fn map(&mut self, range: RangeInclusive<u16>, kind: RegionKind) {
+        for address in range.clone() {
+            let offset = (address - range.start()) as usize;
+            self.slots[address as usize] = match &kind {
+                RegionKind::Ram => Slot::Ram(address),
+                RegionKind::Rom => Slot::Rom(address),
+                RegionKind::Unmapped => Slot::Open,
+                RegionKind::Mirror(target) => {
+                    let size = (target.end() - target.start()) as usize + 1;
+                    self.slots[*target.start() as usize + offset % size]
+                }
+            };
+        }
+    }
+
+    pub fn is_writable(&self, address: u16) -> bool {
+        matches!(self.slots[address as usize], Slot::Ram(_))
+    }
+
+    pub fn is_mapped(&self, address: u16) -> bool {
+        self.slots[address as usize] != Slot::Open
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap::new()
+    }
+}
+
+impl RAM for MemoryMap {
+    fn size(&self) -> usize {
+        ADDRESS_SPACE
+    }
+
+    // Loading also fills ROM, only unmapped addresses are skipped
+    fn load_vec(&mut self, vec: Vec<u8>, start: u16) {
+        for (offset, byte) in vec.into_iter().enumerate() {
+            match self.slots[(start as usize + offset) % ADDRESS_SPACE] {
+                Slot::Ram(address) | Slot::Rom(address) => self.mem[address as usize] = byte,
+                Slot::Open => {}
+            }
+        }
+    }
+
+    fn get_ptr(&self) -> *const u8 {
+        self.mem.as_ptr()
+    }
+
+    fn get_last_changed_address(&self) -> u16 {
+        self.last_change
+    }
+}
+
+impl Index<u16> for MemoryMap {
+    type Output = u8;
+
+    fn index(&self, index: u16) -> &Self::Output {
+        match self.slots[index as usize] {
+            Slot::Ram(address) | Slot::Rom(address) => &self.mem[address as usize],
+            Slot::Open => &self.open_bus,
+        }
+    }
+}
+
+impl IndexMut<u16> for MemoryMap {
+    fn index_mut(&mut self, index: u16) -> &mut Self::Output {
+        match self.slots[index as usize] {
+            Slot::Ram(address) => {
+                self.last_change = address;
+                &mut self.mem[address as usize]
+            }
+            Slot::Rom(_) | Slot::Open => &mut self.sink,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::Emulator;
+
+    #[test]
+    fn regions() {
+        let mut map = MemoryMap::new();
+        map.map(0x0000..=0x00ff, RegionKind::Rom);
+        map.map(0x0100..=0x01ff, RegionKind::Ram);
+        map.load_vec(vec![1, 2], 0x00ff);
+        assert_eq!(1, map[0x00ff]);
+        assert_eq!(2, map[0x0100]);
+
+        map[0x00ff] = 9;
+        map[0x0100] = 9;
+        assert_eq!(1, map[0x00ff]);
+        assert_eq!(9, map[0x0100]);
+        assert_eq!(0x0100, map.get_last_changed_address());
+
+        map[0x0200] = 9;
+        assert_eq!(OPEN_BUS, map[0x0200]);
+        assert!(!map.is_mapped(0x0200));
+        assert!(map.is_writable(0x0100));
+        assert!(!map.is_writable(0x0000));
+    }
+
+    #[test]
+    fn mirrors() {
+        let mut map = MemoryMap::new();
+        map.map(0x0000..=0x000f, RegionKind::Ram);
+        // four copies of the 16 bytes
+        map.map(0x0010..=0x004f, RegionKind::Mirror(0x0000..=0x000f));
+        map[0x0003] = 7;
+        assert_eq!(7, map[0x0013]);
+        assert_eq!(7, map[0x0043]);
+        map[0x0025] = 5;
+        assert_eq!(5, map[0x0005]);
+        assert_eq!(0x0005, map.get_last_changed_address());
+        assert_eq!(OPEN_BUS, map[0x0050]);
+
+        // later regions replace earlier ones
+        map.map(0x0040..=0x004f, RegionKind::Unmapped);
+        assert_eq!(OPEN_BUS, map[0x0043]);
+    }
+
+    #[test]
+    fn space_invaders() {
+        let mut map = MemoryMap::space_invaders();
+        map.load_vec(vec![0xc3, 0xd4, 0x18], 0);
+        map[0x0000] = 0;
+        assert_eq!(0xc3, map[0x0000]);
+        assert_eq!(0xc3, map[0x4000]);
+
+        map[0x2400] = 0x55;
+        assert_eq!(0x55, map[0x6400]);
+        assert_eq!(0x55, map[0xe400]);
+        map[0xffff] = 0xaa;
+        assert_eq!(0xaa, map[0x3fff]);
+        assert!(!map.is_writable(0xc000));
+    }
+
+    #[test]
+    fn roms() {
+        let mut rom = Vec::new();
+        for part in ["h", "g", "f", "e"].iter() {
+            rom.extend(std::fs::read(format!("../roms/invaders.{}", part)).unwrap());
+        }
+        let mut emu = Emulator::new();
+        emu.ram = Box::new(MemoryMap::space_invaders());
+        emu.load_ram(rom, 0);
+        // the first instructions set up the stack and clear the RAM without touching the ROM
+        for _ in 0..10000 {
+            emu.execute_next().unwrap();
+        }
+        assert_eq!(0xc3, emu.ram[0x0003]);
+        assert!(emu.sp >= 0x2000 && emu.sp <= 0x2400);
+    }
+}
@@ -1,4 +1,8 @@
+pub mod banked_memory;
 pub mod emulator;
+pub mod instruction;
 pub mod io;
+pub mod memory_map;
 pub mod ram;
 pub mod register;
+pub mod trace_diff;
@@ -3,15 +3,30 @@ use std::fs::File;
 use std::io;
 use std::io::*;
 
+use serde::{Deserialize, Serialize};
 
-const RAM_SIZE: usize = 0xFFFF;
+
+// The whole 16 bit address space
+const RAM_SIZE: usize = 0x10000;
+
+/*
+ * Contents of memory in a save state
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryState {
+    pub memory: Vec<u8>,
+    // contents of all banks and the selected one, only used by banked memory
+    pub banks: Vec<Vec<u8>>,
+    pub bank: usize,
+}
 
 pub struct DefaultRam {
-    mem: [u8; RAM_SIZE],
+    mem: Vec<u8>,
     lastChange: u16
 }
 
 pub trait RAM: Index<u16, Output=u8> + IndexMut<u16, Output=u8> {
+    // Bytes of physical memory, every address can be indexed regardless
     fn size(&self) -> usize;
 
     fn load_vec(&mut self, vec: Vec<u8>, start: u16);
@@ -19,23 +34,41 @@ pub trait RAM: Index<u16, Output=u8> + IndexMut<u16, Output=u8> {
     fn get_ptr(&self) -> *const u8;
     
     fn get_last_changed_address(&self) -> u16;
+
+    // Bank shown at the address for memory with switched banks
+    fn bank_at(&self, _address: u16) -> Option<usize> {
+        None
+    }
+
+    // The whole address space as the CPU sees it
+    fn save_state(&self) -> MemoryState {
+        MemoryState { memory: (0..=u16::MAX).map(|address| self[address]).collect(), banks: Vec::new(), bank: 0 }
+    }
+
+    fn load_state(&mut self, state: &MemoryState) -> std::result::Result<(), &'static str> {
+        if state.memory.len() != RAM_SIZE || !state.banks.is_empty() {
+            return Err("Save state doesn't match the memory");
+        }
+        self.load_vec(state.memory.clone(), 0);
+        Ok(())
+    }
 }
 
 impl RAM for DefaultRam {
     fn size(&self) -> usize {
-        RAM_SIZE
+        self.mem.len()
     }
 
     fn load_vec(&mut self, vec: Vec<u8>, start: u16) {
         let mut idx = start;
         for byte in vec {
             self[idx] = byte;
-            idx += 1;
+            idx = idx.wrapping_add(1);
         }
     }
     
     fn get_ptr(&self) -> *const u8 {
-        return &self.mem as *const u8;
+        return self.mem.as_ptr();
     }
 
     fn get_last_changed_address(&self) -> u16 {
@@ -46,15 +79,20 @@ impl RAM for DefaultRam {
 impl DefaultRam {
     /*
      * Struct representing the RAM
-     * Can be indexed with u16's but only the 14 LSB's are used
-     * 2 MSB's are masked out, because all adresses >= 2^14 mirror the RAM
-     *
-     * fake ROM: 0000-1fff
-     * RAM: 2000-3fff
-     * RAM-Mirror: 4000-
+     * Flat and writable everywhere, without ROM. MemoryMap provides that,
+     * e.g. with the Space Invaders layout.
      */
     pub fn new() -> Self {
-        Self { mem: [0; RAM_SIZE], lastChange: 0 }
+        Self { mem: vec![0; RAM_SIZE], lastChange: 0 }
+    }
+
+    /*
+     * Less physical memory than the address space, for machines that only
+     * populate part of the bus. The memory is mirrored over all addresses,
+     * sizes are limited to 1 byte up to 64 KiB.
+     */
+    pub fn with_size(size: usize) -> Self {
+        Self { mem: vec![0; size.clamp(1, RAM_SIZE)], lastChange: 0 }
     }
 
     pub fn load_file(&mut self, path: &str, start: u16) -> io::Result<()> {
@@ -69,14 +107,15 @@ impl Index<u16> for DefaultRam {
     type Output = u8;
 
     fn index(&self, index: u16) -> &Self::Output {
-        &self.mem[(index & 0xFFFF) as usize]
+        &self.mem[index as usize % self.mem.len()]
     }
 }
 
 impl IndexMut<u16> for DefaultRam {
     fn index_mut(&mut self, index: u16) -> &mut Self::Output {
         self.lastChange = index;
-        &mut self.mem[(index & 0xFFFF) as usize]
+        let size = self.mem.len();
+        &mut self.mem[index as usize % size]
     }
 }
 
@@ -105,5 +144,23 @@ mod tests {
         r[1] = 2; r[2] = 3; r[3] = 4; r[4] = 5;
         let slice = &r[0..5];
         assert_eq!(slice, &[1, 2, 3, 4, 5]);
+
+        r[0xffff] = 6;
+        assert_eq!(r[0xffff], 6);
+        assert_eq!(r.size(), 0x10000);
+    }
+
+    #[test]
+    fn mirrored() {
+        let mut r = DefaultRam::with_size(0x4000);
+        assert_eq!(r.size(), 0x4000);
+        r[0x0132] = 69;
+        assert_eq!(r[0x4132], 69);
+        assert_eq!(r[0xc132], 69);
+
+        r.load_vec(vec![1, 2], 0xffff);
+        assert_eq!(r[0x3fff], 1);
+        assert_eq!(r[0x0000], 2);
+        assert_eq!(DefaultRam::with_size(0).size(), 1);
     }
 }
@@ -0,0 +1,284 @@
+use std::fmt;
+
+use crate::core::emulator::tracer::{Field, TraceFormat};
+
+/*
+ * An instruction of a trace with the line it was read from
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    // line number in the trace, starting at 1
+    pub number: usize,
+    pub text: String,
+    pub fields: Vec<(Field, String)>,
+}
+
+impl TraceLine {
+    fn get(&self, field: Field) -> Option<&str> {
+        self.fields.iter().find(|(other, _)| *other == field).map(|(_, value)| value.as_str())
+    }
+}
+
+/*
+ * First instruction where two traces differ, None if the trace ended before it
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub left: Option<TraceLine>,
+    pub right: Option<TraceLine>,
+    // fields with different values, with the values of the left and right trace
+    pub differences: Vec<(Field, String, String)>,
+    // the last instructions before the divergence, from the left trace
+    pub context: Vec<TraceLine>,
+}
+
+/*
+ * Result of diff_traces, instructions counts the instructions that matched
+ * before the divergence
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceDiff {
+    pub instructions: usize,
+    pub divergence: Option<Divergence>,
+}
+
+/*
+ * Reads the instructions of a trace. Empty lines are skipped, any other line
+ * not matching the format is an error, as is a trace without instructions.
+ */
+pub fn parse_trace(trace: &str, format: &TraceFormat) -> Result<Vec<TraceLine>, String> {
+    let mut lines = Vec::new();
+    for (index, text) in trace.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        match format.parse_line(text) {
+            Some(fields) => lines.push(TraceLine { number: index + 1, text: text.to_string(), fields }),
+            None => return Err(format!("Line {} doesn't match the trace format: {}", index + 1, text)),
+        }
+    }
+    if lines.is_empty() {
+        return Err(String::from("Trace contains no instructions"));
+    }
+    Ok(lines)
+}
+
+/*
+ * Compares two traces instruction by instruction. The traces can use different
+ * formats, only the fields both contain are compared, apart from the
+ * disassembly, which differs between emulators. Cycles are compared relative
+ * to the first compared instruction.
+ *
+ * If the traces start at different points, the one starting later is aligned
+ * to the first matching instruction of the other one. A trace ending before
+ * the other one diverges at its first missing instruction.
+ */
+pub fn diff_traces(left: &[TraceLine], right: &[TraceLine], context: usize) -> TraceDiff {
+    let (mut l, mut r) = align(left, right);
+    let base = (cycles(left.get(l)), cycles(right.get(r)));
+    let mut instructions = 0;
+    while let (Some(left_line), Some(right_line)) = (left.get(l), right.get(r)) {
+        let differences = differences(left_line, right_line, base);
+        if !differences.is_empty() {
+            let divergence = Divergence {
+                left: Some(left_line.clone()),
+                right: Some(right_line.clone()),
+                differences,
+                context: left[l.saturating_sub(context)..l].to_vec(),
+            };
+            return TraceDiff { instructions, divergence: Some(divergence) };
+        }
+        instructions += 1;
+        l += 1;
+        r += 1;
+    }
+    let divergence = match (left.get(l), right.get(r)) {
+        (None, None) => None,
+        (left_line, right_line) => Some(Divergence {
+            left: left_line.cloned(),
+            right: right_line.cloned(),
+            differences: Vec::new(),
+            context: left[l.saturating_sub(context)..l].to_vec(),
+        }),
+    };
+    TraceDiff { instructions, divergence }
+}
+
+// Start of both traces where they first match
+fn align(left: &[TraceLine], right: &[TraceLine]) -> (usize, usize) {
+    let same = |a: &TraceLine, b: &TraceLine| differences(a, b, (None, None)).is_empty();
+    if let Some(first) = left.first() {
+        if let Some(r) = right.iter().position(|line| same(first, line)) {
+            return (0, r);
+        }
+    }
+    if let Some(first) = right.first() {
+        if let Some(l) = left.iter().position(|line| same(line, first)) {
+            return (l, 0);
+        }
+    }
+    (0, 0)
+}
+
+fn cycles(line: Option<&TraceLine>) -> Option<u64> {
+    line?.get(Field::Cycles)?.parse().ok()
+}
+
+// The cycles are ignored without base
+fn differences(left: &TraceLine, right: &TraceLine, base: (Option<u64>, Option<u64>)) -> Vec<(Field, String, String)> {
+    let mut differences = Vec::new();
+    for (field, value) in &left.fields {
+        let other = match right.get(*field) {
+            Some(other) => other,
+            None => continue,
+        };
+        let same = match field {
+            Field::Disassembly | Field::Step => true,
+            Field::Cycles => match (base, cycles(Some(left)), cycles(Some(right))) {
+                ((Some(left_base), Some(right_base)), Some(left), Some(right)) => {
+                    left.wrapping_sub(left_base) == right.wrapping_sub(right_base)
+                }
+                _ => true,
+            },
+            _ => value.eq_ignore_ascii_case(other),
+        };
+        if !same {
+            differences.push((*field, value.clone(), other.to_string()));
+        }
+    }
+    differences
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.context {
+            writeln!(f, "  {:>6}: {}", line.number, line.text)?;
+        }
+        for (sign, line) in [('-', &self.left), ('+', &self.right)] {
+            match line {
+                Some(line) => writeln!(f, "{} {:>6}: {}", sign, line.number, line.text)?,
+                None => writeln!(f, "{} {:>6}  (end of trace)", sign, "")?,
+            }
+        }
+        for (field, left, right) in &self.differences {
+            writeln!(f, "{}: {} != {}", field, left, right)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::tracer::REFERENCE_TRACE;
+    use crate::core::emulator::Emulator;
+
+    // MVI A,1, INR A, LXI SP,100H, PUSH PSW, HLT
+    const PROGRAM: [u8; 8] = [0x3e, 0x01, 0x3c, 0x31, 0x00, 0x01, 0xf5, 0x76];
+
+    fn trace(format: &str, program: &[u8]) -> String {
+        let mut emu = Emulator::new();
+        emu.load_ram(program.to_vec(), 0);
+        emu.enable_trace(format, 100).unwrap();
+        for _ in 0..5 {
+            emu.execute_next().unwrap();
+        }
+        emu.take_trace()
+    }
+
+    #[test]
+    fn identical() {
+        let format = TraceFormat::default();
+        let left = parse_trace(&trace("", &PROGRAM), &format).unwrap();
+        assert_eq!(5, left.len());
+        assert_eq!(TraceDiff { instructions: 5, divergence: None }, diff_traces(&left, &left, 3));
+    }
+
+    #[test]
+    fn different_lengths() {
+        let format = TraceFormat::default();
+        let left = parse_trace(&trace("", &PROGRAM), &format).unwrap();
+        let diff = diff_traces(&left, &left[..2], 1);
+        assert_eq!(2, diff.instructions);
+        let divergence = diff.divergence.unwrap();
+        assert_eq!(Some(left[2].clone()), divergence.left);
+        assert_eq!(None, divergence.right);
+        assert!(divergence.to_string().ends_with(&format!("-      3: {}\n+         (end of trace)\n", left[2].text)));
+
+        // the other way around
+        let divergence = diff_traces(&left[..4], &left, 1).divergence.unwrap();
+        assert_eq!((None, Some(5)), (divergence.left, divergence.right.map(|line| line.number)));
+    }
+
+    #[test]
+    fn unparseable() {
+        let format = TraceFormat::parse(REFERENCE_TRACE).unwrap();
+        assert_eq!(Err(String::from("Trace contains no instructions")), parse_trace("\n\n", &format));
+        // a trace in another format
+        let other = trace("", &PROGRAM);
+        assert_eq!(
+            Err(format!("Line 1 doesn't match the trace format: {}", other.lines().next().unwrap())),
+            parse_trace(&other, &format)
+        );
+        let output = format!("{}\nHello, world\n", trace(REFERENCE_TRACE, &PROGRAM));
+        assert_eq!(
+            Err(String::from("Line 7 doesn't match the trace format: Hello, world")),
+            parse_trace(&output, &format)
+        );
+    }
+
+    #[test]
+    fn divergence() {
+        let format = TraceFormat::parse(REFERENCE_TRACE).unwrap();
+        let left = parse_trace(&trace(REFERENCE_TRACE, &PROGRAM), &format).unwrap();
+        // HLT replaced with NOP, which only shows up in the memory at pc
+        let mut program = PROGRAM;
+        program[7] = 0x00;
+        let right = format!("\n{}", trace(REFERENCE_TRACE, &program));
+        let right = parse_trace(&right, &format).unwrap();
+        assert_eq!(3, right[1].number);
+
+        let diff = diff_traces(&left, &right, 1);
+        assert_eq!(3, diff.instructions);
+        let divergence = diff.divergence.unwrap();
+        assert_eq!(
+            vec![(Field::Memory, String::from("F5 76 00 00"), String::from("F5 00 00 00"))],
+            divergence.differences
+        );
+        assert_eq!(
+            "       3: PC: 0003, AF: 0202, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 12\t(31 00 01 F5)\n\
+             -      4: PC: 0006, AF: 0202, BC: 0000, DE: 0000, HL: 0000, SP: 0100, CYC: 22\t(F5 76 00 00)\n\
+             +      5: PC: 0006, AF: 0202, BC: 0000, DE: 0000, HL: 0000, SP: 0100, CYC: 22\t(F5 00 00 00)\n\
+             memory: F5 76 00 00 != F5 00 00 00\n",
+            divergence.to_string()
+        );
+    }
+
+    #[test]
+    fn alignment() {
+        let format = TraceFormat::default();
+        let left = parse_trace(&trace("", &PROGRAM), &format).unwrap();
+        // the right trace starts at the INR A, with its own cycle count
+        let mut emu = Emulator::new();
+        emu.load_ram(PROGRAM.to_vec(), 0);
+        emu.execute_next().unwrap();
+        emu.enable_trace("", 100).unwrap();
+        for _ in 0..3 {
+            emu.execute_next().unwrap();
+        }
+        emu.reg['b'] = 1;
+        emu.execute_next().unwrap();
+        let right = parse_trace(&emu.take_trace(), &format).unwrap();
+
+        let diff = diff_traces(&left, &right, 2);
+        assert_eq!(3, diff.instructions);
+        let divergence = diff.divergence.unwrap();
+        assert_eq!(
+            vec![(Field::Pair("bc"), String::from("0000"), String::from("0100"))],
+            divergence.differences
+        );
+        assert_eq!(vec![3, 4], divergence.context.iter().map(|line| line.number).collect::<Vec<_>>());
+        // or the other way around
+        assert_eq!(3, diff_traces(&right, &left, 2).instructions);
+    }
+}
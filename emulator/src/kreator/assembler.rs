@@ -1,10 +1,21 @@
 use super::parser::eval;
-use super::preprocessor::{get_preprocessed_code, get_line_map};
+use crate::core::instruction::OPCODES;
+use super::preprocessor::{cached_regex, get_line_map, preprocess, strip_comment};
 use core::fmt;
-use regex::Regex;
+use std::collections::HashMap;
 
 pub const LABEL_DECL: &str = r"^( *[a-zA-Z@?][a-zA-Z@?0-9]*:)";
 
+// Only the first characters of a label are significant
+pub const LABEL_LENGTH: usize = 5;
+
+/*
+ * Name a label is stored and referenced under, longer names are truncated
+ */
+pub fn label_name(name: &str) -> &str {
+    name.get(..LABEL_LENGTH).unwrap_or(name)
+}
+
 pub fn get_reserved_names() -> Vec<&'static str> {
     vec![
         "STC", "CMC", "INR", "DCR", "CMA", "DAA", "NOP", "MOV", "STAX", "LDAX", "ADD", "ADC",
@@ -40,8 +51,15 @@ impl Assembler {
     }
 
     pub fn assemble(&self) -> Result<Vec<u8>, &'static str> {
-        let preprocessed_code = get_preprocessed_code(&self.code)?;
-        let origins = self.get_origins();
+        Ok(self.assemble_with_labels()?.0)
+    }
+
+    /*
+     * Assembles the code and additionally returns the address of every label
+     */
+    pub fn assemble_with_labels(&self) -> Result<(Vec<u8>, HashMap<String, u16>), &'static str> {
+        let (preprocessed_code, labels) = preprocess(&self.commentless_code())?;
+        let origins = get_origins_of(&preprocessed_code)?;
 
         let mut byte_code: Vec<u8> = Vec::new();
         let mut current_address: u16 = 0;
@@ -70,11 +88,11 @@ impl Assembler {
                 byte_code.extend(bytes);
             }
         }
-        Ok(byte_code)
+        Ok((byte_code, labels))
     }
 
     pub fn get_line_map(&self) -> Result<Vec<usize>, &'static str> {
-        let mapping = get_line_map(&self.code)?;
+        let mapping = get_line_map(&self.commentless_code())?;
         let mut mapped_vec: Vec<usize> = vec![0; mapping.len()];
         for (byte, line) in mapping {
             mapped_vec[byte as usize] = line;
@@ -82,32 +100,37 @@ impl Assembler {
         Ok(mapped_vec)
     }
 
-    pub fn get_origins(&self) -> Vec<(u16, u16)> {
-        let label_regex = Regex::new(LABEL_DECL).unwrap();
-        let mut origins: Vec<(u16, u16)> = Vec::new();
-        let mut executed_bytes = 0;
-
-        for line in get_preprocessed_code(&self.code).unwrap() {
-            if line.contains("ORG") {
-                let split = line.split_once(" ").unwrap();
-                origins.push((executed_bytes, evaluate_str(split.1)));
-            } else if line.starts_with("DB ") {
-                executed_bytes = executed_bytes + convert_db_statement(&line).len() as u16;
-            } else if line.starts_with("DW ") {
-                executed_bytes = executed_bytes + convert_dw_statement(&line).len() as u16;
-            } else if line.starts_with("DS ") {
-                executed_bytes = executed_bytes + convert_ds_statement(&line).len() as u16;
-            } else {
-                let line = label_regex.replace(&line, "").to_string();
-                executed_bytes = executed_bytes + to_machine_code(line).unwrap().len() as u16;
-            }
+    // Comments are kept for Display, the lines stay where they are
+    fn commentless_code(&self) -> Vec<String> {
+        self.code.iter().map(|line| strip_comment(line).trim().to_string()).collect()
+    }
+}
+
+fn get_origins_of(preprocessed_code: &Vec<String>) -> Result<Vec<(u16, u16)>, &'static str> {
+    let label_regex = cached_regex!(LABEL_DECL);
+    let mut origins: Vec<(u16, u16)> = Vec::new();
+    let mut executed_bytes = 0;
+
+    for line in preprocessed_code {
+        if line.contains("ORG") {
+            let split = line.split_once(" ").unwrap();
+            origins.push((executed_bytes, evaluate_str(split.1)));
+        } else if line.starts_with("DB ") {
+            executed_bytes = executed_bytes + convert_db_statement(&line).len() as u16;
+        } else if line.starts_with("DW ") {
+            executed_bytes = executed_bytes + convert_dw_statement(&line).len() as u16;
+        } else if line.starts_with("DS ") {
+            executed_bytes = executed_bytes + convert_ds_statement(&line).len() as u16;
+        } else {
+            let line = label_regex.replace(&line, "").to_string();
+            executed_bytes = executed_bytes + to_machine_code(line)?.len() as u16;
         }
-        origins
     }
+    Ok(origins)
 }
 
-fn to_machine_code(instruction: String) -> Result<Vec<u8>, &'static str> {
-    let label_regex = Regex::new(LABEL_DECL).unwrap();
+pub fn to_machine_code(instruction: String) -> Result<Vec<u8>, &'static str> {
+    let label_regex = cached_regex!(LABEL_DECL);
     let instruction = label_regex.replace(&instruction, "").to_string();
     let mut args: Vec<&str> = Vec::new();
 
@@ -143,168 +166,45 @@ fn to_machine_code(instruction: String) -> Result<Vec<u8>, &'static str> {
                     "D" => return Ok(vec![0x1a]),
                     _ => return Err("wrong register!"),
                 },
-                "SHLD" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0x22, adr as u8, (adr >> 8) as u8]);
-                }
-                "LHLD" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0x2a, adr as u8, (adr >> 8) as u8]);
-                }
-                "STA" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0x32, adr as u8, (adr >> 8) as u8]);
-                }
-                "LDA" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0x3a, adr as u8, (adr >> 8) as u8]);
-                }
-                "JMP" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xc3, adr as u8, (adr >> 8) as u8]);
-                }
-                "JNZ" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xc2, adr as u8, (adr >> 8) as u8]);
-                }
-                "CNZ" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xc4, adr as u8, (adr >> 8) as u8]);
-                }
-                "ADI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xc6, adr as u8]);
-                }
-                "JZ" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xca, adr as u8, (adr >> 8) as u8]);
-                }
-                "CZ" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xcc, adr as u8, (adr >> 8) as u8]);
-                }
-                "CALL" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xcd, adr as u8, (adr >> 8) as u8]);
-                }
-                "ACI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xce, adr as u8]);
-                }
-                "JNC" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xd2, adr as u8, (adr >> 8) as u8]);
-                }
-                "OUT" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xd3, adr as u8]);
-                }
-                "CNC" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xd4, adr as u8, (adr >> 8) as u8]);
-                }
-                "SUI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xd6, adr as u8]);
-                }
-                "JC" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xda, adr as u8, (adr >> 8) as u8]);
-                }
-                "IN" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xdb, adr as u8]);
-                }
-                "CC" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xdc, adr as u8, (adr >> 8) as u8]);
-                }
-                "SBI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xde, adr as u8]);
-                }
-                "JPO" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xe2, adr as u8, (adr >> 8) as u8]);
-                }
-                "CPO" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xe4, adr as u8, (adr >> 8) as u8]);
-                }
-                "ANI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xe6, adr as u8]);
-                }
-                "JPE" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xea, adr as u8, (adr >> 8) as u8]);
-                }
-                "CPE" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xec, adr as u8, (adr >> 8) as u8]);
-                }
-                "XRI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xee, adr as u8]);
-                }
-                "JP" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xf2, adr as u8, (adr >> 8) as u8]);
-                }
-                "CP" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xf4, adr as u8, (adr >> 8) as u8]);
-                }
-                "ORI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xf6, adr as u8]);
-                }
-                "JM" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xfa, adr as u8, (adr >> 8) as u8]);
-                }
-                "CM" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xfc, adr as u8, (adr >> 8) as u8]);
-                }
-                "CPI" => {
-                    let adr = evaluate_str(args[0]);
-                    return Ok(vec![0xfe, adr as u8]);
-                }
-                _ => return Err("Could not match instruction"),
+                _ => return convert_immediate_args(opcode, args),
             }
         }
-        None => match instruction.trim() {
-            "NOP" => return Ok(vec![0x0]),
-            "RLC" => return Ok(vec![0x7]),
-            "RRC" => return Ok(vec![0x0f]),
-            "RAL" => return Ok(vec![0x17]),
-            "RAR" => return Ok(vec![0x1f]),
-            "CMA" => return Ok(vec![0x2f]),
-            "CMC" => return Ok(vec![0x3f]),
-            "DAA" => return Ok(vec![0x27]),
-            "HLT" => return Ok(vec![0x76]),
-            "RNZ" => return Ok(vec![0xc0]),
-            "STC" => return Ok(vec![0x37]),
-            "RET" => return Ok(vec![0xc9]),
-            "RNC" => return Ok(vec![0xd0]),
-            "RPE" => return Ok(vec![0xe8]),
-            "RPO" => return Ok(vec![0xe0]),
-            "EI" => return Ok(vec![0xfb]),
-            "RM" => return Ok(vec![0xf8]),
-            "RZ" => return Ok(vec![0xc8]),
-            "RC" => return Ok(vec![0xd8]),
-            "DI" => return Ok(vec![0xf3]),
-            "RP" => return Ok(vec![0xf0]),
-            "SPHL" => return Ok(vec![0xf9]),
-            "XCHG" => return Ok(vec![0xeb]),
-            "PCHL" => return Ok(vec![0xe9]),
-            "XTHL" => return Ok(vec![0xe3]),
-            _ => return Err("Could not match instruction"),
+        None => match find_opcode(instruction.trim(), 1) {
+            Some(opcode) => return Ok(vec![opcode]),
+            None => return Err("Could not match instruction"),
         },
     }
 }
 
+/*
+ * Finds the opcode of an instruction without fixed operands in the opcode table
+ */
+fn find_opcode(mnemonic: &str, length: u8) -> Option<u8> {
+    OPCODES
+        .iter()
+        .position(|info| info.documented && info.mnemonic == mnemonic && info.operands.is_empty() && info.length == length)
+        .map(|opcode| opcode as u8)
+}
+
+/*
+ * Converts instructions whose only operand is an immediate value or address
+ */
+fn convert_immediate_args(mnemonic: &str, args: Vec<&str>) -> Result<Vec<u8>, &'static str> {
+    if args.len() != 1 {
+        return Err("Could not match instruction");
+    }
+    if let Some(opcode) = find_opcode(mnemonic, 2) {
+        return Ok(vec![opcode, eval(args[0]) as u8]);
+    }
+    match find_opcode(mnemonic, 3) {
+        Some(opcode) => {
+            let adr = evaluate_str(args[0]);
+            Ok(vec![opcode, adr as u8, (adr >> 8) as u8])
+        }
+        None => Err("Could not match instruction"),
+    }
+}
+
 fn evaluate_str(str: &str) -> u16 {
     eval(str) as u16
 }
@@ -473,7 +373,7 @@ fn convert_rst_args(args: Vec<&str>) -> Result<Vec<u8>, &'static str> {
     Err("wrong register!")
 }
 
-fn convert_db_statement(statement: &str) -> Vec<u8> {
+pub(crate) fn convert_db_statement(statement: &str) -> Vec<u8> {
     let (_, operand) = statement.split_once("DB ").unwrap();
     let mut data_vec: Vec<u8> = Vec::new();
     if operand.contains(",") {
@@ -491,7 +391,7 @@ fn convert_db_statement(statement: &str) -> Vec<u8> {
     data_vec
 }
 
-fn convert_dw_statement(statement: &str) -> Vec<u8> {
+pub(crate) fn convert_dw_statement(statement: &str) -> Vec<u8> {
     let (_, operand) = statement.split_once("DW ").unwrap();
     let mut data_vec: Vec<u8> = Vec::new();
     if operand.contains(",") {
@@ -513,7 +413,7 @@ fn convert_dw_statement(statement: &str) -> Vec<u8> {
     data_vec
 }
 
-fn convert_ds_statement(statement: &str) -> Vec<u8> {
+pub(crate) fn convert_ds_statement(statement: &str) -> Vec<u8> {
     let (_, operand) = statement.split_once("DS ").unwrap();
     let val = eval(operand) as usize;
     let result: Vec<u8> = vec![0; val];
@@ -564,6 +464,15 @@ mod tests {
         assert_eq!(expected_text, format!("{}", assembler));
     }
 
+    #[test]
+    fn assemble_with_comments() {
+        let code = "; counts down\nstart: MVI A, 5 ; a, b\nloop: ; inner\nDCR A\nDB ';' ; c\nJNZ loop\nEND ; done";
+        let assembler = Assembler::new(code);
+
+        assert_eq!(Ok(vec![0x3e, 5, 0x3d, 0x3b, 0xc2, 2, 0]), assembler.assemble());
+        assert_eq!(Ok(vec![1, 1, 3, 5, 5, 5]), assembler.get_line_map());
+    }
+
     #[test]
     fn empty_code_file() {
         let assembler = Assembler::new("END");
@@ -824,16 +733,20 @@ mod tests {
         }
     }
 
+    fn origins_of(assembler: &Assembler) -> Vec<(u16, u16)> {
+        get_origins_of(&preprocess(&assembler.code).unwrap().0).unwrap()
+    }
+
     #[test]
     fn org_first_address() {
         let assembler = Assembler::new("RNC \n ORG 20H\nEND");
-        assert_eq!(vec![(1, 32)], assembler.get_origins());
+        assert_eq!(vec![(1, 32)], origins_of(&assembler));
 
         let assembler = Assembler::new("RNC\nEND");
-        assert_eq!(Vec::<(u16, u16)>::new(), assembler.get_origins());
+        assert_eq!(Vec::<(u16, u16)>::new(), origins_of(&assembler));
 
         let assembler = Assembler::new("ORG 5 + 1 \nRNC\nEND");
-        assert_eq!(vec![(0, 6)], assembler.get_origins());
+        assert_eq!(vec![(0, 6)], origins_of(&assembler));
     }
 
     #[test]
@@ -843,7 +756,7 @@ mod tests {
         );
         let jumps: Vec<(u16, u16)> = vec![(0, 0x1000), (6, 0x1050)];
 
-        assert_eq!(jumps, assembler.get_origins());
+        assert_eq!(jumps, origins_of(&assembler));
     }
 
     #[test]
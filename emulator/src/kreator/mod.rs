@@ -1,3 +1,5 @@
 pub mod assembler;
 pub mod parser;
 pub mod preprocessor;
+pub mod session;
+pub mod xref;
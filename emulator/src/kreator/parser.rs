@@ -18,7 +18,7 @@ pub enum UnOp {
 impl UnOp {
     fn apply(&self, arg1: i32) -> i32 {
         match self {
-            Self::Minus => -arg1,
+            Self::Minus => arg1.wrapping_neg(),
             Self::Not => !arg1
         }
     }
@@ -46,11 +46,23 @@ pub enum Op {
     Xor,
     Shr,
     Shl,
+    // comparisons and logic evaluate to 1 or 0
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
 }
 
 impl Op {
     fn precedence(&self) -> i32 {
         match self {
+            Self::LogicalOr => -5,
+            Self::LogicalAnd => -4,
+            Self::Eq | Self::Ne | Self::Lt | Self::Le | Self::Gt | Self::Ge => -3,
             Self::Or | Self::Xor => -2,
             Self::And => -1,
             Self::Add | Self::Sub => 1,
@@ -58,19 +70,30 @@ impl Op {
         }
     }
 
-    fn apply(&self, arg1: i32, arg2: i32) -> i32 {
-        match self {
-            Self::Add => arg1 + arg2,
-            Self::Sub => arg1 - arg2,
-            Self::Mul => arg1 * arg2,
-            Self::Div => arg1 / arg2,
-            Self::Mod => arg1 % arg2,
+    fn apply(&self, arg1: i32, arg2: i32) -> Result<i32, String> {
+        if arg2 == 0 && (*self == Self::Div || *self == Self::Mod) {
+            return Err(String::from("Division by zero"));
+        }
+        Ok(match self {
+            Self::Add => arg1.wrapping_add(arg2),
+            Self::Sub => arg1.wrapping_sub(arg2),
+            Self::Mul => arg1.wrapping_mul(arg2),
+            Self::Div => arg1.wrapping_div(arg2),
+            Self::Mod => arg1.wrapping_rem(arg2),
             Self::And => arg1 & arg2,
             Self::Or => arg1 | arg2,
             Self::Xor => arg1 ^ arg2,
-            Self::Shr => arg1 >> arg2,
-            Self::Shl => arg1 << arg2,
-        }
+            Self::Shr => arg1.wrapping_shr(arg2 as u32),
+            Self::Shl => arg1.wrapping_shl(arg2 as u32),
+            Self::Eq => (arg1 == arg2) as i32,
+            Self::Ne => (arg1 != arg2) as i32,
+            Self::Lt => (arg1 < arg2) as i32,
+            Self::Le => (arg1 <= arg2) as i32,
+            Self::Gt => (arg1 > arg2) as i32,
+            Self::Ge => (arg1 >= arg2) as i32,
+            Self::LogicalAnd => (arg1 != 0 && arg2 != 0) as i32,
+            Self::LogicalOr => (arg1 != 0 || arg2 != 0) as i32,
+        })
     }
 }
 
@@ -87,6 +110,14 @@ impl Display for Op {
             Self::Xor => f.write_str("XOR")?,
             Self::Shr => f.write_str("SHR")?,
             Self::Shl => f.write_str("SHL")?,
+            Self::Eq => f.write_str("==")?,
+            Self::Ne => f.write_str("!=")?,
+            Self::Lt => f.write_str("<")?,
+            Self::Le => f.write_str("<=")?,
+            Self::Gt => f.write_str(">")?,
+            Self::Ge => f.write_str(">=")?,
+            Self::LogicalAnd => f.write_str("&&")?,
+            Self::LogicalOr => f.write_str("||")?,
         };
         Ok(())
     }
@@ -98,23 +129,193 @@ pub enum Item {
     Operator(Op),
 }
 
+/*
+ * Values of names and memory for expressions evaluated with eval_with
+ */
+pub trait Context {
+    fn name(&self, name: &str) -> Option<i32>;
+
+    fn memory(&self, address: u16) -> i32;
+}
+
 pub fn eval(expression: &str) -> i32 {
     eval_tokens(Tokenizer::new(expression)).expect("")
 }
 
+/*
+ * Like eval, but returns an error instead of panicking on malformed input
+ */
+pub fn try_eval(expression: &str) -> Result<i32, String> {
+    eval_tokenizer(Tokenizer::new(expression))
+}
+
+/*
+ * Like try_eval, with names and memory. Names consist of the characters
+ * labels may use and _, and start with a letter, _, @ or ?, so hex numbers
+ * have to start with a digit. [expr] reads a little endian word
+ * from the address the expression evaluates to.
+ */
+pub fn eval_with(expression: &str, context: &dyn Context) -> Result<i32, String> {
+    eval_tokenizer(Tokenizer { context: Some(context), ..Tokenizer::new(expression) })
+}
+
+fn eval_tokenizer(mut tokenizer: Tokenizer) -> Result<i32, String> {
+    let tokens: Vec<Token> = tokenizer.by_ref().collect();
+    if let Some(error) = tokenizer.error {
+        return Err(error);
+    }
+    eval_tokens(tokens.into_iter())
+}
+
+// Characters a name may start with, the same as for labels
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || matches!(c, '_' | '@' | '?')
+}
 
 struct Tokenizer<'a> {
     chars: Peekable<Chars<'a>>,
-    previous: Option<Token>
+    previous: Option<Token>,
+    error: Option<String>,
+    context: Option<&'a dyn Context>
 }
 
 impl<'a> Tokenizer<'a> {
     fn new(input_str: &'a str) -> Self {
         Self {
-            chars: input_str.chars().peekable(), previous: None
+            chars: input_str.chars().peekable(), previous: None, error: None, context: None
+        }
+    }
+
+    fn name(&mut self, first: char, context: &dyn Context) -> Option<Token> {
+        let mut name = String::from(first);
+        while let Some(c) = self.chars.next_if(|&x| is_name_char(x) || x.is_ascii_digit()) {
+            name.push(c);
+        }
+        match name.to_ascii_uppercase().as_str() {
+            "AND" => Some(Token::Operator(Op::And)),
+            "OR" => Some(Token::Operator(Op::Or)),
+            "XOR" => Some(Token::Operator(Op::Xor)),
+            "SHL" => Some(Token::Operator(Op::Shl)),
+            "SHR" => Some(Token::Operator(Op::Shr)),
+            "MOD" => Some(Token::Operator(Op::Mod)),
+            "NOT" => Some(Token::Unary(UnOp::Not)),
+            _ => match context.name(&name) {
+                Some(value) => Some(Token::Number(value)),
+                None => self.fail(format!("Unknown name: {}", name)),
+            },
+        }
+    }
+
+    // Everything up to the matching ], evaluated and dereferenced
+    fn dereference(&mut self, context: &dyn Context) -> Option<Token> {
+        let mut depth = 1;
+        let mut inner = String::new();
+        loop {
+            let c = match self.chars.next() {
+                Some(c) => c,
+                None => return self.fail(String::from("Expected ]")),
+            };
+            match c {
+                '[' => depth += 1,
+                ']' if depth == 1 => break,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            inner.push(c);
+        }
+        match eval_with(&inner, context) {
+            Ok(address) => {
+                let low = context.memory(address as u16);
+                let high = context.memory((address as u16).wrapping_add(1));
+                Some(Token::Number(high << 8 | low))
+            }
+            Err(error) => self.fail(error),
+        }
+    }
+
+    fn operator(&mut self, c: char) -> Option<Token> {
+        match c {
+            '(' => Some(Token::Parenthesis('(')),
+            ')' => Some(Token::Parenthesis(')')),
+            '+' => Some(Token::Operator(Op::Add)),
+            '-' => {
+                match &self.previous {
+                    Some(token) => {
+                        match token {
+                            Token::Operator(_) => Some(Token::Unary(UnOp::Minus)),
+                            Token::Parenthesis('(') => Some(Token::Unary(UnOp::Minus)),
+                            _ => Some(Token::Operator(Op::Sub))
+                        }
+                    }
+                    None => Some(Token::Unary(UnOp::Minus))
+                }
+            }
+            '*' => Some(Token::Operator(Op::Mul)),
+            '/' => Some(Token::Operator(Op::Div)),
+            '=' if self.consume("=") => Some(Token::Operator(Op::Eq)),
+            '!' if self.consume("=") => Some(Token::Operator(Op::Ne)),
+            '<' if self.consume("=") => Some(Token::Operator(Op::Le)),
+            '<' => Some(Token::Operator(Op::Lt)),
+            '>' if self.consume("=") => Some(Token::Operator(Op::Ge)),
+            '>' => Some(Token::Operator(Op::Gt)),
+            '&' if self.consume("&") => Some(Token::Operator(Op::LogicalAnd)),
+            '|' if self.consume("|") => Some(Token::Operator(Op::LogicalOr)),
+            'X' if self.consume("OR") => Some(Token::Operator(Op::Xor)),
+            'A' if self.consume("ND") => Some(Token::Operator(Op::And)),
+            'O' if self.consume("R") => Some(Token::Operator(Op::Or)),
+            'S' if self.consume("H") => {
+                if self.consume("L") {
+                    Some(Token::Operator(Op::Shl))
+                } else if self.consume("R") {
+                    Some(Token::Operator(Op::Shr))
+                } else {
+                    self.fail(String::from("Expected SHL or SHR"))
+                }
+            }
+            'M' if self.consume("OD") => Some(Token::Operator(Op::Mod)),
+            'N' if self.consume("OT") => Some(Token::Unary(UnOp::Not)),
+            '0'..='9' | 'a'..='f' | 'A'..='F' => {
+                let mut num_str = String::from(c);
+                while let Some(digit) = self.chars.next_if(|&x| x.is_ascii_hexdigit()) {
+                    num_str.push(digit);
+                }
+                if let Some(post) = self.chars.peek() {
+                    match post {
+                        'H' => {
+                            self.chars.next();
+                            self.number(&num_str, 16)
+                        }
+                        'O' | 'Q' => {
+                            self.chars.next();
+                            self.number(&num_str, 8)
+                        }
+                        _ => self.number(&num_str, 10),
+                    }
+                } else {
+                    match num_str.chars().last().unwrap() {
+                        'B' => self.number(&num_str[..num_str.len()-1], 2),
+                        'D' => self.number(&num_str[..num_str.len()-1], 10),
+                        _ => self.number(&num_str, 10),
+                    }
+                }
+            }
+            c if c.is_whitespace() => self.next(),
+            c => self.fail(format!("Unexpected character: {}", c))
         }
     }
 
+    fn number(&mut self, digits: &str, radix: u32) -> Option<Token> {
+        match i32::from_str_radix(digits, radix) {
+            Ok(value) => Some(Token::Number(value)),
+            Err(_) => self.fail(format!("Invalid number: {}", digits)),
+        }
+    }
+
+    fn fail(&mut self, error: String) -> Option<Token> {
+        self.error = Some(error);
+        None
+    }
+
     fn consume(&mut self, expected: &str) -> bool {
         for c in expected.chars() {
             if self.chars.next_if(|&x| x == c).is_none() {
@@ -129,66 +330,14 @@ impl<'a> Iterator for Tokenizer<'a> {
     type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
         if let Some(c) = self.chars.next() {
-            self.previous = match c {
-                '(' => Some(Token::Parenthesis('(')),
-                ')' => Some(Token::Parenthesis(')')),
-                '+' => Some(Token::Operator(Op::Add)),
-                '-' => {
-                    match &self.previous {
-                        Some(token) => {
-                            match token {
-                                Token::Operator(_) => Some(Token::Unary(UnOp::Minus)),
-                                Token::Parenthesis('(') => Some(Token::Unary(UnOp::Minus)),
-                                _ => Some(Token::Operator(Op::Sub))
-                            }
-                        }
-                        None => Some(Token::Unary(UnOp::Minus))
-                    }
-                }
-                '*' => Some(Token::Operator(Op::Mul)),
-                '/' => Some(Token::Operator(Op::Div)),
-                'X' if self.consume("OR") => Some(Token::Operator(Op::Xor)),
-                'A' if self.consume("ND") => Some(Token::Operator(Op::And)),
-                'O' if self.consume("R") => Some(Token::Operator(Op::Or)),
-                'S' if self.consume("H") => {
-                    if self.consume("L") {
-                        Some(Token::Operator(Op::Shl))
-                    } else if self.consume("R") {
-                        Some(Token::Operator(Op::Shr))
-                    } else {
-                        panic!();
-                    }
-                }
-                'M' if self.consume("OD") => Some(Token::Operator(Op::Mod)),
-                'N' if self.consume("OT") => Some(Token::Unary(UnOp::Not)),
-                '0'..='9' | 'a'..='f' | 'A'..='F' => {
-                    let mut num_str = String::from(c);
-                    while let Some(digit) = self.chars.next_if(|&x| x.is_ascii_hexdigit()) {
-                        num_str.push(digit);
-                    }
-                    if let Some(post) = self.chars.peek() {
-                        match post {
-                            'H' => {
-                                self.chars.next();
-                                Some(Token::Number(i32::from_str_radix(&num_str, 16).unwrap()))
-                            }
-                            'O' | 'Q' => {
-                                self.chars.next();
-                                Some(Token::Number(i32::from_str_radix(&num_str, 8).unwrap()))
-                            }
-                            _ => Some(Token::Number(i32::from_str_radix(&num_str, 10).unwrap())),
-                        }
-                    } else {
-                        match num_str.chars().last().unwrap() {
-                            'B' => Some(Token::Number(i32::from_str_radix(&num_str[..num_str.len()-1], 2).unwrap())),
-                            'D' => Some(Token::Number(i32::from_str_radix(&num_str[..num_str.len()-1], 10).unwrap())),
-                            _ => Some(Token::Number(i32::from_str_radix(&num_str, 10).unwrap())),
-                        }
-                    }
-                }
-                c if c.is_whitespace() => self.next(),
-                _ => panic!()
+            self.previous = match (c, self.context) {
+                (c, Some(context)) if is_name_char(c) => self.name(c, context),
+                ('[', Some(context)) => self.dereference(context),
+                _ => self.operator(c),
             };
             self.previous
         } else {
@@ -227,7 +376,7 @@ where
                             let t1 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
                             let t2 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
                             if let Token::Operator(top) = stack.pop().unwrap() {
-                                args.push(top.apply(t1, t2));
+                                args.push(top.apply(t1, t2)?);
                             }
                         } else {
                             break;
@@ -252,7 +401,7 @@ where
                             if let Token::Operator(op) = stack.pop().unwrap() {
                                 let t2 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
                                 let t1 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
-                                args.push(op.apply(t1, t2));
+                                args.push(op.apply(t1, t2)?);
                             }
                         }
                     }
@@ -264,7 +413,7 @@ where
     // No more Tokens in input -> process the remaining operators on the stack
     while stack.len() > 0 {
         if let Token::Parenthesis(_) = stack[stack.len() - 1] {
-            panic!("Parenthesis in stack after traversing all tokens");
+            return Err(String::from("Parenthesis in stack after traversing all tokens"));
         }
         if let Token::Unary(op) = stack[stack.len() - 1] {
             let t1 = args.pop().ok_or(format!("Not enough arguments for unary operator: {}", &op))?;
@@ -273,10 +422,10 @@ where
         } else if let Token::Operator(op) = stack.pop().unwrap() {
             let t2 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
             let t1 = args.pop().ok_or(format!("Not enough arguments for operator: {}", &op))?;
-            args.push(op.apply(t1, t2));
+            args.push(op.apply(t1, t2)?);
         }
     }
-    Ok(args.pop().unwrap())
+    args.pop().ok_or(String::from("Empty expression"))
 }
 
 #[cfg(test)]
@@ -310,7 +459,9 @@ mod tests {
             ("15 MOD 5", 0),
             ("4 MOD 3", 1),
             ("8 MOD 9", 8),
-            ("NOT 9", !9)
+            ("NOT 9", !9),
+            ("(3 < 4) AND 3 < 4 OR 8", 1),
+            ("2 + 2 == 4 && 3 != 3 || 5 >= 5", 1)
         ];
         for (expr, res) in expressions {
             let tokens = Tokenizer::new(expr);
@@ -334,6 +485,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(Ok(7), try_eval("3 + 4"));
+        assert_eq!(Err(String::from("Unexpected character: x")), try_eval("3 + x"));
+        assert_eq!(Err(String::from("Invalid number: 12")), try_eval("12B"));
+        assert_eq!(Err(String::from("Expected SHL or SHR")), try_eval("1 SHX 2"));
+        assert_eq!(Err(String::from("Empty expression")), try_eval(""));
+        assert_eq!(Err(String::from("Division by zero")), try_eval("4 MOD 0"));
+    }
+
+    struct Registers;
+
+    impl Context for Registers {
+        fn name(&self, name: &str) -> Option<i32> {
+            match name {
+                "A" => Some(0),
+                "HL" => Some(0x2100),
+                "LOOP" => Some(0x100),
+                "@RET?" => Some(0x200),
+                _ => None,
+            }
+        }
+
+        fn memory(&self, address: u16) -> i32 {
+            (address & 0xff) as i32
+        }
+    }
+
+    #[test]
+    fn context() {
+        assert_eq!(Ok(1), eval_with("A == 0 && HL > 2000H", &Registers));
+        assert_eq!(Ok(0x0302), eval_with("[A + 2]", &Registers));
+        assert_eq!(Ok(1), eval_with("[[LOOP] - 2] == 0FFFEH", &Registers));
+        assert_eq!(Ok(-0x2100), eval_with("-HL", &Registers));
+        assert_eq!(Ok(1), eval_with("A and 1 OR 1", &Registers));
+        assert_eq!(Ok(0x201), eval_with("@RET? + 1", &Registers));
+        assert_eq!(Err(String::from("Unknown name: B")), eval_with("B", &Registers));
+        assert_eq!(Err(String::from("Expected ]")), eval_with("[A", &Registers));
+        // without a context names aren't known
+        assert!(try_eval("HL").is_err());
+    }
+
     #[test]
     fn tokenizer() {
         for x in 0..1000 {
@@ -1,13 +1,30 @@
-use super::assembler::{get_reserved_names, LABEL_DECL};
-use super::parser::eval;
+use super::assembler::{get_reserved_names, label_name, LABEL_DECL};
+use super::parser::{eval, try_eval};
+use crate::core::instruction::OPCODES;
 use std::collections::HashMap;
-use regex::Regex;
 
 const MACRO_START: &str = "Custom Mac";
 const MACRO_END: &str = "Custom End";
 
-pub fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static str> {
-    let decl_regex = Regex::new(LABEL_DECL).unwrap();
+/*
+ * Compiling a regex is far more expensive than matching it and the same
+ * patterns are needed for every line, so each pattern is compiled once.
+ * Only for fixed patterns, every use of the macro keeps its regex forever.
+ */
+macro_rules! cached_regex {
+    ($pattern:expr) => {{
+        static REGEX: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+        REGEX.get_or_init(|| regex::Regex::new(&$pattern).unwrap())
+    }};
+}
+pub(crate) use cached_regex;
+
+/*
+ * Returns the code with macros, conditionals, variables and labels resolved,
+ * and the addresses of all labels
+ */
+pub fn preprocess(code: &Vec<String>) -> Result<(Vec<String>, HashMap<String, u16>), &'static str> {
+    let decl_regex = cached_regex!(LABEL_DECL);
     if !has_correct_end(code) {
         return Err("A program must only contain one END statement and it has to be the last");
     }
@@ -20,6 +37,7 @@ pub fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static
     let code = replace_macros(&code)?;
     let code = replace_variable_usages(&code)?;
     let labels = get_labels(&code)?;
+    let label_values = to_string_map(&labels);
 
     for line in code {
         let mut owned_line = line.trim().to_string();
@@ -32,7 +50,7 @@ pub fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static
             owned_line = decl_regex.replace(&owned_line, "").to_string();
         }
 
-        owned_line = replace_names(&owned_line, &to_string_map(&labels));
+        owned_line = replace_names(&owned_line, &label_values);
 
         if owned_line.contains(" EQU ") || owned_line.contains(" SET ") {
             continue;
@@ -62,11 +80,13 @@ pub fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static
             }
         }
 
-        pc += get_byte_amount_of_line(&line);
+        // $ is the address of its line, counted like the addresses of labels
+        if let Some(origin) = owned_line.strip_prefix("ORG ") {
+            pc = eval_str(origin.to_string());
+        }
+        pc += get_byte_amount_of_line(&owned_line);
         if !owned_line.is_empty() {
             preprocessed_code.push(owned_line.trim().to_string());
-        } else {
-            pc -= get_byte_amount_of_line(&line);
         }
     }
 
@@ -76,7 +96,7 @@ pub fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static
 
     // remove "END" from code
     preprocessed_code.remove(preprocessed_code.len() - 1);
-    Ok(preprocessed_code)
+    Ok((preprocessed_code, labels))
 }
 
 fn to_string_map(map: &HashMap<String, u16>) -> HashMap<String, String> {
@@ -89,7 +109,7 @@ fn to_string_map(map: &HashMap<String, u16>) -> HashMap<String, String> {
 
 pub fn get_line_map(code: &Vec<String>) -> Result<HashMap<u16, usize>, &'static str> {
     let (one_byte_labels, two_byte_labels, three_byte_labels) = get_opc_by_byte_size();
-    let label_decl = Regex::new(LABEL_DECL).unwrap();
+    let label_decl = cached_regex!(LABEL_DECL);
     let code = replace_variable_usages(code)?;
     
     let mut byte_to_line_map: HashMap<u16, usize> = HashMap::new();
@@ -172,12 +192,24 @@ pub fn get_line_map(code: &Vec<String>) -> Result<HashMap<u16, usize>, &'static
 }
 
 fn replace_variable_usages(code: &Vec<String>) -> Result<Vec<String>, &'static str> {
+    Ok(evaluate_variables(code)?.0)
+}
+
+/*
+ * Value of the EQU or SET assignment on every line, None for all other lines
+ */
+pub fn get_assignments(code: &Vec<String>) -> Result<Vec<Option<u16>>, &'static str> {
+    Ok(evaluate_variables(code)?.1)
+}
+
+fn evaluate_variables(code: &Vec<String>) -> Result<(Vec<String>, Vec<Option<u16>>), &'static str> {
     let mut new_code: Vec<String> = Vec::new();
+    let mut values: Vec<Option<u16>> = Vec::new();
     let mut equ_assignments: HashMap<String, u16> = HashMap::new();
     let mut set_assignments: HashMap<String, u16> = HashMap::new();
     let mut in_conditional = false;
     let mut condition = false;
-    let name_format = Regex::new(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4})$").unwrap();
+    let name_format = cached_regex!(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4})$");
 
     for line in code {
         let mut line = line.trim().to_string();
@@ -199,9 +231,11 @@ fn replace_variable_usages(code: &Vec<String>) -> Result<Vec<String>, &'static s
             condition = eval_str(condition_str) != 0;
         } else if in_conditional && !condition {
             new_code.push(line);
+            values.push(None);
             continue;
         }
 
+        let mut value = None;
         for assignment_map in vec![&equ_assignments.clone(), &set_assignments.clone()] {
             line = replace_names(&line, &to_string_map(&assignment_map));
         }
@@ -211,7 +245,8 @@ fn replace_variable_usages(code: &Vec<String>) -> Result<Vec<String>, &'static s
             if get_reserved_names().iter().any(|&reserved_name| reserved_name == name) || !name_format.is_match(&name) {
                 return Err("Supplied illegal variable name");
             }
-            set_assignments.insert(name.to_string(), eval_str(expression.to_string()));
+            value = Some(eval_str(expression.to_string()));
+            set_assignments.insert(name.to_string(), value.unwrap());
         }
 
         if line.contains(" EQU ") {
@@ -222,16 +257,18 @@ fn replace_variable_usages(code: &Vec<String>) -> Result<Vec<String>, &'static s
             if equ_assignments.contains_key(name) {
                 return Err("Can't assign a variable more than once using EQU!");
             }
-            equ_assignments.insert(name.to_string(), eval_str(expression.to_string()));
+            value = Some(eval_str(expression.to_string()));
+            equ_assignments.insert(name.to_string(), value.unwrap());
         }
 
         new_code.push(line);
+        values.push(value);
     }
-    Ok(new_code)
+    Ok((new_code, values))
 }
 
 fn get_commentless_code(code: &Vec<String>) -> Vec<String> {
-    let comment_regex = Regex::new(r";.*").unwrap();
+    let comment_regex = cached_regex!(r";.*");
     let mut new_code = Vec::new();
 
     for line in code {
@@ -240,6 +277,21 @@ fn get_commentless_code(code: &Vec<String>) -> Vec<String> {
     new_code
 }
 
+/*
+ * The line without its comment, a ; inside a string literal doesn't start one
+ */
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '\'' => in_string = !in_string,
+            ';' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
 fn eval_str(str: String) -> u16 {
     eval(&str) as u16
 }
@@ -307,39 +359,34 @@ fn replace_names(line: &str, names: &HashMap<String, String>) -> String {
     let replacement_protection = "@";
     let mut line = line.trim().to_string();
     
-    for (variable, value) in names {
-        let var_regex = Regex::new(&format!(r"[ ,+\-*/]{}[ ,+\-*/].", variable)).unwrap();
-        let end_regex = Regex::new(&format!(r"[ ,+\-*/]{}$", variable)).unwrap();
-
-        while let Some(reg_match) = var_regex.find(&line.clone()) {
-            let first_match_symbol = line.get(reg_match.start()..reg_match.start() + 1).unwrap();
-            let last_match_symbol =  line.get(reg_match.end()..).unwrap();
-            let start = match first_match_symbol {
-                " " | "," | "+" | "-" | "*" | "/" => reg_match.start() + 1,
-                _ => reg_match.start()
-            };
-            let end = match last_match_symbol {
-                " " | "," | "+" | "-" | "*" | "/" => reg_match.end() - 2,
-                _ => reg_match.end() -1
-            };
-            line.replace_range(start..end - 1, &format!("{}{}", &value, replacement_protection));
-        }
-        if let Some(reg_match) = end_regex.find(&line.clone()) {
-            let first_symbol = line.get(reg_match.start()..reg_match.start() + 1).unwrap();
-            let start = match first_symbol {
-                " " | "," | "+" | "-" | "*" | "/" => reg_match.start() + 1,
-                _ => reg_match.start()
-            };
-            line.replace_range(start..reg_match.end(), &format!("{}{}", &value, replacement_protection));
+    for (variable, value) in names.iter().filter(|(variable, _)| !variable.is_empty()) {
+        while let Some(start) = find_name(&line, variable) {
+            line.replace_range(start..start + variable.len(), &format!("{}{}", &value, replacement_protection));
         }
     }
     line.replace(replacement_protection, "").trim().to_string()
 }
 
+/*
+ * Position of the first use of name in line. It has to follow an operator,
+ * space or comma and either end the line or be followed by one of them and
+ * something else.
+ */
+fn find_name(line: &str, name: &str) -> Option<usize> {
+    let delimiters = [b' ', b',', b'+', b'-', b'*', b'/'];
+    let bytes = line.as_bytes();
+    line.match_indices(name).map(|(start, _)| start).find(|&start| {
+        let end = start + name.len();
+        start > 0
+            && delimiters.contains(&bytes[start - 1])
+            && (end == bytes.len() || (delimiters.contains(&bytes[end]) && end + 1 < bytes.len()))
+    })
+}
+
 fn handle_macro_locals(code: &Vec<String>) -> Result<Vec<String>, &'static str> {
-    let loc_label_regex = Regex::new(LABEL_DECL).unwrap();
-    let glob_label_regex = Regex::new(&format!("{}:", LABEL_DECL)).unwrap();
-    let var_name_regex = Regex::new(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4} )").unwrap();
+    let loc_label_regex = cached_regex!(LABEL_DECL);
+    let glob_label_regex = cached_regex!(&format!("{}:", LABEL_DECL));
+    let var_name_regex = cached_regex!(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4} )");
 
     let mut generated_label_count: u32 = 0;
     let mut handled_code: Vec<String> = Vec::new();
@@ -480,7 +527,7 @@ fn generate_label_name(taken_names: &Vec<String>, generated_label_count: &mut u3
 }
 
 fn get_labels(code: &Vec<String>) -> Result<HashMap<String, u16>, &'static str> {
-    let label_regex = Regex::new(LABEL_DECL).unwrap();
+    let label_regex = cached_regex!(LABEL_DECL);
     let (one_byte_labels, two_byte_labels, three_byte_labels) = get_opc_by_byte_size();
     let mut reserved_names = vec![
         "ORG", "EQU", "SET", "END", "IF", "ENDIF", "MACRO", "ENDM", "B", "C", "D", "H", "L", "A", "SP", "PSW"
@@ -501,11 +548,8 @@ fn get_labels(code: &Vec<String>) -> Result<HashMap<String, u16>, &'static str>
             mem_address = eval(line.split_once("ORG ").unwrap().1) as u16;
         }
         if label_regex.is_match(&line) {
-            let (label_name, operand) = line.split_once(":").unwrap();
-            let mut label = label_name.trim_start().to_string();
-            while label.len() > 5 {
-                label.pop();
-            }
+            let (declaration, operand) = line.split_once(":").unwrap();
+            let label = label_name(declaration.trim_start()).to_string();
             if reserved_names.contains(&&label[..]) {
                 return Err("illegal label name");
             }
@@ -538,39 +582,55 @@ fn get_labels(code: &Vec<String>) -> Result<HashMap<String, u16>, &'static str>
     Ok(labels)
 }
 
-fn get_opc_by_byte_size() -> (Vec<&'static str>, Vec<&'static str>, Vec<&'static str>) {
-    (vec![
-        "NOP", "STAX", "INX", "INR", "DCR", "RLC", "DAD", "LDAX", "DCX", "RRC", "RAL", "RAR",
-        "DAA", "STC", "CMC", "HLT", "SBB", "MOV", "ANA", "XRA", "ORA", "CMP", "RNZ", "POP",
-        "PUSH", "RST", "RZ", "RET", "RNC", "RC", "RPO", "XTHL", "RPE", "PCHL", "XCHG", "RP",
-        "DI", "RM", "SPHL", "EI", "CMA", "ADD", "ADC", "SUB"
-    ],
-    vec![
-        "MVI", "ADI", "ACI", "OUT", "SUI", "IN", "SBI", "ANI", "XRI", "ORI", "CPI"
-    ],
-    vec![
-        "LXI", "SHLD", "LHLD", "STA", "LDA", "JNZ", "JMP", "CNZ", "JZ", "CZ", "CALL", "JNC",
-        "CNC", "JC", "CC", "JPO", "CPO", "JPE", "CPE", "JP", "CP", "JM", "CM"
-    ])
+/*
+ * Mnemonics of all documented instructions, grouped by instruction length
+ */
+pub fn get_opc_by_byte_size() -> (Vec<&'static str>, Vec<&'static str>, Vec<&'static str>) {
+    let mut sizes: [Vec<&'static str>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+    for info in OPCODES.iter().filter(|info| info.documented) {
+        let mnemonics = &mut sizes[info.length as usize - 1];
+        if !mnemonics.contains(&info.mnemonic) {
+            mnemonics.push(info.mnemonic);
+        }
+    }
+    let [one_byte, two_byte, three_byte] = sizes;
+    (one_byte, two_byte, three_byte)
 }
 
-fn get_byte_amount_of_line(line: &String) -> u16 {
-    let (one_byte_labels, two_byte_labels, three_byte_labels) = get_opc_by_byte_size();
+pub fn get_byte_amount_of_line(line: &String) -> u16 {
     if !line.trim().is_empty() {
         let opc = line.trim().split(" ").next().unwrap();
-        if one_byte_labels.contains(&opc) {
-            return 1;
-        } else if two_byte_labels.contains(&opc) {
-            return 2;
-        } else if three_byte_labels.contains(&opc) {
-            return 3;
+        if let Some(info) = OPCODES.iter().find(|info| info.documented && info.mnemonic == opc) {
+            return info.length as u16;
+        } else if opc == "DB" || opc == "DW" || opc == "DS" {
+            return get_data_size(line.trim());
         }
     }
     0
 }
 
-fn get_macros(code: &Vec<String>) -> Result<(HashMap<String, Vec<String>>, HashMap<String, Vec<String>>), &'static str> {
-    let name_regex = Regex::new(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4})").unwrap();
+/*
+ * Size of a DB, DW or DS statement, counted the same way the assembler
+ * converts them
+ */
+fn get_data_size(line: &str) -> u16 {
+    let (directive, operand) = line.split_once(' ').unwrap_or((line, ""));
+    let count = if operand.contains(',') {
+        operand.split(',').count()
+    } else if operand.contains('\'') {
+        operand.trim().replace('\'', "").chars().count()
+    } else {
+        1
+    } as u16;
+    match directive {
+        "DB" => count,
+        "DW" => count * 2,
+        _ => try_eval(operand).map_or(0, |size| size as u16),
+    }
+}
+
+pub fn get_macros(code: &Vec<String>) -> Result<(HashMap<String, Vec<String>>, HashMap<String, Vec<String>>), &'static str> {
+    let name_regex = cached_regex!(r"^( *[a-zA-Z@?][a-zA-Z@?0-9]{0,4})");
 
     let mut macros: HashMap<String, Vec<String>> = HashMap::new();
     let mut parameters: HashMap<String, Vec<String>> = HashMap::new();
@@ -652,6 +712,11 @@ fn has_correct_end(code: &Vec<String>) -> bool {
 mod tests {
     use super::*;
 
+    #[cfg(test)]
+    fn get_preprocessed_code(code: &Vec<String>) -> Result<Vec<String>, &'static str> {
+        preprocess(code).map(|(code, _)| code)
+    }
+
     #[test]
     fn remove_comments() {
         let ppc = get_commentless_code(&convert_input(vec![";comment\nMOV A, B;asdf\n;END;\nEND"]));
@@ -660,6 +725,13 @@ mod tests {
         assert_eq!(ppc, expected);
     }
 
+    #[test]
+    fn strip_comments_outside_strings() {
+        assert_eq!("MOV A, B", strip_comment("MOV A, B;asdf"));
+        assert_eq!("DB ';' ", strip_comment("DB ';' ; semicolon"));
+        assert_eq!("", strip_comment(";END;"));
+    }
+
     #[test]
     fn preprocessing_pc() {
         let code = vec!["MOV A,B", "LDA 3", "JMP $", "END"];
@@ -668,6 +740,11 @@ mod tests {
 
         let preprocessed_code = get_preprocessed_code(&convert_input(vec!["LDA 0", "MOV $, $", "END"]));
         assert_eq!(Ok(convert_input(vec!["LDA 0", "MOV 3, 3"])), preprocessed_code);
+
+        // lines with labels and ORG move the program counter like they move labels
+        let code = vec!["NOP", "loop: NOP", "JMP $", "ORG 10H", "JMP $", "END"];
+        let ppc = get_preprocessed_code(&convert_input(code));
+        assert_eq!(Ok(convert_input(vec!["NOP", "NOP", "JMP 2", "ORG 10H", "JMP 16"])), ppc);
     }
 
     #[test]
@@ -861,6 +938,19 @@ mod tests {
         assert_eq!(Ok(labels), get_labels(&code));
     }
 
+    #[test]
+    fn labels_after_data() {
+        let code = convert_input(vec!["DB 1,2,3", "a: DB 'text'", "b: DW 1,2", "c: DS 4", "d: DB 7", "e: HLT", "END"]);
+        let mut labels = HashMap::new();
+        labels.insert("a".to_string(), 3);
+        labels.insert("b".to_string(), 7);
+        labels.insert("c".to_string(), 11);
+        labels.insert("d".to_string(), 15);
+        labels.insert("e".to_string(), 16);
+
+        assert_eq!(Ok(labels), get_labels(&code));
+    }
+
     #[test]
     fn duplicate_labels() {
         let labels = get_labels(&convert_input(vec!["label:", "label:", "MOV A,B"]));
@@ -0,0 +1,707 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use super::assembler::{
+    convert_db_statement, convert_ds_statement, convert_dw_statement, label_name, to_machine_code, Assembler, LABEL_DECL,
+};
+use super::parser::try_eval;
+use super::preprocessor::{cached_regex, get_byte_amount_of_line, strip_comment};
+
+// Lines with one of these make the preprocessor rewrite other lines (macro
+// expansion, conditionals, reassignable variables), so programs using them
+// are always reassembled as a whole
+const GLOBAL_DIRECTIVES: [&str; 5] = ["MACRO", "ENDM", "IF", "ENDIF", "SET"];
+
+// Names that may appear in an operand without being a symbol
+const OPERAND_KEYWORDS: [&str; 17] = [
+    "B", "C", "D", "E", "H", "L", "M", "A", "SP", "PSW", "AND", "OR", "XOR", "NOT", "MOD", "SHL",
+    "SHR",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LineSpan {
+    address: u16,
+    len: u16,
+    // data lines don't show up in the line map
+    instruction: bool,
+}
+
+/*
+ * Address of every line that emits bytes and the values of all symbols, as
+ * retraced from the source without assembling it
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct Layout {
+    spans: Vec<Option<LineSpan>>,
+    labels: HashMap<String, u16>,
+    equates: HashMap<String, (u16, usize)>,
+    size: usize,
+}
+
+impl Layout {
+    // Same as the line map of the assembler, the line of every instruction byte
+    fn line_map(&self) -> Vec<usize> {
+        let mut line_map = Vec::new();
+        for (index, span) in self.spans.iter().enumerate() {
+            if let Some(span) = span.filter(|span| span.instruction) {
+                line_map.extend(std::iter::repeat_n(index, span.len as usize));
+            }
+        }
+        line_map
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ByteChange {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssemblyUpdate {
+    pub changes: Vec<ByteChange>,
+    pub size: usize,
+    pub line_map_changed: bool,
+    // whether the whole program had to be assembled again
+    pub reassembled: bool,
+    // lines that were assembled, the bytes of the others were only moved
+    pub encoded: usize,
+}
+
+/*
+ * Assembler that stays alive while the code is being edited
+ *
+ * After a full assembly the session remembers the address of every line and
+ * the values of all symbols. On an edit only the new lines and the lines using
+ * a symbol whose value changed are encoded, the bytes of all other lines are
+ * moved to their new address. Programs with macros, conditionals or SET are
+ * always reassembled as a whole. Either way only the bytes that differ
+ * are reported.
+ */
+#[wasm_bindgen]
+pub struct AssemblerSession {
+    lines: Vec<String>,
+    bytes: Vec<u8>,
+    line_map: Vec<usize>,
+    labels: HashMap<String, u16>,
+    // None if the program can't be assembled line by line
+    layout: Option<Layout>,
+    error: Option<&'static str>,
+}
+
+#[wasm_bindgen]
+impl AssemblerSession {
+    pub fn new(code: &str) -> Self {
+        let mut session = Self {
+            lines: Vec::new(),
+            bytes: Vec::new(),
+            line_map: Vec::new(),
+            labels: HashMap::new(),
+            layout: None,
+            error: None,
+        };
+        let lines: Vec<&str> = code.split('\n').collect();
+        let _ = session.replace_lines(0, 0, &lines);
+        session
+    }
+
+    /*
+     * Replaces `removed` lines starting at `start` with the lines of `text`,
+     * returns the AssemblyUpdate as JSON
+     */
+    pub fn edit(&mut self, start: usize, removed: usize, text: &str) -> Result<String, &'static str> {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let update = self.replace_lines(start, removed, &lines)?;
+        Ok(serde_json::to_string(&update).unwrap())
+    }
+
+    pub fn remove(&mut self, start: usize, count: usize) -> Result<String, &'static str> {
+        let update = self.replace_lines(start, count, &[])?;
+        Ok(serde_json::to_string(&update).unwrap())
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    pub fn get_linemap(&self) -> String {
+        serde_json::to_string(&self.line_map).unwrap()
+    }
+}
+
+impl AssemblerSession {
+    pub fn machine_code(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn line_map(&self) -> &[usize] {
+        &self.line_map
+    }
+
+    pub fn labels(&self) -> &HashMap<String, u16> {
+        &self.labels
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /*
+     * Error of the last assembly, the machine code is then still the one of
+     * the last successful assembly
+     */
+    pub fn error(&self) -> Option<&'static str> {
+        self.error
+    }
+
+    pub fn replace_lines(&mut self, start: usize, removed: usize, new_lines: &[&str]) -> Result<AssemblyUpdate, &'static str> {
+        if start + removed > self.lines.len() {
+            return Err("Edit is out of bounds");
+        }
+        let new_lines: Vec<String> = new_lines.iter().map(|line| line.trim().to_string()).collect();
+        let inserted = new_lines.len();
+        self.lines.splice(start..start + removed, new_lines);
+
+        match self.relocate(start, removed, inserted) {
+            Some(update) => Ok(update),
+            None => self.reassemble(),
+        }
+    }
+
+    fn reassemble(&mut self) -> Result<AssemblyUpdate, &'static str> {
+        // the layout no longer matches the lines, even if the assembly fails
+        self.layout = None;
+        let assembler = Assembler::new(&self.lines.join("\n"));
+        let (bytes, labels) = match assembler.assemble_with_labels() {
+            Ok(result) => result,
+            Err(msg) => {
+                self.error = Some(msg);
+                return Err(msg);
+            }
+        };
+        let line_map = match assembler.get_line_map() {
+            Ok(map) => map,
+            Err(msg) => {
+                self.error = Some(msg);
+                return Err(msg);
+            }
+        };
+
+        let changes = diff(&self.bytes, &bytes);
+        self.bytes = bytes;
+        self.line_map = line_map;
+        self.labels = labels;
+        self.error = None;
+        // edits are only assembled line by line if the layout agrees with the assembler
+        self.layout = layout(&self.lines).filter(|layout| {
+            layout.size == self.bytes.len() && layout.labels == self.labels && layout.line_map() == self.line_map
+        });
+        Ok(AssemblyUpdate { changes, size: self.bytes.len(), line_map_changed: true, reassembled: true, encoded: self.lines.len() })
+    }
+
+    /*
+     * Assembles the program after `removed` lines at `start` were replaced by
+     * `inserted` lines without assembling the lines that didn't change, returns
+     * None if the program has to be reassembled as a whole
+     */
+    fn relocate(&mut self, start: usize, removed: usize, inserted: usize) -> Option<AssemblyUpdate> {
+        let old = self.layout.as_ref()?;
+        let layout = layout(&self.lines)?;
+        let changed = changed_symbols(old, &layout);
+
+        let mut bytes = vec![0; layout.size];
+        let mut encoded = 0;
+        for (index, span) in layout.spans.iter().enumerate() {
+            let span = match span {
+                Some(span) => span,
+                None => continue,
+            };
+            let old_span = match index {
+                index if index < start => old.spans[index],
+                index if index < start + inserted => None,
+                index => old.spans[index - inserted + removed],
+            };
+            let line = strip_comment(&self.lines[index]);
+            let address = span.address as usize;
+            let target = &mut bytes[address..address + span.len as usize];
+            // $ changes with the address of the line
+            let moved = |old_span: &LineSpan| old_span.address != span.address && line.contains('$');
+            match old_span {
+                Some(old_span) if old_span.len == span.len && !moved(&old_span) && !uses_any(line, &changed) => {
+                    let old_address = old_span.address as usize;
+                    target.copy_from_slice(&self.bytes[old_address..old_address + span.len as usize]);
+                }
+                _ => {
+                    let line_bytes = encode(line, index, &layout)?;
+                    if line_bytes.len() != target.len() {
+                        return None;
+                    }
+                    target.copy_from_slice(&line_bytes);
+                    encoded += 1;
+                }
+            }
+        }
+
+        let changes = diff(&self.bytes, &bytes);
+        let line_map = layout.line_map();
+        let line_map_changed = line_map != self.line_map;
+        self.bytes = bytes;
+        self.line_map = line_map;
+        self.labels = layout.labels.clone();
+        self.layout = Some(layout);
+        Some(AssemblyUpdate { changes, size: self.bytes.len(), line_map_changed, reassembled: false, encoded })
+    }
+}
+
+/*
+ * Assembles a single line with the symbols of the layout, returns None if
+ * it can't be assembled on its own
+ */
+fn encode(line: &str, index: usize, layout: &Layout) -> Option<Vec<u8>> {
+    let address = layout.spans[index]?.address;
+    let line = strip_labels(strip_comment(line)).replace('$', &address.to_string());
+    let (mnemonic, operands) = line.split_once(' ').unwrap_or((&line, ""));
+    let operands = resolve(operands, index, Some(&layout.labels), &layout.equates)?;
+    let resolved = format!("{} {}", mnemonic, operands);
+    match mnemonic {
+        "DB" | "DW" | "DS" => {
+            // the conversions panic on malformed expressions
+            let expressions: Vec<&str> = if operands.contains(',') {
+                operands.split(',').collect()
+            } else if operands.contains('\'') && mnemonic != "DS" {
+                Vec::new()
+            } else {
+                vec![&operands]
+            };
+            if expressions.iter().any(|expression| try_eval(expression).is_err()) {
+                return None;
+            }
+            Some(match mnemonic {
+                "DB" => convert_db_statement(&resolved),
+                "DW" => convert_dw_statement(&resolved),
+                _ => convert_ds_statement(&resolved),
+            })
+        }
+        _ => to_machine_code(resolved.trim_end().to_string()).ok(),
+    }
+}
+
+fn strip_labels(line: &str) -> String {
+    let label_regex = cached_regex!(LABEL_DECL);
+    let mut line = line.to_string();
+    while let Some(label) = label_regex.find(&line) {
+        line = line[label.end()..].trim().to_string();
+    }
+    line
+}
+
+/*
+ * Names of the symbols used in a line, text in quotes is skipped
+ */
+fn symbols(line: &str) -> Vec<&str> {
+    let mut symbols = Vec::new();
+    let mut quoted = false;
+    let mut start = None;
+    for (position, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+        if !quoted && (c.is_ascii_alphanumeric() || c == '@' || c == '?') {
+            start.get_or_insert(position);
+            continue;
+        }
+        if let Some(start) = start.take() {
+            if !line[start..].starts_with(|first: char| first.is_ascii_digit()) {
+                symbols.push(&line[start..position]);
+            }
+        }
+        if c == '\'' {
+            quoted = !quoted;
+        }
+    }
+    symbols
+}
+
+// Declaring a label that moved doesn't change the bytes of the line
+fn uses_any(line: &str, names: &HashSet<String>) -> bool {
+    !names.is_empty() && symbols(&strip_labels(line)).iter().any(|symbol| names.contains(*symbol))
+}
+
+// Labels and equates that were added, removed or got a different value
+fn changed_symbols(old: &Layout, new: &Layout) -> HashSet<String> {
+    let mut changed = HashSet::new();
+    for (labels, others) in [(&old.labels, &new.labels), (&new.labels, &old.labels)] {
+        for (name, value) in labels {
+            if others.get(name) != Some(value) {
+                changed.insert(name.clone());
+            }
+        }
+    }
+    for (equates, others) in [(&old.equates, &new.equates), (&new.equates, &old.equates)] {
+        for (name, (value, _)) in equates {
+            if others.get(name).map(|(other, _)| other) != Some(value) {
+                changed.insert(name.clone());
+            }
+        }
+    }
+    changed
+}
+
+/*
+ * Replaces all symbols in an operand by their values the same way the
+ * preprocessor would, returns None if a name can't be resolved
+ */
+fn resolve(operands: &str, index: usize, labels: Option<&HashMap<String, u16>>, equates: &HashMap<String, (u16, usize)>) -> Option<String> {
+    let mut resolved = String::new();
+    let mut name = String::new();
+    let mut quoted = false;
+    for c in operands.chars().chain(std::iter::once(' ')) {
+        if !quoted && (c.is_ascii_alphanumeric() || c == '@' || c == '?') {
+            name.push(c);
+            continue;
+        }
+        if !name.is_empty() {
+            if name.starts_with(|first: char| first.is_ascii_digit()) {
+                resolved.push_str(&name);
+            } else if let Some((value, _)) = equates.get(&name).filter(|(_, line)| *line < index) {
+                resolved.push_str(&value.to_string());
+            } else if let Some(value) = labels.and_then(|labels| labels.get(&name)) {
+                resolved.push_str(&value.to_string());
+            } else if OPERAND_KEYWORDS.contains(&name.as_str()) {
+                resolved.push_str(&name);
+            } else {
+                return None;
+            }
+            name.clear();
+        }
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        resolved.push(c);
+    }
+    Some(resolved.trim_end().to_string())
+}
+
+/*
+ * Retraces the address of every line and the value of every symbol, returns
+ * None if the program can't be assembled line by line
+ */
+fn layout(lines: &[String]) -> Option<Layout> {
+    let label_regex = cached_regex!(LABEL_DECL);
+    let mut layout = Layout { spans: Vec::with_capacity(lines.len()), labels: HashMap::new(), equates: HashMap::new(), size: 0 };
+    // labels on their own line point to the next line
+    let mut pending: Vec<String> = Vec::new();
+    let mut address: u32 = 0;
+    let end = lines.iter().rposition(|line| !strip_comment(line).trim().is_empty())?;
+    if strip_comment(&lines[end]).trim() != "END" {
+        return None;
+    }
+
+    for (index, line) in lines[..end].iter().enumerate() {
+        let line = strip_comment(line).trim();
+        if line == "END" {
+            return None;
+        }
+        if line.split(|c: char| c.is_whitespace() || c == ',').any(|token| GLOBAL_DIRECTIVES.contains(&token)) {
+            return None;
+        }
+        layout.spans.push(None);
+        let mut owned_line = line.to_string();
+        while let Some(label) = label_regex.find(&owned_line) {
+            pending.push(label_name(label.as_str().trim().trim_end_matches(':')).to_string());
+            owned_line = owned_line[label.end()..].trim().to_string();
+        }
+        if owned_line.is_empty() {
+            continue;
+        }
+        // $ is the address of the line, EQU is evaluated before addresses are known
+        if owned_line.contains('$') {
+            if owned_line.contains(" EQU ") {
+                return None;
+            }
+            owned_line = owned_line.replace('$', &(address as u16).to_string());
+        }
+
+        if let Some((name, expression)) = owned_line.split_once(" EQU ") {
+            let value = try_eval(&resolve(expression, index, None, &layout.equates)?).ok()? as u16;
+            layout.equates.insert(name.trim().to_string(), (value, index));
+        } else if let Some(operand) = owned_line.strip_prefix("ORG ") {
+            let origin = try_eval(&resolve(operand, index, None, &layout.equates)?).ok()? as u16 as u32;
+            if origin < address {
+                return None;
+            }
+            address = origin;
+        }
+        for label in pending.drain(..) {
+            if layout.labels.insert(label, address as u16).is_some() {
+                return None;
+            }
+        }
+        if owned_line.contains(" EQU ") || owned_line.starts_with("ORG ") {
+            continue;
+        }
+
+        let (mnemonic, operand) = owned_line.split_once(' ').unwrap_or((&owned_line, ""));
+        let len = match mnemonic {
+            "DB" | "DW" => get_byte_amount_of_line(&owned_line),
+            "DS" => try_eval(&resolve(operand, index, None, &layout.equates)?).ok()? as u16,
+            _ => match get_byte_amount_of_line(&owned_line) {
+                0 => return None,
+                len => len,
+            },
+        };
+        if len > 0 {
+            let instruction = !matches!(mnemonic, "DB" | "DW" | "DS");
+            layout.spans[index] = Some(LineSpan { address: address as u16, len, instruction });
+            address += len as u32;
+            layout.size = address as usize;
+        }
+    }
+    if !pending.is_empty() {
+        return None;
+    }
+    layout.spans.resize(lines.len(), None);
+    Some(layout)
+}
+
+/*
+ * Collects the ranges in which `new` differs from `old`
+ */
+fn diff(old: &[u8], new: &[u8]) -> Vec<ByteChange> {
+    let mut changes: Vec<ByteChange> = Vec::new();
+    let mut current: Option<ByteChange> = None;
+    for (address, &byte) in new.iter().enumerate() {
+        if old.get(address) == Some(&byte) {
+            if let Some(change) = current.take() {
+                changes.push(change);
+            }
+            continue;
+        }
+        match current.as_mut() {
+            Some(change) => change.bytes.push(byte),
+            None => current = Some(ByteChange { address: address as u16, bytes: vec![byte] }),
+        }
+    }
+    if let Some(change) = current {
+        changes.push(change);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(code: &str) -> Vec<u8> {
+        Assembler::new(code).assemble().unwrap()
+    }
+
+    #[test]
+    fn initial_assembly() {
+        let code = "start: MVI A, 5\nDCR A\nJNZ start\nHLT\nEND";
+        let session = AssemblerSession::new(code);
+
+        assert_eq!(None, session.error());
+        assert_eq!(assemble(code), session.machine_code());
+        assert_eq!(Assembler::new(code).get_line_map().unwrap(), session.line_map());
+        assert_eq!(Some(&0), session.labels().get("start"));
+    }
+
+    #[test]
+    fn patch_single_line() {
+        let mut session = AssemblerSession::new("VAL EQU 7\nstart: MVI A, 5\nDCR A\nJNZ start\nHLT\nEND");
+
+        let update = session.replace_lines(2, 1, &["INR A"]).unwrap();
+        assert!(!update.line_map_changed);
+        assert_eq!(vec![ByteChange { address: 2, bytes: vec![0x3c] }], update.changes);
+
+        let update = session.replace_lines(3, 1, &["JZ start+VAL"]).unwrap();
+        assert!(!update.line_map_changed);
+        assert_eq!(vec![ByteChange { address: 3, bytes: vec![0xca, 0x07] }], update.changes);
+
+        let expected = assemble("VAL EQU 7\nstart: MVI A, 5\nINR A\nJZ start+VAL\nHLT\nEND");
+        assert_eq!(expected, session.machine_code());
+    }
+
+    #[test]
+    fn patch_after_org_and_data() {
+        let code = "ORG 10H\ndata: DB 1, 2, 3\nDB 'abc'\nORG 20H\nloop: NOP\nJMP loop\nEND";
+        let mut session = AssemblerSession::new(code);
+
+        let update = session.replace_lines(4, 2, &["loop: NOP", "JMP data"]).unwrap();
+        assert!(!update.line_map_changed);
+        assert_eq!(vec![ByteChange { address: 0x22, bytes: vec![0x10] }], update.changes);
+        assert_eq!(assemble("ORG 10H\ndata: DB 1, 2, 3\nDB 'abc'\nORG 20H\nloop: NOP\nJMP data\nEND"), session.machine_code());
+    }
+
+    #[test]
+    fn size_changes_move_the_following_lines() {
+        let mut session = AssemblerSession::new("MVI A, 5\nloop: DCR A\nJNZ loop\nHLT\nEND");
+
+        // different size moves the label and the jump to it
+        let update = session.replace_lines(0, 1, &["NOP"]).unwrap();
+        assert!(!update.reassembled);
+        assert!(update.line_map_changed);
+        let expected = assemble("NOP\nloop: DCR A\nJNZ loop\nHLT\nEND");
+        assert_eq!(expected, session.machine_code());
+        assert_eq!(Some(&1), session.labels().get("loop"));
+        assert_eq!(Assembler::new("NOP\nloop: DCR A\nJNZ loop\nHLT\nEND").get_line_map().unwrap(), session.line_map());
+
+        // inserted lines
+        let update = session.replace_lines(0, 0, &["MVI B, 1", "MVI C, 2"]).unwrap();
+        assert!(!update.reassembled);
+        let expected = assemble("MVI B, 1\nMVI C, 2\nNOP\nloop: DCR A\nJNZ loop\nHLT\nEND");
+        assert_eq!(expected, session.machine_code());
+
+        // removed lines
+        let update = session.replace_lines(0, 3, &[]).unwrap();
+        assert!(!update.reassembled);
+        assert_eq!(5, update.size);
+        assert_eq!(assemble("loop: DCR A\nJNZ loop\nHLT\nEND"), session.machine_code());
+    }
+
+    #[test]
+    fn insert_before_data_and_equates() {
+        let code = "SIZE EQU 2\nJMP main\ntable: DW main, table\nbuf: DS SIZE\nmsg: DB 'ab'\nmain: LXI H, table\nMVI C, SIZE\nHLT\nEND";
+        let mut session = AssemblerSession::new(code);
+        assert_eq!(assemble(code), session.machine_code());
+
+        let update = session.replace_lines(1, 0, &["ORG 10H", "start:", "XRA A"]).unwrap();
+        assert!(!update.reassembled);
+        let code = "SIZE EQU 2\nORG 10H\nstart:\nXRA A\nJMP main\ntable: DW main, table\nbuf: DS SIZE\nmsg: DB 'ab'\nmain: LXI H, table\nMVI C, SIZE\nHLT\nEND";
+        assert_eq!(assemble(code), session.machine_code());
+        assert_eq!(Some(&0x10), session.labels().get("start"));
+
+        // a changed equate changes the size of the reserved space
+        let update = session.replace_lines(0, 1, &["SIZE EQU 4"]).unwrap();
+        assert!(!update.reassembled);
+        assert_eq!(assemble(&code.replace("SIZE EQU 2", "SIZE EQU 4")), session.machine_code());
+    }
+
+    #[test]
+    fn errors_are_reported_by_the_full_assembly() {
+        let mut session = AssemblerSession::new("start: NOP\nJMP start\nEND");
+
+        assert_eq!(Err("label must not be assigned twice"), session.replace_lines(1, 0, &["start: HLT"]));
+        session.replace_lines(1, 1, &[]).unwrap();
+        assert!(session.replace_lines(1, 0, &["DB"]).is_err());
+    }
+
+    #[test]
+    fn macros_are_assembled_in_full() {
+        let code = "SHRT MACRO\nRRC\nENDM\nSHRT\nNOP\nEND";
+        let mut session = AssemblerSession::new(code);
+
+        let update = session.replace_lines(4, 1, &["HLT"]).unwrap();
+        assert!(update.reassembled);
+        assert_eq!(vec![ByteChange { address: 1, bytes: vec![0x76] }], update.changes);
+    }
+
+    #[test]
+    fn errors_keep_last_machine_code() {
+        let mut session = AssemblerSession::new("MVI A, 5\nHLT\nEND");
+
+        assert_eq!(Err("Could not match instruction"), session.replace_lines(1, 1, &["HALT"]));
+        assert_eq!(Some("Could not match instruction"), session.error());
+        assert_eq!(assemble("MVI A, 5\nHLT\nEND"), session.machine_code());
+
+        session.replace_lines(1, 1, &["RET"]).unwrap();
+        assert_eq!(None, session.error());
+        assert_eq!(vec![0x3e, 0x05, 0xc9], session.machine_code());
+
+        assert_eq!(Err("Edit is out of bounds"), session.replace_lines(2, 2, &[]));
+    }
+
+    fn large_program() -> Vec<String> {
+        let mut lines = Vec::new();
+        for i in 0..5000 {
+            if i % 50 == 0 {
+                lines.push(format!("L{}: LXI H, L{}", i / 50, (i / 50 + 1) % 100));
+            } else {
+                lines.push(format!("MVI A, {}", i % 256));
+            }
+        }
+        lines.push("JMP L0".to_string());
+        lines.push("END".to_string());
+        lines
+    }
+
+    #[test]
+    fn edit_large_program() {
+        let mut session = AssemblerSession::new(&large_program().join("\n"));
+
+        let update = session.replace_lines(2501, 1, &["ADI 42"]).unwrap();
+        assert!(!update.line_map_changed);
+        assert_eq!(1, update.encoded);
+        assert_eq!(vec![ByteChange { address: 50 * 101 + 3, bytes: vec![0xc6, 42] }], update.changes);
+    }
+
+    #[test]
+    fn insert_into_large_program() {
+        let mut lines = large_program();
+        let mut session = AssemblerSession::new(&lines.join("\n"));
+
+        let update = session.replace_lines(10, 0, &["INX H"]).unwrap();
+        assert!(!update.reassembled);
+        lines.insert(10, "INX H".to_string());
+        assert_eq!(assemble(&lines.join("\n")), session.machine_code());
+        assert_eq!(Assembler::new(&lines.join("\n")).get_line_map().unwrap(), session.line_map());
+    }
+
+    #[test]
+    fn insertion_only_encodes_affected_lines() {
+        let mut session = AssemblerSession::new(&large_program().join("\n"));
+
+        // the new line and the 99 lines loading a label after it
+        let update = session.replace_lines(10, 0, &["INX H"]).unwrap();
+        assert!(!update.reassembled);
+        assert_eq!(100, update.encoded);
+    }
+
+    #[test]
+    fn comments() {
+        let code = "; counts down\nstart: MVI A, 5 ; five\nloop: DCR A ; a, b\nJNZ loop\nDB ';' ; semicolon\nEND ; done";
+        let mut session = AssemblerSession::new(code);
+        assert_eq!(assemble(code), session.machine_code());
+
+        let update = session.replace_lines(1, 1, &["start: MVI A, 7 ; seven"]).unwrap();
+        assert!(!update.reassembled);
+        assert_eq!(1, update.encoded);
+        assert_eq!(vec![ByteChange { address: 1, bytes: vec![7] }], update.changes);
+
+        let update = session.replace_lines(3, 0, &["NOP ; waits"]).unwrap();
+        assert!(!update.reassembled);
+        let code = "; counts down\nstart: MVI A, 7 ; seven\nloop: DCR A ; a, b\nNOP ; waits\nJNZ loop\nDB ';' ; semicolon\nEND ; done";
+        assert_eq!(assemble(code), session.machine_code());
+    }
+
+    #[test]
+    fn program_counter() {
+        let code = "loop: DCR A\nJNZ $\nJMP $+3\nORG 10H\nJMP $\nHLT\nEND";
+        let mut session = AssemblerSession::new(code);
+        assert_eq!(assemble(code), session.machine_code());
+
+        // the new line and the lines using $ before the ORG, which move
+        let update = session.replace_lines(0, 0, &["NOP"]).unwrap();
+        assert!(!update.reassembled);
+        assert_eq!(3, update.encoded);
+        assert_eq!(assemble(&format!("NOP\n{}", code)), session.machine_code());
+    }
+
+    #[test]
+    fn directives_are_whole_words() {
+        let lines = |code: &str| -> Vec<String> { code.split('\n').map(String::from).collect() };
+        assert!(layout(&lines("SHIFT: NOP\nJMP SHIFT\nLIFE EQU 3\nEND")).is_some());
+        assert!(layout(&lines("IF 1\nNOP\nENDIF\nEND")).is_none());
+        assert!(layout(&lines("X SET 1\nEND")).is_none());
+        assert!(layout(&lines("X EQU $\nEND")).is_none());
+    }
+
+    #[test]
+    fn byte_diff() {
+        assert_eq!(Vec::<ByteChange>::new(), diff(&[1, 2, 3], &[1, 2, 3]));
+        assert_eq!(
+            vec![ByteChange { address: 1, bytes: vec![5] }, ByteChange { address: 3, bytes: vec![7, 8] }],
+            diff(&[1, 2, 3], &[1, 5, 3, 7, 8])
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use super::assembler::{label_name, Assembler, LABEL_DECL};
+use super::preprocessor::{cached_regex, get_assignments, get_macros, strip_comment};
+
+const JUMPS: [&str; 9] = ["JMP", "JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+const CALLS: [&str; 9] = ["CALL", "CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+
+// Names in operands that never refer to a symbol
+const KEYWORDS: [&str; 17] = [
+    "B", "C", "D", "E", "H", "L", "M", "A", "SP", "PSW", "AND", "OR", "XOR", "NOT", "MOD", "SHL",
+    "SHR",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    Label,
+    Equate,
+    Variable,
+    Macro,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceKind {
+    Read,
+    Jump,
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Definition {
+    pub line: usize,
+    pub value: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reference {
+    pub line: usize,
+    pub kind: ReferenceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+}
+
+/*
+ * Cross-reference listing of all labels, EQU constants, SET variables and
+ * macros of a program. Lines are counted from 0 like in the line map, only
+ * the text report counts them from 1.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CrossReference {
+    pub symbols: Vec<Symbol>,
+}
+
+impl CrossReference {
+    pub fn new(code: &str) -> Result<Self, &'static str> {
+        let (_, labels) = Assembler::new(code).assemble_with_labels()?;
+        let lines: Vec<String> = code.split('\n').map(|line| strip_comment(line).trim().to_string()).collect();
+        let (macros, parameters) = get_macros(&lines)?;
+        let values = get_assignments(&without_macro_definitions(&lines))?;
+        let label_regex = cached_regex!(LABEL_DECL);
+
+        let mut symbols: BTreeMap<String, Symbol> = BTreeMap::new();
+        let mut uses: Vec<(String, Reference)> = Vec::new();
+        let mut macro_parameters: Option<&Vec<String>> = None;
+
+        for (index, line) in lines.iter().enumerate() {
+            let mut rest = line.clone();
+            while let Some(declaration) = label_regex.find(&rest) {
+                let name = declaration.as_str().trim().trim_end_matches(':').to_string();
+                let is_global = rest[declaration.end()..].starts_with(':');
+                // labels local to a macro get a new name in every expansion
+                let value = if macro_parameters.is_none() || is_global {
+                    labels.get(label_name(&name)).copied()
+                } else {
+                    None
+                };
+                define(&mut symbols, &name, SymbolKind::Label, index, value);
+                rest = rest[declaration.end()..].trim_start_matches(':').trim().to_string();
+            }
+
+            if rest == "ENDM" {
+                macro_parameters = None;
+                continue;
+            }
+            if let Some((name, _)) = rest.split_once(" MACRO") {
+                define(&mut symbols, name.trim(), SymbolKind::Macro, index, None);
+                macro_parameters = parameters.get(name.trim());
+                continue;
+            }
+
+            let assignment = rest
+                .split_once(" EQU ")
+                .map(|(name, expression)| (name, expression, SymbolKind::Equate))
+                .or_else(|| rest.split_once(" SET ").map(|(name, expression)| (name, expression, SymbolKind::Variable)));
+            if let Some((name, expression, kind)) = assignment {
+                let value = match macro_parameters {
+                    Some(_) => None,
+                    None => values[index],
+                };
+                define(&mut symbols, name.trim(), kind, index, value);
+                for name in identifiers(expression) {
+                    uses.push((name, Reference { line: index, kind: ReferenceKind::Read }));
+                }
+                continue;
+            }
+
+            let (mnemonic, operands) = rest.split_once(' ').unwrap_or((&rest, ""));
+            let kind = if JUMPS.contains(&mnemonic) {
+                ReferenceKind::Jump
+            } else if CALLS.contains(&mnemonic) {
+                ReferenceKind::Call
+            } else {
+                ReferenceKind::Read
+            };
+            if macros.contains_key(mnemonic) {
+                uses.push((mnemonic.to_string(), Reference { line: index, kind: ReferenceKind::Call }));
+            }
+            for name in identifiers(operands) {
+                if macro_parameters.is_some_and(|parameters| parameters.contains(&name)) {
+                    continue;
+                }
+                uses.push((name, Reference { line: index, kind }));
+            }
+        }
+
+        for (name, reference) in uses {
+            if let Some(symbol) = symbols.get_mut(&name) {
+                symbol.references.push(reference);
+            }
+        }
+        Ok(Self { symbols: symbols.into_values().collect() })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl fmt::Display for CrossReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<12}{:<10}{:<7}{:<10}REFERENCES", "SYMBOL", "TYPE", "VALUE", "DEFINED")?;
+        for symbol in &self.symbols {
+            let kind = match symbol.kind {
+                SymbolKind::Label => "label",
+                SymbolKind::Equate => "equ",
+                SymbolKind::Variable => "set",
+                SymbolKind::Macro => "macro",
+            };
+            // SET variables change their value, so only constant values are listed
+            let value = match (symbol.kind, symbol.definitions.as_slice()) {
+                (SymbolKind::Label, [definition]) | (SymbolKind::Equate, [definition]) => match definition.value {
+                    Some(value) => format!("{:04X}H", value),
+                    None => String::from("-"),
+                },
+                _ => String::from("-"),
+            };
+            let definitions: Vec<String> = symbol.definitions.iter().map(|d| (d.line + 1).to_string()).collect();
+            let references: Vec<String> = symbol
+                .references
+                .iter()
+                .map(|reference| match reference.kind {
+                    ReferenceKind::Read => (reference.line + 1).to_string(),
+                    ReferenceKind::Jump => format!("{}J", reference.line + 1),
+                    ReferenceKind::Call => format!("{}C", reference.line + 1),
+                })
+                .collect();
+            writeln!(
+                f,
+                "{:<12}{:<10}{:<7}{:<10}{}",
+                symbol.name,
+                kind,
+                value,
+                definitions.join(","),
+                references.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn define(symbols: &mut BTreeMap<String, Symbol>, name: &str, kind: SymbolKind, line: usize, value: Option<u16>) {
+    let symbol = symbols.entry(name.to_string()).or_insert_with(|| Symbol {
+        name: name.to_string(),
+        kind,
+        definitions: Vec::new(),
+        references: Vec::new(),
+    });
+    symbol.definitions.push(Definition { line, value });
+}
+
+/*
+ * The lines with the definitions of macros left empty, they are only
+ * evaluated where the macros are expanded
+ */
+fn without_macro_definitions(lines: &[String]) -> Vec<String> {
+    let mut in_macro = false;
+    let mut code = Vec::with_capacity(lines.len());
+    for line in lines {
+        if line.contains(" MACRO") {
+            in_macro = true;
+        }
+        code.push(if in_macro { String::new() } else { line.clone() });
+        if line == "ENDM" {
+            in_macro = false;
+        }
+    }
+    code
+}
+
+/*
+ * All names used in an operand, ignoring numbers, registers, operators and
+ * the contents of string literals
+ */
+fn identifiers(operands: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut name = String::new();
+    let mut in_string = false;
+    for c in operands.chars().chain(std::iter::once(' ')) {
+        if c == '\'' {
+            in_string = !in_string;
+        }
+        if !in_string && (c.is_ascii_alphanumeric() || c == '@' || c == '?') {
+            name.push(c);
+            continue;
+        }
+        if !name.is_empty() && !name.starts_with(|first: char| first.is_ascii_digit()) && !KEYWORDS.contains(&name.as_str()) {
+            names.push(name.clone());
+        }
+        name.clear();
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl CrossReference {
+        fn get(&self, name: &str) -> Option<&Symbol> {
+            self.symbols.iter().find(|symbol| symbol.name == name)
+        }
+    }
+
+    const PROGRAM: &str = "PORT EQU 10H
+COUNT SET 3
+SHOUT MACRO VAL
+MVI A, VAL
+OUT PORT
+ENDM
+start: MVI B, COUNT
+loop: CALL print
+DCR B
+JNZ loop
+COUNT SET COUNT+1
+HLT
+print:
+SHOUT 78H
+RET
+END";
+
+    #[test]
+    fn symbols() {
+        let xref = CrossReference::new(PROGRAM).unwrap();
+        let names: Vec<&str> = xref.symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(vec!["COUNT", "PORT", "SHOUT", "loop", "print", "start"], names);
+
+        let port = xref.get("PORT").unwrap();
+        assert_eq!(SymbolKind::Equate, port.kind);
+        assert_eq!(vec![Definition { line: 0, value: Some(0x10) }], port.definitions);
+        assert_eq!(vec![Reference { line: 4, kind: ReferenceKind::Read }], port.references);
+
+        let count = xref.get("COUNT").unwrap();
+        assert_eq!(SymbolKind::Variable, count.kind);
+        assert_eq!(
+            vec![Definition { line: 1, value: Some(3) }, Definition { line: 10, value: Some(4) }],
+            count.definitions
+        );
+        assert_eq!(
+            vec![Reference { line: 6, kind: ReferenceKind::Read }, Reference { line: 10, kind: ReferenceKind::Read }],
+            count.references
+        );
+
+        let shout = xref.get("SHOUT").unwrap();
+        assert_eq!(SymbolKind::Macro, shout.kind);
+        assert_eq!(vec![Reference { line: 13, kind: ReferenceKind::Call }], shout.references);
+    }
+
+    #[test]
+    fn labels() {
+        let xref = CrossReference::new(PROGRAM).unwrap();
+
+        let start = xref.get("start").unwrap();
+        assert_eq!(vec![Definition { line: 6, value: Some(0) }], start.definitions);
+        assert!(start.references.is_empty());
+
+        let looped = xref.get("loop").unwrap();
+        assert_eq!(vec![Definition { line: 7, value: Some(2) }], looped.definitions);
+        assert_eq!(vec![Reference { line: 9, kind: ReferenceKind::Jump }], looped.references);
+
+        let print = xref.get("print").unwrap();
+        assert_eq!(vec![Definition { line: 12, value: Some(10) }], print.definitions);
+        assert_eq!(vec![Reference { line: 7, kind: ReferenceKind::Call }], print.references);
+    }
+
+    #[test]
+    fn report() {
+        let report = CrossReference::new(PROGRAM).unwrap().to_string();
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!("SYMBOL      TYPE      VALUE  DEFINED   REFERENCES", lines[0]);
+        assert_eq!("COUNT       set       -      2,11      7 11", lines[1]);
+        assert_eq!("PORT        equ       0010H  1         5", lines[2]);
+        assert_eq!("loop        label     0002H  8         10J", lines[4]);
+        assert_eq!("print       label     000AH  13        8C", lines[5]);
+    }
+
+    #[test]
+    fn json() {
+        let json = CrossReference::new("lab: JMP lab\nEND").unwrap().to_json();
+        assert_eq!(
+            r#"{"symbols":[{"name":"lab","kind":"label","definitions":[{"line":0,"value":0}],"references":[{"line":0,"kind":"jump"}]}]}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn values_from_the_preprocessor() {
+        let xref = CrossReference::new("A1 EQU 5\nB1 EQU 0A1H+A1\nDB ';'\nDB A1\nEND").unwrap();
+        assert_eq!(vec![Definition { line: 1, value: Some(0xa6) }], xref.get("B1").unwrap().definitions);
+        assert_eq!(vec![Reference { line: 1, kind: ReferenceKind::Read }, Reference { line: 3, kind: ReferenceKind::Read }], xref.get("A1").unwrap().references);
+    }
+
+    #[test]
+    fn long_labels() {
+        let xref = CrossReference::new("NOP\nfinish: HLT\nEND").unwrap();
+        // only the first five characters are stored
+        assert_eq!(vec![Definition { line: 1, value: Some(1) }], xref.get("finish").unwrap().definitions);
+    }
+
+    #[test]
+    fn comments() {
+        let xref = CrossReference::new("start: NOP\nloop: JMP loop ; back to start\nA1 EQU 5 ; A1\nDB ';'\nDB A1 ; loop\nEND").unwrap();
+        assert!(xref.get("start").unwrap().references.is_empty());
+        assert_eq!(vec![Reference { line: 1, kind: ReferenceKind::Jump }], xref.get("loop").unwrap().references);
+        let a1 = xref.get("A1").unwrap();
+        assert_eq!(vec![Definition { line: 2, value: Some(5) }], a1.definitions);
+        assert_eq!(vec![Reference { line: 4, kind: ReferenceKind::Read }], a1.references);
+    }
+
+    #[test]
+    fn assembly_errors() {
+        assert_eq!(Err("Could not match instruction"), CrossReference::new("FOO\nEND"));
+    }
+}
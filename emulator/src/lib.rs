@@ -3,14 +3,24 @@ mod terminator;
 mod kreator;
 mod utils;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use wasm_bindgen::prelude::*;
 use serde::{Serialize, Deserialize};
 
+use crate::core::banked_memory::BankedMemory;
 use crate::core::emulator::Emulator;
+use crate::core::memory_map::MemoryMap;
+use crate::core::ram::DefaultRam;
 use crate::kreator::assembler::Assembler;
-use crate::terminator::disassembler::Disassembler;
+use crate::kreator::xref::CrossReference;
+use crate::terminator::disassembler::{Disassembler, Recovery};
+use crate::terminator::disassembler::data::DataHeuristics;
+use crate::terminator::disassembler::flow::RESET_VECTORS;
+use crate::terminator::disassembler::graph::ControlFlowGraph;
+use crate::terminator::disassembler::style::OutputStyle;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -65,9 +75,39 @@ pub fn get_linemap(code: &str) -> JsValue {
     return JsValue::NULL;
 }
 
+#[wasm_bindgen]
+pub fn cross_reference(code: &str) -> String {
+    match CrossReference::new(code) {
+        Ok(xref) => {
+            return xref.to_json();
+        }
+        Err(msg) => {
+            log("Error while building cross-reference: ");
+            log(msg);
+        }
+    }
+
+    return "".to_string();
+}
+
+#[wasm_bindgen]
+pub fn cross_reference_report(code: &str) -> String {
+    match CrossReference::new(code) {
+        Ok(xref) => {
+            return xref.to_string();
+        }
+        Err(msg) => {
+            log("Error while building cross-reference: ");
+            log(msg);
+        }
+    }
+
+    return "".to_string();
+}
+
 #[wasm_bindgen]
 pub fn disassemble(bytes: Vec<u8>) -> String {
-    let mut disassembler = Disassembler::load_bytes(bytes);
+    let mut disassembler = Disassembler::load_bytes(bytes, 0);
     let result = disassembler.disassemble();
     
     match result {
@@ -76,16 +116,200 @@ pub fn disassemble(bytes: Vec<u8>) -> String {
         }
         Err(msg) => {
             log("Error while disassembling: ");
-            log(msg);
+            log(&msg.to_string());
         }
     }
     
     return "".to_string();
 }
 
+/*
+ * Like disassemble, but undefined opcodes are shown as the instruction the
+ * emulator executes for them instead of as data
+ */
+#[wasm_bindgen]
+pub fn disassemble_with_aliases(bytes: Vec<u8>) -> String {
+    let mut disassembler = Disassembler::load_bytes(bytes, 0);
+    disassembler.set_recovery(Recovery::Alias);
+
+    match disassembler.disassemble() {
+        Ok(code) => {
+            return code.join("\n");
+        }
+        Err(msg) => {
+            log("Error while disassembling: ");
+            log(&msg.to_string());
+        }
+    }
+
+    return "".to_string();
+}
+
+/*
+ * Disassembles only the code reachable from the entry points and emits the
+ * rest as data. Without entry points reset and the RST vectors are used.
+ */
+#[wasm_bindgen]
+pub fn disassemble_flow(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>) -> String {
+    let disassembler = Disassembler::load_bytes(bytes, base);
+    if entry_points.is_empty() {
+        return disassembler.disassemble_flow(&RESET_VECTORS).join("\n");
+    }
+    return disassembler.disassemble_flow(&entry_points).join("\n");
+}
+
+/*
+ * Disassembles into source code that assembles back into the same bytes.
+ * Without entry points the bytes are decoded linearly.
+ */
+#[wasm_bindgen]
+pub fn disassemble_source(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>) -> String {
+    let disassembler = Disassembler::load_bytes(bytes, base);
+    let map = match entry_points.is_empty() {
+        true => disassembler.sweep(),
+        false => disassembler.trace(&entry_points),
+    };
+    return disassembler.disassemble_source(&map).join("\n");
+}
+
+/*
+ * Like disassemble_source, but with the settings for recognizing strings and
+ * address tables given as JSON, e.g. {"min_text": 6, "regions": [{"start":
+ * 4096, "end": 4200, "kind": "text"}]}. Missing settings keep their default.
+ */
+#[wasm_bindgen]
+pub fn disassemble_source_with_data(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>, heuristics: &str) -> String {
+    let heuristics: DataHeuristics = match serde_json::from_str(heuristics) {
+        Ok(heuristics) => heuristics,
+        Err(msg) => {
+            log(&msg.to_string());
+            return "".to_string();
+        }
+    };
+    let mut disassembler = Disassembler::load_bytes(bytes, base);
+    disassembler.set_data_heuristics(heuristics);
+    let map = match entry_points.is_empty() {
+        true => disassembler.sweep(),
+        false => disassembler.trace(&entry_points),
+    };
+    return disassembler.disassemble_source(&map).join("\n");
+}
+
+/*
+ * Disassembles into a listing with addresses, raw bytes and optionally clock
+ * cycles. Without entry points the bytes are decoded linearly.
+ */
+#[wasm_bindgen]
+pub fn disassemble_listing(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>, cycles: bool) -> String {
+    let disassembler = Disassembler::load_bytes(bytes, base);
+    let map = match entry_points.is_empty() {
+        true => disassembler.sweep(),
+        false => disassembler.trace(&entry_points),
+    };
+    return disassembler.listing(&map, cycles).join("\n");
+}
+
+/*
+ * Disassembles into source code or a listing in the given style as JSON, e.g.
+ * {"syntax": "zilog", "hex": "dollar", "hex_case": "upper", "label_width":
+ * 8}. Missing settings keep their default, the Intel syntax.
+ */
+#[wasm_bindgen]
+pub fn disassemble_with_style(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>, listing: bool, style: &str) -> String {
+    let style: OutputStyle = match serde_json::from_str(style) {
+        Ok(style) => style,
+        Err(msg) => {
+            log(&msg.to_string());
+            return "".to_string();
+        }
+    };
+    let mut disassembler = Disassembler::load_bytes(bytes, base);
+    disassembler.set_style(style);
+    let map = match entry_points.is_empty() {
+        true => disassembler.sweep(),
+        false => disassembler.trace(&entry_points),
+    };
+    if listing {
+        return disassembler.listing(&map, false).join("\n");
+    }
+    return disassembler.disassemble_source(&map).join("\n");
+}
+
+/*
+ * Control flow graph of the code reachable from the entry points as JSON.
+ * Without entry points reset and the RST vectors are used.
+ */
+#[wasm_bindgen]
+pub fn control_flow_graph(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>) -> String {
+    return flow_graph(bytes, base, entry_points).to_json();
+}
+
+/*
+ * Same as control_flow_graph, but in the Graphviz DOT format
+ */
+#[wasm_bindgen]
+pub fn control_flow_graph_dot(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>) -> String {
+    return flow_graph(bytes, base, entry_points).to_dot();
+}
+
+fn flow_graph(bytes: Vec<u8>, base: u16, entry_points: Vec<u16>) -> ControlFlowGraph {
+    let disassembler = Disassembler::load_bytes(bytes, base);
+    let map = match entry_points.is_empty() {
+        true => disassembler.trace(&RESET_VECTORS),
+        false => disassembler.trace(&entry_points),
+    };
+    disassembler.control_flow_graph(&map)
+}
+
 #[wasm_bindgen]
 pub fn createEmulator(memory: Vec<u8>) -> Emulator {
     let mut emu = Emulator::new();
     emu.load_ram(memory, 0);
     return emu;
-}
\ No newline at end of file
+}
+
+/*
+ * Emulator with less physical memory than the address space, it is mirrored
+ * over all addresses
+ */
+#[wasm_bindgen]
+pub fn createEmulatorWithMemorySize(memory: Vec<u8>, size: usize) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.ram = Box::new(DefaultRam::with_size(size));
+    emu.load_ram(memory, 0);
+    return emu;
+}
+
+/*
+ * Emulator with the memory layout of the Space Invaders board, the ROM is
+ * loaded at 0 and can't be overwritten by the program
+ */
+#[wasm_bindgen]
+pub fn createSpaceInvaders(rom: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.ram = Box::new(MemoryMap::space_invaders());
+    emu.load_ram(rom, 0);
+    return emu;
+}
+
+/*
+ * Emulator where window_start to window_end shows one of the banks, selected
+ * by writing to the port. Reading the port returns the selected bank.
+ * The memory is loaded at 0 with bank 0 selected. Bank switches are part of
+ * save states and the journal.
+ */
+#[wasm_bindgen]
+pub fn createBankedEmulator(memory: Vec<u8>, window_start: u16, window_end: u16, banks: usize, port: u8) -> Emulator {
+    let mut emu = Emulator::new();
+    let banked = BankedMemory::new(window_start..=window_end.max(window_start), banks);
+    let selector = Rc::new(RefCell::new(banked.selector()));
+    emu.ram = Box::new(banked);
+    if emu.register_output_device(selector.clone(), port as usize).is_err()
+        || emu.register_input_device(selector.clone(), port as usize).is_err()
+        || emu.register_device_state("bank selector", selector).is_err()
+    {
+        log("Failed to register bank selector");
+    }
+    emu.load_ram(memory, 0);
+    return emu;
+}
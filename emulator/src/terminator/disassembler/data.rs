@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use super::flow::FlowMap;
+use super::Disassembler;
+
+// The preprocessor and assembler look for these anywhere in a line
+const KEYWORDS: [&str; 6] = ["IF", "ENDM", "MACRO", "EQU", "SET", "ORG"];
+
+/*
+ * How a run of data bytes is written to the source code
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataKind {
+    // DB with numbers
+    Bytes,
+    // DB with quoted strings
+    Text,
+    // DW with addresses, labels where possible
+    Words,
+}
+
+/*
+ * Data from start up to the end address that is always written as the given
+ * kind, no matter what the heuristics find. Code is never affected.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub kind: DataKind,
+}
+
+/*
+ * Settings for recognizing strings and address tables between the code
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DataHeuristics {
+    // shortest run of printable characters written as text, 0 turns it off
+    pub min_text: usize,
+    // shortest string in front of a $ as printed by CP/M, 0 turns it off
+    pub min_cpm_text: usize,
+    // fewest consecutive words pointing at instructions written as an address
+    // table, 0 turns it off
+    pub min_table: usize,
+    pub regions: Vec<Region>,
+}
+
+impl Default for DataHeuristics {
+    fn default() -> Self {
+        DataHeuristics { min_text: 4, min_cpm_text: 2, min_table: 2, regions: Vec::new() }
+    }
+}
+
+impl Disassembler {
+    pub fn set_data_heuristics(&mut self, heuristics: DataHeuristics) {
+        self.heuristics = heuristics;
+    }
+
+    /*
+     * Splits all bytes that are not code into runs of one kind. The result
+     * maps the start of every run to its end and kind.
+     */
+    pub(super) fn data_spans(&self, map: &FlowMap) -> BTreeMap<usize, (usize, DataKind)> {
+        let mut spans = BTreeMap::new();
+        let mut address = self.base;
+        while address < self.end() {
+            if map.is_code(address) {
+                address += 1;
+                continue;
+            }
+            let end = (address..self.end()).find(|&address| map.is_code(address)).unwrap_or(self.end());
+            while address < end {
+                let (length, kind) = match self.heuristics.regions.iter().find(|r| r.start <= address && address < r.end) {
+                    Some(region) => (region.end.min(end) - address, region.kind),
+                    None => {
+                        // the heuristics stop where the next region starts
+                        let limit = self.heuristics.regions.iter()
+                            .map(|region| region.start)
+                            .filter(|&start| start > address)
+                            .fold(end, usize::min);
+                        self.detect(map, address, limit)
+                    }
+                };
+                match spans.values_mut().next_back() {
+                    Some((last, DataKind::Bytes)) if *last == address && kind == DataKind::Bytes => *last += length,
+                    _ => {
+                        spans.insert(address, (address + length, kind));
+                    }
+                }
+                address += length;
+            }
+        }
+        spans
+    }
+
+    // Length and kind of the data starting at the address
+    fn detect(&self, map: &FlowMap, address: usize, limit: usize) -> (usize, DataKind) {
+        let words = self.table_length(map, address, limit);
+        if self.heuristics.min_table > 0 && words >= self.heuristics.min_table {
+            return (words * 2, DataKind::Words);
+        }
+        let text = self.text_length(address, limit);
+        if self.heuristics.min_text > 0 && text >= self.heuristics.min_text {
+            // strings the assembler would misread stay numbers
+            return match self.is_safe_text(address, address + text) {
+                true => (text, DataKind::Text),
+                false => (text, DataKind::Bytes),
+            };
+        }
+        // the $ itself becomes a number, the preprocessor replaces it inside of strings
+        let terminated = address + text < limit && self.byte(address + text) == b'$';
+        let cpm_text = self.heuristics.min_cpm_text > 0 && text >= self.heuristics.min_cpm_text;
+        if cpm_text && terminated && self.is_safe_text(address, address + text) {
+            return (text, DataKind::Text);
+        }
+        (1, DataKind::Bytes)
+    }
+
+    /*
+     * Number of consecutive little endian words that point at instructions.
+     * Zeros are padding far more often than pointers to the reset vector.
+     */
+    fn table_length(&self, map: &FlowMap, address: usize, limit: usize) -> usize {
+        (address..limit)
+            .step_by(2)
+            .take_while(|&entry| entry + 1 < limit && self.word(entry) != 0 && map.is_instruction(self.word(entry)))
+            .count()
+    }
+
+    // Number of characters that can be put between quotes, starting at the address
+    pub(super) fn text_length(&self, address: usize, limit: usize) -> usize {
+        (address..limit).take_while(|&address| is_printable(self.byte(address))).count()
+    }
+
+    pub(super) fn is_safe_text(&self, start: usize, end: usize) -> bool {
+        let text = self.text(start, end);
+        !KEYWORDS.iter().any(|keyword| text.contains(keyword))
+    }
+
+    pub(super) fn text(&self, start: usize, end: usize) -> String {
+        self.bytes[start - self.base..end - self.base].iter().map(|&byte| byte as char).collect()
+    }
+
+    pub(super) fn word(&self, address: usize) -> usize {
+        ((self.byte(address + 1) as usize) << 8) | self.byte(address) as usize
+    }
+}
+
+/*
+ * Printable ASCII without the characters that end a string, split operands,
+ * start a comment or stand for the current address. The assembler also drops
+ * @ from strings.
+ */
+fn is_printable(byte: u8) -> bool {
+    (0x20..0x7f).contains(&byte) && !b"',;:$@".contains(&byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+
+    fn source(bytes: &[u8], heuristics: DataHeuristics) -> Vec<String> {
+        let mut d = Disassembler::load_bytes(bytes.to_vec(), 0);
+        d.set_data_heuristics(heuristics);
+        let map = d.trace(&[0]);
+        let source = d.disassemble_source(&map);
+        assert_eq!(bytes.to_vec(), Assembler::new(&source.join("\n")).assemble().unwrap());
+        source
+    }
+
+    #[test]
+    fn strings() {
+        let mut bytes = vec![0x76];
+        bytes.extend(b"Hello World?");
+        bytes.extend(&[0x00, 0x41, 0x42, 0x00]);
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DB 'Hello World?'", "DB 0H,41H,42H,0H", "END"],
+            source(&bytes, DataHeuristics::default())
+        );
+    }
+
+    #[test]
+    fn cpm_strings() {
+        let mut bytes = vec![0x76];
+        bytes.extend(b"OK$NO, go$");
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DB 'OK'", "DB 24H,4eH,4fH,2cH", "DB ' go'", "DB 24H", "END"],
+            source(&bytes, DataHeuristics::default())
+        );
+        let heuristics = DataHeuristics { min_cpm_text: 0, ..DataHeuristics::default() };
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DB 4fH,4bH,24H,4eH,4fH,2cH,20H,67H", "DB 6fH,24H", "END"],
+            source(&bytes, heuristics)
+        );
+    }
+
+    #[test]
+    fn keywords_stay_numbers() {
+        let mut bytes = vec![0x76];
+        bytes.extend(b"DIFFICULT");
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DB 44H,49H,46H,46H,49H,43H,55H,4cH", "DB 54H", "END"],
+            source(&bytes, DataHeuristics::default())
+        );
+    }
+
+    #[test]
+    fn address_tables() {
+        // 0: LXI H,7, 3: PCHL, 4: HLT, 5: RET, 6: data, 7: table of 4, 5 and 3, 0dH: padding
+        let bytes = [0x21, 0x07, 0x00, 0xe9, 0x76, 0xc9, 0xff, 0x04, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00];
+        let mut d = Disassembler::load_bytes(bytes.to_vec(), 0);
+        let map = d.trace(&[0, 4, 5]);
+        let spans = d.data_spans(&map);
+        assert_eq!(Some(&(7, DataKind::Bytes)), spans.get(&6));
+        assert_eq!(Some(&(13, DataKind::Words)), spans.get(&7));
+        assert_eq!(Some(&(15, DataKind::Bytes)), spans.get(&13));
+
+        d.set_data_heuristics(DataHeuristics { min_table: 4, ..DataHeuristics::default() });
+        assert_eq!(Some(&(15, DataKind::Bytes)), d.data_spans(&map).get(&6));
+
+        d.set_data_heuristics(DataHeuristics::default());
+        let source = d.disassemble_source(&map);
+        assert_eq!(
+            vec![
+                "ORG 0H",
+                "LXI H,D0007",
+                "L0003: PCHL",
+                "L0004: HLT",
+                "L0005: RET",
+                "DB 0ffH",
+                "D0007: DW L0004,L0005,L0003",
+                "DB 0H,0H",
+                "END",
+            ],
+            source
+        );
+        assert_eq!(bytes.to_vec(), Assembler::new(&source.join("\n")).assemble().unwrap());
+    }
+
+    #[test]
+    fn regions() {
+        // the heuristics would see a string and a table of HLT
+        let mut bytes = vec![0x76];
+        bytes.extend(b"ABCD");
+        bytes.extend(&[0x00, 0x00, 0x00, 0x00]);
+        let heuristics = DataHeuristics {
+            regions: vec![
+                Region { start: 1, end: 5, kind: DataKind::Words },
+                Region { start: 5, end: 9, kind: DataKind::Bytes },
+            ],
+            ..DataHeuristics::default()
+        };
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DW 4241H,4443H", "DB 0H,0H,0H,0H", "END"],
+            source(&bytes, heuristics)
+        );
+
+        let heuristics = DataHeuristics {
+            regions: vec![Region { start: 0, end: 9, kind: DataKind::Text }],
+            ..DataHeuristics::default()
+        };
+        assert_eq!(
+            vec!["ORG 0H", "HLT", "DB 'ABCD'", "DB 0H,0H,0H,0H", "END"],
+            source(&bytes, heuristics)
+        );
+    }
+}
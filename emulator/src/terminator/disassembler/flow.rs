@@ -0,0 +1,280 @@
+use std::ops::Range;
+
+use super::Disassembler;
+use crate::core::instruction::Instruction;
+
+// Reset and the targets of RST 1 to RST 7
+pub const RESET_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+// Maximum number of bytes in one DB line
+pub(super) const DATA_PER_LINE: usize = 8;
+
+/*
+ * Result of following the control flow through a program. For every byte
+ * that starts a reachable instruction the length of the instruction is
+ * stored, all other bytes are either operands of such an instruction or data.
+ * All addresses include the base address of the program.
+ */
+pub struct FlowMap {
+    base: usize,
+    lengths: Vec<usize>,
+    code: Vec<bool>,
+}
+
+impl FlowMap {
+    fn new(base: usize, size: usize) -> Self {
+        FlowMap { base, lengths: vec![0; size], code: vec![false; size] }
+    }
+
+    fn mark(&mut self, address: usize, length: usize) {
+        let offset = address - self.base;
+        self.lengths[offset] = length;
+        for code in &mut self.code[offset..offset + length] {
+            *code = true;
+        }
+    }
+
+    pub fn addresses(&self) -> Range<usize> {
+        self.base..self.base + self.lengths.len()
+    }
+
+    pub fn is_instruction(&self, address: usize) -> bool {
+        self.instruction_length(address) > 0
+    }
+
+    pub fn is_code(&self, address: usize) -> bool {
+        address.checked_sub(self.base).and_then(|offset| self.code.get(offset)).copied().unwrap_or(false)
+    }
+
+    pub fn instruction_length(&self, address: usize) -> usize {
+        address.checked_sub(self.base).and_then(|offset| self.lengths.get(offset)).copied().unwrap_or(0)
+    }
+}
+
+impl Disassembler {
+    /*
+     * Decodes everything reachable from the entry points, following jumps,
+     * calls, branches and RST instructions. Targets outside of the program and
+     * undefined opcodes end a path, PCHL, RET and HLT end it as well.
+     */
+    pub fn trace(&self, entry_points: &[u16]) -> FlowMap {
+        let mut map = FlowMap::new(self.base, self.bytes.len());
+        let mut pending: Vec<usize> = entry_points.iter().rev().map(|&address| address as usize).collect();
+
+        while let Some(address) = pending.pop() {
+            if !map.addresses().contains(&address) || map.is_code(address) {
+                continue;
+            }
+            let instruction = match self.decode_at(address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let length = instruction.length();
+            // a jump into the middle of an instruction that was already decoded
+            if (address..address + length).any(|address| map.is_code(address)) {
+                continue;
+            }
+            map.mark(address, length);
+
+            // pushed in reverse, so the fall through is decoded first
+            for next in successors(&instruction, address + length).into_iter().rev() {
+                pending.push(next);
+            }
+        }
+        map
+    }
+
+    /*
+     * Decodes the program linearly like the iterator does, but treats
+     * undefined opcodes and cut off instructions as single data bytes
+     */
+    pub fn sweep(&self) -> FlowMap {
+        let mut map = FlowMap::new(self.base, self.bytes.len());
+        let mut address = self.base;
+        while address < self.end() {
+            match self.decode_at(address) {
+                Some(instruction) => {
+                    map.mark(address, instruction.length());
+                    address += instruction.length();
+                }
+                None => address += 1,
+            }
+        }
+        map
+    }
+
+    /*
+     * Disassembles only the reachable instructions, all other bytes are
+     * emitted as DB lines
+     */
+    pub fn disassemble_flow(&self, entry_points: &[u16]) -> Vec<String> {
+        let map = self.trace(entry_points);
+        let mut out = Vec::new();
+        let mut address = self.base;
+        while address < self.end() {
+            if map.is_instruction(address) {
+                out.push(self.style.instruction(&self.decode_at(address).unwrap()));
+                address += map.instruction_length(address);
+                continue;
+            }
+            let start = address;
+            while address < self.end() && !map.is_instruction(address) && address - start < DATA_PER_LINE {
+                address += 1;
+            }
+            out.push(self.style.text("DB", &self.data(start, address)));
+        }
+        out
+    }
+
+    /*
+     * Decodes the instruction at an address without moving the pc. Returns
+     * None for undefined opcodes and instructions cut off by the end of input.
+     */
+    pub(super) fn decode_at(&self, address: usize) -> Option<Instruction> {
+        Instruction::decode(&self.bytes[address - self.base..]).ok()
+    }
+
+    pub(super) fn byte(&self, address: usize) -> u8 {
+        self.bytes[address - self.base]
+    }
+
+    // The 16 bit operand of the instruction at the address
+    pub(super) fn operand(&self, address: usize) -> usize {
+        ((self.byte(address + 2) as usize) << 8) | self.byte(address + 1) as usize
+    }
+
+    // The comma separated bytes from start up to the end address
+    pub(super) fn data(&self, start: usize, end: usize) -> String {
+        let data: Vec<String> = self.bytes[start - self.base..end - self.base]
+            .iter()
+            .map(|&byte| self.style.hex(byte as u16, 2))
+            .collect();
+        data.join(",")
+    }
+
+    // First address after the program
+    pub(super) fn end(&self) -> usize {
+        self.base + self.bytes.len()
+    }
+}
+
+/*
+ * How an instruction passes control on
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Transfer {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    // calls and RST, execution continues after them once the subroutine returns
+    Call(usize),
+    Return,
+    ConditionalReturn,
+    // PCHL and HLT, the next address is unknown
+    Stop,
+}
+
+pub(super) fn transfer(instruction: &Instruction) -> Transfer {
+    let mnemonic = instruction.mnemonic();
+    match *instruction {
+        Instruction::Word(_, target) if mnemonic == "JMP" => Transfer::Jump(target as usize),
+        Instruction::Word(_, target) if mnemonic.starts_with('J') => Transfer::Branch(target as usize),
+        Instruction::Word(_, target) if mnemonic.starts_with('C') => Transfer::Call(target as usize),
+        Instruction::Implied(opcode) if mnemonic == "RST" => Transfer::Call((opcode & 0x38) as usize),
+        Instruction::Implied(_) if mnemonic == "RET" => Transfer::Return,
+        Instruction::Implied(opcode) if opcode & 0xc7 == 0xc0 => Transfer::ConditionalReturn,
+        Instruction::Implied(_) if mnemonic == "PCHL" || mnemonic == "HLT" => Transfer::Stop,
+        _ => Transfer::Next,
+    }
+}
+
+/*
+ * Addresses that can be executed after an instruction
+ */
+fn successors(instruction: &Instruction, next: usize) -> Vec<usize> {
+    match transfer(instruction) {
+        Transfer::Next | Transfer::ConditionalReturn => vec![next],
+        Transfer::Jump(target) => vec![target],
+        Transfer::Branch(target) | Transfer::Call(target) => vec![next, target],
+        Transfer::Return | Transfer::Stop => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn data_after_jump() {
+        // JMP 5, two bytes of data, MVI A,1, HLT
+        let mut d = Disassembler::load_bytes(vec![0xc3, 0x05, 0x00, 0x08, 0x10, 0x3e, 0x01, 0x76], 0);
+        assert_eq!(vec!["JMP 5H", "DB 8H,10H", "MVI A,1H", "HLT"], d.disassemble_flow(&[0]));
+        assert_eq!(vec!["JMP 5H", "DB 8H", "DB 10H", "MVI A,1H", "HLT"], d.disassemble().unwrap());
+    }
+
+    #[test]
+    fn branches_and_calls() {
+        // 0: JZ 8, 3: CALL 0bH, 6: RET, 7: data, 8: RST 2 (to 10H), 9: RET, 0a: data, 0b: RZ, 0c: RET
+        let bytes = vec![0xca, 0x08, 0x00, 0xcd, 0x0b, 0x00, 0xc9, 0xff, 0xd7, 0xc9, 0xff, 0xc8, 0xc9];
+        let d = Disassembler::load_bytes(bytes, 0);
+        let map = d.trace(&[0]);
+        for address in [0, 3, 6, 8, 9, 11, 12].iter() {
+            assert!(map.is_instruction(*address), "{} should be code", address);
+        }
+        assert!(map.is_code(4));
+        assert!(!map.is_instruction(4));
+        assert!(!map.is_code(7));
+        assert!(!map.is_code(10));
+        assert_eq!(3, map.instruction_length(3));
+        assert_eq!(
+            vec!["JZ 8H", "CALL 0bH", "RET", "DB 0ffH", "RST 2", "RET", "DB 0ffH", "RZ", "RET"],
+            d.disassemble_flow(&[0])
+        );
+    }
+
+    #[test]
+    fn undefined_and_truncated() {
+        // the undefined opcode is data, so is the LXI without its operand
+        let d = Disassembler::load_bytes(vec![0x00, 0x08, 0x01, 0x02], 0);
+        assert_eq!(vec!["NOP", "DB 8H,1H,2H"], d.disassemble_flow(&[0, 2]));
+    }
+
+    #[test]
+    fn overlapping_instructions() {
+        // jumping into the operand of LXI keeps the first decoding
+        let d = Disassembler::load_bytes(vec![0x01, 0x00, 0xc9, 0xc3, 0x02, 0x00], 0);
+        assert_eq!(vec!["LXI B,0c900H", "JMP 2H"], d.disassemble_flow(&[0]));
+    }
+
+    #[test]
+    fn long_data_is_split() {
+        let mut bytes = vec![0x76];
+        bytes.extend(vec![0x08; 10]);
+        let d = Disassembler::load_bytes(bytes, 0);
+        assert_eq!(
+            vec!["HLT", "DB 8H,8H,8H,8H,8H,8H,8H,8H", "DB 8H,8H"],
+            d.disassemble_flow(&[0])
+        );
+    }
+
+    #[test]
+    fn space_invaders() {
+        let mut rom = Vec::new();
+        for part in ["h", "g", "f", "e"].iter() {
+            rom.extend(fs::read(format!("../roms/invaders.{}", part)).unwrap());
+        }
+        let d = Disassembler::load_bytes(rom.clone(), 0);
+        let map = d.trace(&RESET_VECTORS);
+        let out = d.disassemble_flow(&RESET_VECTORS);
+
+        assert_eq!(vec!["NOP", "NOP", "NOP", "JMP 18d4H"], out[..4].to_vec());
+        assert!(map.is_instruction(0x18d4));
+        assert!(out.iter().any(|line| line.starts_with("DB ")));
+        // every byte appears exactly once in the output
+        let size: usize = (0..rom.len()).filter(|&a| map.is_instruction(a)).map(|a| map.instruction_length(a)).sum::<usize>()
+            + out.iter().filter(|line| line.starts_with("DB ")).map(|line| line.split(',').count()).sum::<usize>();
+        assert_eq!(rom.len(), size);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use super::flow::{transfer, FlowMap, Transfer};
+use super::Disassembler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // a conditional jump that is taken
+    Branch,
+    Call,
+    Return,
+}
+
+/*
+ * Instructions that are always executed one after another. End is the first
+ * address after the block.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub instructions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/*
+ * Basic blocks of the reachable code, edges refer to blocks by their start
+ * address. Returns lead to every place the subroutine is called from.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+}
+
+impl ControlFlowGraph {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /*
+     * Graphviz representation, blocks list their instructions left aligned
+     */
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+        for block in &self.blocks {
+            let mut label = format!("{:04X}:\\l", block.start);
+            for instruction in &block.instructions {
+                label.push_str(&format!("    {}\\l", instruction));
+            }
+            dot.push_str(&format!("    b{:04X} [label=\"{}\"];\n", block.start, label));
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Fallthrough => "",
+                EdgeKind::Jump => " [label=\"jump\"]",
+                EdgeKind::Branch => " [label=\"branch\"]",
+                EdgeKind::Call => " [label=\"call\" style=bold]",
+                EdgeKind::Return => " [label=\"return\" style=dashed]",
+            };
+            dot.push_str(&format!("    b{:04X} -> b{:04X}{};\n", edge.from, edge.to, style));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl Disassembler {
+    pub fn control_flow_graph(&self, map: &FlowMap) -> ControlFlowGraph {
+        let instructions: Vec<(usize, Transfer, String)> = map
+            .addresses()
+            .filter(|&address| map.is_instruction(address))
+            .map(|address| {
+                let instruction = self.decode_at(address).unwrap();
+                (address, transfer(&instruction), self.style.instruction(&instruction))
+            })
+            .collect();
+
+        // a block starts wherever control arrives from somewhere else than the previous instruction
+        let mut leaders = BTreeSet::new();
+        let mut falls_through_to = None;
+        for (address, transfer, _) in &instructions {
+            if falls_through_to != Some(*address) {
+                leaders.insert(*address);
+            }
+            let next = address + map.instruction_length(*address);
+            match transfer {
+                Transfer::Next => falls_through_to = Some(next),
+                Transfer::Jump(target) | Transfer::Branch(target) | Transfer::Call(target) => {
+                    leaders.insert(*target);
+                    leaders.insert(next);
+                    falls_through_to = Some(next);
+                }
+                _ => {
+                    leaders.insert(next);
+                    falls_through_to = Some(next);
+                }
+            }
+        }
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut last_transfers = Vec::new();
+        for (address, transfer, text) in instructions {
+            let end = address + map.instruction_length(address);
+            match blocks.last_mut() {
+                Some(block) if !leaders.contains(&address) => {
+                    block.end = end;
+                    block.instructions.push(text);
+                    *last_transfers.last_mut().unwrap() = transfer;
+                }
+                _ => {
+                    blocks.push(Block { start: address, end, instructions: vec![text] });
+                    last_transfers.push(transfer);
+                }
+            }
+        }
+
+        let starts: BTreeSet<usize> = blocks.iter().map(|block| block.start).collect();
+        let mut edges = Vec::new();
+        let mut add_edge = |from: usize, to: usize, kind: EdgeKind| {
+            if starts.contains(&to) {
+                edges.push(Edge { from, to, kind });
+            }
+        };
+        for (block, transfer) in blocks.iter().zip(&last_transfers) {
+            match *transfer {
+                Transfer::Next | Transfer::ConditionalReturn => add_edge(block.start, block.end, EdgeKind::Fallthrough),
+                Transfer::Jump(target) => add_edge(block.start, target, EdgeKind::Jump),
+                Transfer::Branch(target) => {
+                    add_edge(block.start, target, EdgeKind::Branch);
+                    add_edge(block.start, block.end, EdgeKind::Fallthrough);
+                }
+                Transfer::Call(target) => {
+                    add_edge(block.start, target, EdgeKind::Call);
+                    add_edge(block.start, block.end, EdgeKind::Fallthrough);
+                }
+                Transfer::Return | Transfer::Stop => {}
+            }
+        }
+
+        let returns = return_edges(&blocks, &last_transfers, &edges);
+        edges.extend(returns);
+        ControlFlowGraph { blocks, edges }
+    }
+}
+
+/*
+ * Connects the returns of every subroutine with the blocks following its
+ * calls. A subroutine consists of all blocks reachable from its start without
+ * following calls.
+ */
+fn return_edges(blocks: &[Block], last_transfers: &[Transfer], edges: &[Edge]) -> Vec<Edge> {
+    let transfers: BTreeMap<usize, Transfer> = blocks.iter().map(|block| block.start).zip(last_transfers.iter().copied()).collect();
+    let ends: BTreeMap<usize, usize> = blocks.iter().map(|block| (block.start, block.end)).collect();
+
+    let mut returns = Vec::new();
+    for call in edges.iter().filter(|edge| edge.kind == EdgeKind::Call) {
+        let return_site = ends[&call.from];
+        if !transfers.contains_key(&return_site) {
+            continue;
+        }
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![call.to];
+        while let Some(block) = pending.pop() {
+            if !visited.insert(block) {
+                continue;
+            }
+            if let Transfer::Return | Transfer::ConditionalReturn = transfers[&block] {
+                let edge = Edge { from: block, to: return_site, kind: EdgeKind::Return };
+                if !returns.contains(&edge) {
+                    returns.push(edge);
+                }
+            }
+            for edge in edges.iter().filter(|edge| edge.from == block && edge.kind != EdgeKind::Call) {
+                pending.push(edge.to);
+            }
+        }
+    }
+    returns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+    use crate::terminator::disassembler::flow::RESET_VECTORS;
+
+    use std::fs;
+
+    const PROGRAM: &str = "MVI B,3
+loop: CALL sub
+DCR B
+JNZ loop
+HLT
+sub: MOV A,B
+CPI 2
+RZ
+INR A
+RET
+END";
+
+    fn graph(code: &str) -> ControlFlowGraph {
+        let bytes = Assembler::new(code).assemble().unwrap();
+        let d = Disassembler::load_bytes(bytes, 0);
+        let map = d.trace(&[0]);
+        d.control_flow_graph(&map)
+    }
+
+    #[test]
+    fn blocks() {
+        let cfg = graph(PROGRAM);
+        let blocks: Vec<(usize, usize)> = cfg.blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(vec![(0, 2), (2, 5), (5, 9), (9, 10), (10, 14), (14, 16)], blocks);
+        assert_eq!(vec!["MOV A,B", "CPI 2H", "RZ"], cfg.blocks[4].instructions);
+    }
+
+    #[test]
+    fn edges() {
+        let cfg = graph(PROGRAM);
+        let edges: Vec<(usize, usize, EdgeKind)> = cfg.edges.iter().map(|edge| (edge.from, edge.to, edge.kind)).collect();
+        assert_eq!(
+            vec![
+                (0, 2, EdgeKind::Fallthrough),
+                (2, 10, EdgeKind::Call),
+                (2, 5, EdgeKind::Fallthrough),
+                (5, 2, EdgeKind::Branch),
+                (5, 9, EdgeKind::Fallthrough),
+                (10, 14, EdgeKind::Fallthrough),
+                (10, 5, EdgeKind::Return),
+                (14, 5, EdgeKind::Return),
+            ],
+            edges
+        );
+    }
+
+    #[test]
+    fn dot() {
+        let dot = graph("JMP end\nend: HLT\nEND").to_dot();
+        assert_eq!(
+            "digraph cfg {\n    node [shape=box fontname=monospace];\n    b0000 [label=\"0000:\\l    JMP 3H\\l\"];\n    b0003 [label=\"0003:\\l    HLT\\l\"];\n    b0000 -> b0003 [label=\"jump\"];\n}\n",
+            dot
+        );
+    }
+
+    #[test]
+    fn json() {
+        let json = graph("JMP end\nend: HLT\nEND").to_json();
+        assert_eq!(
+            r#"{"blocks":[{"start":0,"end":3,"instructions":["JMP 3H"]},{"start":3,"end":4,"instructions":["HLT"]}],"edges":[{"from":0,"to":3,"kind":"jump"}]}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn roms() {
+        let mut rom = Vec::new();
+        for part in ["h", "g", "f", "e"].iter() {
+            rom.extend(fs::read(format!("../roms/invaders.{}", part)).unwrap());
+        }
+        let d = Disassembler::load_bytes(rom, 0);
+        let map = d.trace(&RESET_VECTORS);
+        let cfg = d.control_flow_graph(&map);
+
+        let starts: BTreeSet<usize> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert!(cfg.edges.iter().all(|edge| starts.contains(&edge.from) && starts.contains(&edge.to)));
+        assert!(cfg.edges.iter().any(|edge| edge.kind == EdgeKind::Return));
+        // the blocks cover every instruction exactly once
+        let covered: usize = cfg.blocks.iter().map(|block| block.instructions.len()).sum();
+        assert_eq!(map.addresses().filter(|&address| map.is_instruction(address)).count(), covered);
+    }
+}
@@ -0,0 +1,85 @@
+use super::flow::FlowMap;
+use super::Disassembler;
+
+// Data lines are shorter than in source code, so the bytes fit into their column
+const LISTING_DATA_PER_LINE: usize = 4;
+
+impl Disassembler {
+    /*
+     * Disassembles the program as a listing where every line starts with its
+     * address and raw bytes, optionally followed by the clock cycles the
+     * instruction takes. Conditional calls and returns list the cycles taken
+     * and not taken.
+     */
+    pub fn listing(&self, map: &FlowMap, cycles: bool) -> Vec<String> {
+        let mut out = Vec::new();
+        for line in self.source_lines(map, LISTING_DATA_PER_LINE, LISTING_DATA_PER_LINE) {
+            let bytes: Vec<String> = (line.address..line.address + line.length)
+                .map(|address| format!("{:02X}", self.byte(address)))
+                .collect();
+            let mut prefix = format!("{:04X}  {:<11}  ", line.address, bytes.join(" "));
+            if cycles {
+                let count = match self.decode_at(line.address).filter(|_| map.is_instruction(line.address)) {
+                    None => String::new(),
+                    Some(instruction) if instruction.cycles() != instruction.cycles_not_taken() => {
+                        format!("{}/{}", instruction.cycles(), instruction.cycles_not_taken())
+                    }
+                    Some(instruction) => instruction.cycles().to_string(),
+                };
+                prefix.push_str(&format!("{:>5}  ", count));
+            }
+            out.push(format!("{}{}", prefix, line.text));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 100H: MVI A,2, JMP 107H, data, 107H: HLT
+    const PROGRAM: [u8; 12] = [0x3e, 0x02, 0xc3, 0x07, 0x01, 0x01, 0x02, 0x76, 0x03, 0x04, 0x05, 0x06];
+
+    #[test]
+    fn addresses_and_bytes() {
+        let d = Disassembler::load_bytes(PROGRAM.to_vec(), 0x100);
+        let map = d.trace(&[0x100]);
+        assert_eq!(
+            vec![
+                "0100  3E 02        MVI A,2H",
+                "0102  C3 07 01     JMP L0107",
+                "0105  01 02        DB 1H,2H",
+                "0107  76           L0107: HLT",
+                "0108  03 04 05 06  DB 3H,4H,5H,6H",
+            ],
+            d.listing(&map, false)
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        let d = Disassembler::load_bytes(PROGRAM.to_vec(), 0x100);
+        let map = d.trace(&[0x100]);
+        let listing = d.listing(&map, true);
+        assert_eq!("0100  3E 02            7  MVI A,2H", listing[0]);
+        assert_eq!("0102  C3 07 01        10  JMP L0107", listing[1]);
+        assert_eq!("0105  01 02               DB 1H,2H", listing[2]);
+        assert_eq!("0107  76               7  L0107: HLT", listing[3]);
+
+        let d = Disassembler::load_bytes(vec![0xc0, 0xc4, 0x34, 0x12], 0);
+        let map = d.sweep();
+        let listing = d.listing(&map, true);
+        assert_eq!("0000  C0            11/5  RNZ", listing[0]);
+        assert_eq!("0001  C4 34 12     17/11  CNZ 1234H", listing[1]);
+    }
+
+    #[test]
+    fn load_file_with_base() {
+        let d = Disassembler::load_file("../roms/invaders.e", 0x1800).unwrap();
+        let map = d.sweep();
+        let listing = d.listing(&map, false);
+        assert!(listing[0].starts_with("1800  "));
+        assert!(listing.last().unwrap().starts_with("1F"));
+    }
+}
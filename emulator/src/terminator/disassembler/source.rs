@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+
+use super::data::DataKind;
+use super::flow::{FlowMap, DATA_PER_LINE};
+use super::Disassembler;
+
+// Maximum number of characters in one quoted DB line
+const TEXT_PER_LINE: usize = 32;
+
+// One line of source code and the bytes it assembles to
+pub(super) struct SourceLine {
+    pub address: usize,
+    pub length: usize,
+    pub text: String,
+}
+
+impl Disassembler {
+    /*
+     * Disassembles the program into source code that assembles back into the
+     * same bytes. Jump targets get an L label, call targets an S label and
+     * addresses loaded by LXI, LDA, STA, LHLD and SHLD a D label. Targets
+     * outside of the program or inside of an instruction stay numbers. Data
+     * is written as strings, address tables or bytes, see DataHeuristics.
+     */
+    pub fn disassemble_source(&self, map: &FlowMap) -> Vec<String> {
+        let mut out = vec![self.style.line(None, "ORG", &self.style.hex(self.base as u16, 4))];
+        out.extend(self.source_lines(map, DATA_PER_LINE, TEXT_PER_LINE).into_iter().map(|line| line.text));
+        out.push(self.style.line(None, "END", ""));
+        out
+    }
+
+    pub(super) fn source_lines(&self, map: &FlowMap, data_per_line: usize, text_per_line: usize) -> Vec<SourceLine> {
+        let spans = self.data_spans(map);
+        let labels = self.generate_labels(map, &spans);
+        let mut lines = Vec::new();
+        let mut address = self.base;
+        while address < self.end() {
+            let declaration = labels.get(&address).map(|label| label.as_str());
+            if map.is_instruction(address) {
+                let instruction = self.decode_at(address).unwrap();
+                let operand = self.target(map, address).and_then(|target| labels.get(&target));
+                let (mnemonic, operands) = self.style.parts(&instruction, operand.map(|label| label.as_str()));
+                let length = map.instruction_length(address);
+                lines.push(SourceLine { address, length, text: self.style.line(declaration, mnemonic, &operands) });
+                address += length;
+                continue;
+            }
+            // data lines end before the next label so it can be declared
+            let (_, &(span_end, kind)) = spans.range(..=address).next_back().unwrap();
+            let limit = labels.range(address + 1..).next().map_or(span_end, |(&label, _)| label.min(span_end));
+            let (length, directive, operands) = self.data_line(&labels, address, limit, kind, data_per_line, text_per_line);
+            lines.push(SourceLine { address, length, text: self.style.line(declaration, directive, &operands) });
+            address += length;
+        }
+        lines
+    }
+
+    /*
+     * One line of data from the address up to at most the limit. Returns the
+     * number of bytes, the directive and its operands.
+     */
+    fn data_line(
+        &self,
+        labels: &BTreeMap<usize, String>,
+        address: usize,
+        limit: usize,
+        kind: DataKind,
+        data_per_line: usize,
+        text_per_line: usize,
+    ) -> (usize, &'static str, String) {
+        let text = self.text_length(address, limit).min(text_per_line);
+        match kind {
+            DataKind::Text if text > 0 && self.is_safe_text(address, address + text) => {
+                (text, "DB", format!("'{}'", self.text(address, address + text)))
+            }
+            DataKind::Words if limit - address >= 2 => {
+                let words: Vec<String> = (address..limit - 1)
+                    .step_by(2)
+                    .take(data_per_line / 2)
+                    .map(|entry| match labels.get(&self.word(entry)) {
+                        Some(label) => label.clone(),
+                        None => self.style.hex(self.word(entry) as u16, 4),
+                    })
+                    .collect();
+                (words.len() * 2, "DW", words.join(","))
+            }
+            _ => {
+                // numbers in a text region end where the next string starts
+                let end = match kind {
+                    DataKind::Text if text == 0 => {
+                        (address + 1..limit).find(|&address| self.text_length(address, limit) > 0).unwrap_or(limit)
+                    }
+                    _ => limit,
+                };
+                let end = end.min(address + data_per_line);
+                (end - address, "DB", self.data(address, end))
+            }
+        }
+    }
+
+    fn generate_labels(&self, map: &FlowMap, spans: &BTreeMap<usize, (usize, DataKind)>) -> BTreeMap<usize, String> {
+        let mut labels = BTreeMap::new();
+        let instructions = map.addresses().filter(|&address| map.is_instruction(address));
+        let mut targets: Vec<(usize, char)> = instructions
+            .filter_map(|address| {
+                let prefix = match self.byte(address) & 0xc7 {
+                    0xc4 | 0xc5 => 'S',
+                    0xc2 | 0xc3 => 'L',
+                    _ => 'D',
+                };
+                self.target(map, address).map(|target| (target, prefix))
+            })
+            .collect();
+        // entries of address tables are jump targets
+        for (&start, &(end, _)) in spans.iter().filter(|(_, &(_, kind))| kind == DataKind::Words) {
+            for entry in (start..end - 1).step_by(2) {
+                let target = self.word(entry);
+                if can_label(map, target) {
+                    targets.push((target, 'L'));
+                }
+            }
+        }
+        for (target, prefix) in targets {
+            let label = format!("{}{:04X}", prefix, target);
+            // S > L > D, so subroutines win over jump targets and those over data
+            let current = labels.entry(target).or_insert_with(String::new);
+            if *current < label {
+                *current = label;
+            }
+        }
+        labels
+    }
+
+    /*
+     * The address an instruction refers to, if a label can be declared there
+     */
+    fn target(&self, map: &FlowMap, address: usize) -> Option<usize> {
+        if map.instruction_length(address) != 3 {
+            return None;
+        }
+        let target = self.operand(address);
+        Some(target).filter(|&target| can_label(map, target))
+    }
+}
+
+// Labels are declared in front of a line, so not outside of the program or inside of an instruction
+fn can_label(map: &FlowMap, address: usize) -> bool {
+    map.addresses().contains(&address) && (map.is_instruction(address) || !map.is_code(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreator::assembler::Assembler;
+    use crate::terminator::disassembler::flow::RESET_VECTORS;
+
+    use std::fs;
+    use std::io::{self, BufRead};
+
+    fn reassemble(source: &[String]) -> Vec<u8> {
+        Assembler::new(&source.join("\n")).assemble().unwrap()
+    }
+
+    #[test]
+    fn labels() {
+        // 0: CALL 7, 3: JNZ 0, 6: HLT, 7: LXI H,0bH, 0a: RET, 0b: data
+        let bytes = vec![0xcd, 0x07, 0x00, 0xc2, 0x00, 0x00, 0x76, 0x21, 0x0b, 0x00, 0xc9, 0x01, 0x02];
+        let d = Disassembler::load_bytes(bytes.clone(), 0);
+        let map = d.trace(&[0]);
+        let source = d.disassemble_source(&map);
+        assert_eq!(
+            vec!["ORG 0H", "L0000: CALL S0007", "JNZ L0000", "HLT", "S0007: LXI H,D000B", "RET", "D000B: DB 1H,2H", "END"],
+            source
+        );
+        assert_eq!(bytes, reassemble(&source));
+    }
+
+    #[test]
+    fn label_priority() {
+        // the subroutine at 6 is also jumped to and loaded
+        let bytes = vec![0x21, 0x06, 0x00, 0xc3, 0x06, 0x00, 0xcd, 0x06, 0x00];
+        let d = Disassembler::load_bytes(bytes.clone(), 0);
+        let map = d.trace(&[0]);
+        let source = d.disassemble_source(&map);
+        assert_eq!(vec!["ORG 0H", "LXI H,S0006", "JMP S0006", "S0006: CALL S0006", "END"], source);
+        assert_eq!(bytes, reassemble(&source));
+    }
+
+    #[test]
+    fn targets_without_labels() {
+        // the JMP points into its own operand, LDA outside of the program
+        let bytes = vec![0xc3, 0x01, 0x00, 0x3a, 0x00, 0x20];
+        let d = Disassembler::load_bytes(bytes.clone(), 0);
+        let map = d.sweep();
+        let source = d.disassemble_source(&map);
+        assert_eq!(vec!["ORG 0H", "JMP 1H", "LDA 2000H", "END"], source);
+        assert_eq!(bytes, reassemble(&source));
+    }
+
+    #[test]
+    fn base_address() {
+        // 100H: JMP 105H, data, 105H: CALL 100H
+        let bytes = vec![0xc3, 0x05, 0x01, 0x41, 0x42, 0xcd, 0x00, 0x01];
+        let d = Disassembler::load_bytes(bytes.clone(), 0x100);
+        let map = d.trace(&[0x100]);
+        let source = d.disassemble_source(&map);
+        assert_eq!(vec!["ORG 100H", "S0100: JMP L0105", "DB 41H,42H", "L0105: CALL S0100", "END"], source);
+        assert_eq!(bytes, reassemble(&source)[0x100..].to_vec());
+    }
+
+    #[test]
+    fn all_opcodes_round_trip() -> io::Result<()> {
+        let f = fs::File::open("./test_data/test_input")?;
+        let mut bytes = Vec::new();
+        for line in io::BufReader::new(f).lines() {
+            let line = line?;
+            let data = line.split(':').next().unwrap();
+            bytes.extend(data.split(',').map(|byte| byte.parse::<u8>().unwrap()));
+        }
+        assert!((0..=255).all(|opcode| bytes.contains(&opcode)));
+
+        let d = Disassembler::load_bytes(bytes.clone(), 0);
+        let map = d.sweep();
+        assert_eq!(bytes, reassemble(&d.disassemble_source(&map)));
+        Ok(())
+    }
+
+    #[test]
+    fn roms_round_trip() {
+        let mut rom = Vec::new();
+        for part in ["h", "g", "f", "e"].iter() {
+            rom.extend(fs::read(format!("../roms/invaders.{}", part)).unwrap());
+        }
+        let d = Disassembler::load_bytes(rom.clone(), 0);
+        let map = d.trace(&RESET_VECTORS);
+        let source = d.disassemble_source(&map);
+        assert!(source.iter().any(|line| line.starts_with("S")));
+        assert_eq!(rom, reassemble(&source));
+    }
+}
@@ -0,0 +1,324 @@
+use serde::Deserialize;
+
+use super::Disassembler;
+use crate::core::instruction::Instruction;
+
+// Conditions of Jcc, Ccc and Rcc in the order of bits 3 to 5 of the opcode
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+
+// Stands in for the immediate value while the case of the operands is changed
+const VALUE: &str = "\u{0}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Syntax {
+    // MOV A,M
+    Intel,
+    // LD A,(HL)
+    Zilog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HexStyle {
+    // 0abcdH, as few digits as possible
+    Intel,
+    // $abcd, always 2 or 4 digits
+    Dollar,
+    // 0xabcd, always 2 or 4 digits
+    C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Case {
+    Upper,
+    Lower,
+}
+
+/*
+ * How instructions and data are written. The default is the Intel syntax the
+ * assembler reads.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OutputStyle {
+    pub syntax: Syntax,
+    pub hex: HexStyle,
+    // case of mnemonics, registers, directives and the H suffix
+    pub case: Case,
+    // case of hex digits
+    pub hex_case: Case,
+    // column of the mnemonic, 0 puts it one space after the label
+    pub label_width: usize,
+    // column of the operands counted from the mnemonic, 0 puts them one space after it
+    pub mnemonic_width: usize,
+}
+
+impl Default for OutputStyle {
+    fn default() -> Self {
+        OutputStyle {
+            syntax: Syntax::Intel,
+            hex: HexStyle::Intel,
+            case: Case::Upper,
+            hex_case: Case::Lower,
+            label_width: 0,
+            mnemonic_width: 0,
+        }
+    }
+}
+
+impl OutputStyle {
+    // A number with the given number of digits for styles that don't drop leading zeros
+    pub fn hex(&self, value: u16, digits: usize) -> String {
+        let number = match self.hex {
+            HexStyle::Intel => format!("{:x}", value),
+            _ => format!("{:01$x}", value, digits),
+        };
+        let number = apply_case(self.hex_case, &number);
+        match self.hex {
+            HexStyle::Intel if number.starts_with(|c: char| c.is_ascii_alphabetic()) => format!("0{}{}", number, self.case("H")),
+            HexStyle::Intel => format!("{}{}", number, self.case("H")),
+            HexStyle::Dollar => format!("${}", number),
+            HexStyle::C => format!("0x{}", number),
+        }
+    }
+
+    pub fn instruction(&self, instruction: &Instruction) -> String {
+        let (mnemonic, operands) = self.parts(instruction, None);
+        self.text(mnemonic, &operands)
+    }
+
+    /*
+     * The mnemonic and operands of an instruction, the immediate value is
+     * replaced by the operand if one is given
+     */
+    pub(super) fn parts(&self, instruction: &Instruction, operand: Option<&str>) -> (&'static str, String) {
+        let (mnemonic, template) = match self.syntax {
+            Syntax::Intel => {
+                let operands: Vec<&str> = vec![instruction.info().operands]
+                    .into_iter()
+                    .chain(instruction.immediate().map(|_| VALUE))
+                    .filter(|operand| !operand.is_empty())
+                    .collect();
+                (instruction.mnemonic(), operands.join(","))
+            }
+            Syntax::Zilog => self.zilog(instruction),
+        };
+        let value = match (operand, *instruction) {
+            (Some(operand), _) => operand.to_string(),
+            (None, Instruction::Byte(_, value)) => self.hex(value as u16, 2),
+            (None, Instruction::Word(_, value)) => self.hex(value, 4),
+            // the restart address instead of its number, only Zilog shows it
+            (None, Instruction::Implied(opcode)) if instruction.mnemonic() == "RST" => self.hex((opcode & 0x38) as u16, 2),
+            (None, Instruction::Implied(_)) => String::new(),
+        };
+        (mnemonic, self.case(&template).replace(VALUE, &value))
+    }
+
+    // Mnemonic and operands in their columns
+    pub fn text(&self, mnemonic: &str, operands: &str) -> String {
+        match operands.is_empty() {
+            true => self.case(mnemonic),
+            false => format!("{}{}", column(self.case(mnemonic), self.mnemonic_width), operands),
+        }
+    }
+
+    // A whole line with an optional label declaration in front
+    pub fn line(&self, label: Option<&str>, mnemonic: &str, operands: &str) -> String {
+        let label = label.map_or(String::new(), |label| format!("{}:", label));
+        format!("{}{}", column(label, self.label_width), self.text(mnemonic, operands))
+    }
+
+    fn case(&self, text: &str) -> String {
+        apply_case(self.case, text)
+    }
+
+    fn zilog(&self, instruction: &Instruction) -> (&'static str, String) {
+        let operands = instruction.info().operands;
+        let opcode = instruction.opcode();
+        let condition = CONDITIONS[(opcode >> 3 & 7) as usize];
+        match (opcode & 0xc7, instruction.mnemonic()) {
+            (0xc2, _) => ("JP", format!("{},{}", condition, VALUE)),
+            (0xc4, _) => ("CALL", format!("{},{}", condition, VALUE)),
+            (0xc0, _) => ("RET", condition.to_string()),
+            (_, "MOV") => {
+                let (destination, source) = operands.split_once(',').unwrap();
+                ("LD", format!("{},{}", register(destination), register(source)))
+            }
+            (_, "MVI") => ("LD", format!("{},{}", register(operands), VALUE)),
+            (_, "LXI") => ("LD", format!("{},{}", pair(operands), VALUE)),
+            (_, "LDA") => ("LD", format!("A,({})", VALUE)),
+            (_, "STA") => ("LD", format!("({}),A", VALUE)),
+            (_, "LHLD") => ("LD", format!("HL,({})", VALUE)),
+            (_, "SHLD") => ("LD", format!("({}),HL", VALUE)),
+            (_, "LDAX") => ("LD", format!("A,({})", pair(operands))),
+            (_, "STAX") => ("LD", format!("({}),A", pair(operands))),
+            (_, "XCHG") => ("EX", String::from("DE,HL")),
+            (_, "XTHL") => ("EX", String::from("(SP),HL")),
+            (_, "SPHL") => ("LD", String::from("SP,HL")),
+            (_, "PCHL") => ("JP", String::from("(HL)")),
+            (_, "ADD") => ("ADD", format!("A,{}", register(operands))),
+            (_, "ADC") => ("ADC", format!("A,{}", register(operands))),
+            (_, "SUB") => ("SUB", register(operands).to_string()),
+            (_, "SBB") => ("SBC", format!("A,{}", register(operands))),
+            (_, "ANA") => ("AND", register(operands).to_string()),
+            (_, "XRA") => ("XOR", register(operands).to_string()),
+            (_, "ORA") => ("OR", register(operands).to_string()),
+            (_, "CMP") => ("CP", register(operands).to_string()),
+            (_, "ADI") => ("ADD", format!("A,{}", VALUE)),
+            (_, "ACI") => ("ADC", format!("A,{}", VALUE)),
+            (_, "SUI") => ("SUB", VALUE.to_string()),
+            (_, "SBI") => ("SBC", format!("A,{}", VALUE)),
+            (_, "ANI") => ("AND", VALUE.to_string()),
+            (_, "XRI") => ("XOR", VALUE.to_string()),
+            (_, "ORI") => ("OR", VALUE.to_string()),
+            (_, "CPI") => ("CP", VALUE.to_string()),
+            (_, "INR") => ("INC", register(operands).to_string()),
+            (_, "DCR") => ("DEC", register(operands).to_string()),
+            (_, "INX") => ("INC", pair(operands).to_string()),
+            (_, "DCX") => ("DEC", pair(operands).to_string()),
+            (_, "DAD") => ("ADD", format!("HL,{}", pair(operands))),
+            (_, "PUSH") => ("PUSH", pair(operands).to_string()),
+            (_, "POP") => ("POP", pair(operands).to_string()),
+            (_, "CMA") => ("CPL", String::new()),
+            (_, "STC") => ("SCF", String::new()),
+            (_, "CMC") => ("CCF", String::new()),
+            (_, "RLC") => ("RLCA", String::new()),
+            (_, "RRC") => ("RRCA", String::new()),
+            (_, "RAL") => ("RLA", String::new()),
+            (_, "RAR") => ("RRA", String::new()),
+            (_, "HLT") => ("HALT", String::new()),
+            (_, "JMP") => ("JP", VALUE.to_string()),
+            (_, "CALL") => ("CALL", VALUE.to_string()),
+            (_, "RST") => ("RST", VALUE.to_string()),
+            (_, "IN") => ("IN", format!("A,({})", VALUE)),
+            (_, "OUT") => ("OUT", format!("({}),A", VALUE)),
+            // NOP, RET, DAA, EI and DI are the same
+            (_, mnemonic) => (mnemonic, String::new()),
+        }
+    }
+}
+
+impl Disassembler {
+    pub fn set_style(&mut self, style: OutputStyle) {
+        self.style = style;
+    }
+}
+
+fn register(register: &str) -> &str {
+    match register {
+        "M" => "(HL)",
+        register => register,
+    }
+}
+
+fn pair(pair: &str) -> &str {
+    match pair {
+        "B" => "BC",
+        "D" => "DE",
+        "H" => "HL",
+        "PSW" => "AF",
+        pair => pair,
+    }
+}
+
+fn apply_case(case: Case, text: &str) -> String {
+    match case {
+        Case::Upper => text.to_uppercase(),
+        Case::Lower => text.to_lowercase(),
+    }
+}
+
+// Pads the text to the width, text that doesn't fit is followed by one space
+fn column(text: String, width: usize) -> String {
+    match text.len() < width {
+        true => format!("{:1$}", text, width),
+        false if text.is_empty() => text,
+        false => format!("{} ", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(syntax: Syntax, hex: HexStyle, case: Case, hex_case: Case) -> OutputStyle {
+        OutputStyle { syntax, hex, case, hex_case, ..OutputStyle::default() }
+    }
+
+    #[test]
+    fn hex_styles() {
+        let intel = OutputStyle::default();
+        assert_eq!("0abcdH", intel.hex(0xabcd, 4));
+        assert_eq!("5H", intel.hex(5, 2));
+        assert_eq!("0ABCDH", style(Syntax::Intel, HexStyle::Intel, Case::Upper, Case::Upper).hex(0xabcd, 4));
+        assert_eq!("0abcdh", style(Syntax::Intel, HexStyle::Intel, Case::Lower, Case::Lower).hex(0xabcd, 4));
+        assert_eq!("$ABCD", style(Syntax::Intel, HexStyle::Dollar, Case::Upper, Case::Upper).hex(0xabcd, 4));
+        assert_eq!("$05", style(Syntax::Intel, HexStyle::Dollar, Case::Upper, Case::Upper).hex(5, 2));
+        assert_eq!("0xabcd", style(Syntax::Intel, HexStyle::C, Case::Upper, Case::Lower).hex(0xabcd, 4));
+        assert_eq!("0x0005", style(Syntax::Intel, HexStyle::C, Case::Upper, Case::Lower).hex(5, 4));
+    }
+
+    #[test]
+    fn zilog() {
+        let zilog = style(Syntax::Zilog, HexStyle::Dollar, Case::Upper, Case::Upper);
+        let cases: [(&[u8], &str); 14] = [
+            (&[0x7e], "LD A,(HL)"),
+            (&[0x36, 0x12], "LD (HL),$12"),
+            (&[0x21, 0x34, 0x12], "LD HL,$1234"),
+            (&[0x3a, 0x34, 0x12], "LD A,($1234)"),
+            (&[0x22, 0x34, 0x12], "LD ($1234),HL"),
+            (&[0x12], "LD (DE),A"),
+            (&[0xeb], "EX DE,HL"),
+            (&[0x9e], "SBC A,(HL)"),
+            (&[0x39], "ADD HL,SP"),
+            (&[0xf1], "POP AF"),
+            (&[0xe2, 0x00, 0x01], "JP PO,$0100"),
+            (&[0xc8], "RET Z"),
+            (&[0xff], "RST $38"),
+            (&[0xd3, 0x01], "OUT ($01),A"),
+        ];
+        for (bytes, text) in cases.iter() {
+            assert_eq!(*text, zilog.instruction(&Instruction::decode(bytes).unwrap()));
+        }
+        assert_eq!("HALT", zilog.instruction(&Instruction::decode(&[0x76]).unwrap()));
+    }
+
+    #[test]
+    fn case() {
+        let lower = style(Syntax::Zilog, HexStyle::C, Case::Lower, Case::Lower);
+        let instruction = Instruction::decode(&[0x3a, 0x0b, 0x00]).unwrap();
+        assert_eq!("ld a,(0x000b)", lower.instruction(&instruction));
+        // labels keep their case
+        assert_eq!(("LD", String::from("a,(D000B)")), lower.parts(&instruction, Some("D000B")));
+        assert_eq!("ld", lower.text("LD", ""));
+
+        // hex digits and prefixes are inserted after the case is applied
+        let upper = style(Syntax::Zilog, HexStyle::C, Case::Upper, Case::Lower);
+        assert_eq!("RST 0x38", upper.instruction(&Instruction::decode(&[0xff]).unwrap()));
+        assert_eq!("LD A,0xab", upper.instruction(&Instruction::decode(&[0x3e, 0xab]).unwrap()));
+    }
+
+    #[test]
+    fn columns() {
+        let style = OutputStyle { label_width: 8, mnemonic_width: 6, ..OutputStyle::default() };
+        assert_eq!("L0000:  MOV   A,B", style.line(Some("L0000"), "MOV", "A,B"));
+        assert_eq!("        RET", style.line(None, "RET", ""));
+        assert_eq!("LONGER: SHLD  1234H", OutputStyle { label_width: 6, ..style }.line(Some("LONGER"), "SHLD", "1234H"));
+
+        let style = OutputStyle::default();
+        assert_eq!("L0000: MOV A,B", style.line(Some("L0000"), "MOV", "A,B"));
+        assert_eq!("RET", style.line(None, "RET", ""));
+    }
+
+    #[test]
+    fn disassembler() {
+        // MVI A,0fH, JMP 0
+        let mut d = Disassembler::load_bytes(vec![0x3e, 0x0f, 0xc3, 0x00, 0x00], 0);
+        d.set_style(OutputStyle { syntax: Syntax::Zilog, label_width: 8, ..OutputStyle::default() });
+        let map = d.trace(&[0]);
+        assert_eq!(vec!["        ORG 0H", "L0000:  LD A,0fH", "        JP L0000", "        END"], d.disassemble_source(&map));
+        assert_eq!(vec!["LD A,0fH", "JP 0H"], d.disassemble().unwrap());
+    }
+}